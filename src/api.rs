@@ -0,0 +1,682 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::icy;
+use crate::metrics;
+use crate::request::{self, Request};
+use crate::response::Response;
+use crate::server::{self, Permission, Server};
+use crate::source::Source;
+
+pub type HandlerFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Response, Box<dyn Error>>> + Send + 'a>>;
+pub type Handler = for<'a> fn(&'a Arc<RwLock<Server>>, &'a Request<'a>) -> HandlerFuture<'a>;
+
+// Every endpoint that answers with a single response, anything else is either a mountpoint or a 404
+const ROUTES: &[(&str, Handler)] = &[
+    ("/admin/metadata", admin_metadata),
+    ("/admin/listclients", admin_listclients),
+    ("/admin/fallbacks", admin_fallbacks),
+    ("/admin/moveclients", admin_moveclients),
+    ("/admin/killclient", admin_killclient),
+    ("/admin/killsource", admin_killsource),
+    ("/admin/listmounts", admin_listmounts),
+    ("/admin/streamlist", admin_streamlist),
+    ("/admin/reload", admin_reload),
+    ("/admin.cgi", admin_cgi),
+    ("/api/serverinfo", api_serverinfo),
+    ("/api/mountinfo", api_mountinfo),
+    ("/api/stats", api_stats),
+    ("/metrics", metrics::metrics),
+    // /api/events streams instead, so handle_connection in main.rs hands it to events::serve
+];
+
+pub fn find_route(path: &str) -> Option<Handler> {
+    ROUTES
+        .iter()
+        .find(|(route, _)| *route == path)
+        .map(|(_, handler)| *handler)
+}
+
+/**
+ * Check the basic auth header against the configured users
+ * Returns the response to send instead if the request could not be authenticated
+ */
+pub async fn authenticate(
+    server: &Arc<RwLock<Server>>,
+    headers: &[httparse::Header<'_>],
+) -> Result<server::Credential, Response> {
+    match request::get_basic_auth(headers) {
+        Some((name, pass)) => match server::validate_user(server, &name, &pass).await {
+            Some(user) => Ok(user),
+            // Invalid user/pass provided
+            None => Err(Response::unauthorized(Some((
+                "text/plain; charset=utf-8",
+                "Invalid credentials",
+            )))),
+        },
+        None => Err(Response::unauthorized(Some((
+            "text/plain; charset=utf-8",
+            "You need to authenticate",
+        )))),
+    }
+}
+
+pub fn missing_permission(permission: Permission, mount: Option<&str>) -> Response {
+    let message = match mount {
+        Some(mount) => format!("Missing permission '{}' for {}", permission, mount),
+        None => format!("Missing permission '{}'", permission),
+    };
+    Response::forbidden(Some(("text/plain; charset=utf-8", &message)))
+}
+
+pub fn invalid_query() -> Response {
+    Response::bad_request(Some(("text/plain; charset=utf-8", "Invalid query")))
+}
+
+fn invalid_mount() -> Response {
+    Response::forbidden(Some(("text/plain; charset=utf-8", "Invalid mount")))
+}
+
+fn success() -> Response {
+    Response::ok(Some(("application/json; charset=utf-8", "Success")))
+}
+
+// Takes in mode, mount, song and url
+fn admin_metadata<'a>(server: &'a Arc<RwLock<Server>>, req: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mode", "mount", "song", "url"], queries)[..].as_ref() {
+            [Some(mode), Some(mount), song, url] if mode == "updinfo" => {
+                if !user.has_permission(Permission::Metadata, Some(mount)) {
+                    return Ok(missing_permission(Permission::Metadata, Some(mount)));
+                }
+
+                Ok(update_metadata(&serv, mount, song, url).await)
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+async fn update_metadata(
+    serv: &Server,
+    mount: &str,
+    song: &Option<String>,
+    url: &Option<String>,
+) -> Response {
+    match serv.sources.get(mount) {
+        Some(source) => {
+            log_info!(
+                "Updated source {} metadata with title '{}' and url '{}'",
+                mount,
+                song.as_deref().unwrap_or(""),
+                url.as_deref().unwrap_or("")
+            );
+            let metadata = match (song, url) {
+                (None, None) => None,
+                _ => Some(icy::Metadata {
+                    title: song.clone(),
+                    url: url.clone(),
+                }),
+            };
+            let mut source = source.write().await;
+            // Ogg listeners only see the title through the comment header
+            if let Some(headers) = &mut source.ogg_headers {
+                headers.retitle(&metadata);
+            }
+            source.set_metadata(metadata);
+            Response::ok(Some(("text/plain; charset=utf-8", "Success")))
+        }
+        None => invalid_mount(),
+    }
+}
+
+// SHOUTcast v1 encoders update the title through here, with the password in the query
+// The mount defaults to the one SHOUTcast sources are mounted on
+fn admin_cgi<'a>(server: &'a Arc<RwLock<Server>>, req: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mode", "pass", "mount", "song", "url"], queries)[..]
+            .as_ref()
+        {
+            [Some(mode), pass, mount, song, url] if mode == "updinfo" => {
+                let shoutcast = server.read().await.properties.shoutcast.clone();
+                let user = match pass {
+                    Some(pass) => {
+                        match server::validate_user(server, &shoutcast.username, pass).await {
+                            Some(user) => user,
+                            None => {
+                                return Ok(Response::unauthorized(Some((
+                                    "text/plain; charset=utf-8",
+                                    "Invalid credentials",
+                                ))))
+                            }
+                        }
+                    }
+                    None => match authenticate(server, req.headers).await {
+                        Ok(user) => user,
+                        Err(response) => return Ok(response),
+                    },
+                };
+
+                let serv = server.read().await;
+                let mount = mount.as_deref().unwrap_or(&shoutcast.mount);
+                if !user.has_permission(Permission::Metadata, Some(mount)) {
+                    return Ok(missing_permission(Permission::Metadata, Some(mount)));
+                }
+
+                Ok(update_metadata(&serv, mount, song, url).await)
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+fn admin_listclients<'a>(
+    server: &'a Arc<RwLock<Server>>,
+    req: &'a Request<'a>,
+) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mount"], queries)[..].as_ref() {
+            [Some(mount)] => {
+                if !user.has_permission(Permission::ListClients, Some(mount)) {
+                    return Ok(missing_permission(Permission::ListClients, Some(mount)));
+                }
+
+                if let Some(source) = serv.sources.get(mount) {
+                    let mut clients: HashMap<Uuid, Value> = HashMap::new();
+
+                    for client in source.read().await.clients.values() {
+                        let client = client.read().await;
+                        let properties = client.properties.clone();
+
+                        let value = json!( {
+                            "user_agent": properties.uagent,
+                            "metadata_enabled": properties.metadata,
+                            "stats": &*client.stats.read().await
+                        } );
+
+                        clients.insert(properties.id, value);
+                    }
+
+                    Ok(Response::json(&json!(clients)))
+                } else {
+                    Ok(invalid_mount())
+                }
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+fn admin_fallbacks<'a>(server: &'a Arc<RwLock<Server>>, req: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mount", "fallback"], queries)[..].as_ref() {
+            [Some(mount), fallback] => {
+                if !user.has_permission(Permission::Fallbacks, Some(mount)) {
+                    return Ok(missing_permission(Permission::Fallbacks, Some(mount)));
+                }
+
+                if let Some(source) = serv.sources.get(mount) {
+                    source.write().await.fallback = fallback.clone();
+
+                    if let Some(fallback) = fallback {
+                        log_info!("Set the fallback for {} to {}", mount, fallback);
+                    } else {
+                        log_info!("Unset the fallback for {}", mount);
+                    }
+                    Ok(success())
+                } else {
+                    Ok(invalid_mount())
+                }
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+fn admin_moveclients<'a>(
+    server: &'a Arc<RwLock<Server>>,
+    req: &'a Request<'a>,
+) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mount", "destination"], queries)[..].as_ref() {
+            [Some(mount), Some(dest)] => {
+                // Both mounts are affected by the move
+                for affected in [mount, dest] {
+                    if !user.has_permission(Permission::MoveClients, Some(affected)) {
+                        return Ok(missing_permission(Permission::MoveClients, Some(affected)));
+                    }
+                }
+
+                match (serv.sources.get(mount), serv.sources.get(dest)) {
+                    (Some(source), Some(destination)) => {
+                        let mut from = source.write().await;
+                        let mut to = destination.write().await;
+
+                        for (uuid, client) in from.clients.drain() {
+                            *client.read().await.source.write().await = to.mountpoint.clone();
+                            to.clients.insert(uuid, client);
+                        }
+
+                        log_info!("Moved clients from {} to {}", mount, dest);
+                        Ok(success())
+                    }
+                    _ => Ok(invalid_mount()),
+                }
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+fn admin_killclient<'a>(
+    server: &'a Arc<RwLock<Server>>,
+    req: &'a Request<'a>,
+) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mount", "id"], queries)[..].as_ref() {
+            [Some(mount), Some(uuid_str)] => {
+                if !user.has_permission(Permission::KillClient, Some(mount)) {
+                    return Ok(missing_permission(Permission::KillClient, Some(mount)));
+                }
+
+                match (serv.sources.get(mount), Uuid::parse_str(uuid_str)) {
+                    (Some(source), Ok(uuid)) => {
+                        if let Some(client) = source.read().await.clients.get(&uuid) {
+                            drop(
+                                client
+                                    .read()
+                                    .await
+                                    .sender
+                                    .write()
+                                    .await
+                                    .send(Arc::new(Vec::new())),
+                            );
+                            log_info!("Killing client {}", uuid);
+                            Ok(success())
+                        } else {
+                            Ok(Response::forbidden(Some((
+                                "text/plain; charset=utf-8",
+                                "Invalid id",
+                            ))))
+                        }
+                    }
+                    (None, _) => Ok(invalid_mount()),
+                    (Some(_), Err(_)) => Ok(Response::forbidden(Some((
+                        "text/plain; charset=utf-8",
+                        "Invalid id",
+                    )))),
+                }
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+fn admin_killsource<'a>(
+    server: &'a Arc<RwLock<Server>>,
+    req: &'a Request<'a>,
+) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mount"], queries)[..].as_ref() {
+            [Some(mount)] => {
+                if !user.has_permission(Permission::KillSource, Some(mount)) {
+                    return Ok(missing_permission(Permission::KillSource, Some(mount)));
+                }
+
+                if let Some(source) = serv.sources.get(mount) {
+                    source.write().await.disconnect_flag = true;
+
+                    log_info!("Killing source {}", mount);
+                    Ok(success())
+                } else {
+                    Ok(invalid_mount())
+                }
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+fn admin_listmounts<'a>(
+    server: &'a Arc<RwLock<Server>>,
+    req: &'a Request<'a>,
+) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        if !user.has_permission(Permission::ListMounts, None) {
+            return Ok(missing_permission(Permission::ListMounts, None));
+        }
+
+        let mut sources: HashMap<String, Value> = HashMap::new();
+
+        for source in serv.sources.values() {
+            let source = source.read().await;
+            let hidden = source.hidden || serv.hidden(&source.mountpoint);
+            if hidden && !user.has_permission(Permission::Hidden, Some(&source.mountpoint)) {
+                continue;
+            }
+
+            let value = json!( {
+                "hidden": hidden,
+                "fallback": source.fallback,
+                "metadata": source.metadata,
+                "properties": source.properties,
+                "stats": &*source.stats.read().await,
+                "clients": source.clients.keys().cloned().collect::< Vec< Uuid > >()
+            } );
+
+            sources.insert(source.mountpoint.clone(), value);
+        }
+        // On demand relays are listed even while they're disconnected
+        for (relay, _) in serv.on_demand_relays() {
+            let hidden = serv.hidden(&relay.mount);
+            if hidden && !user.has_permission(Permission::Hidden, Some(&relay.mount)) {
+                continue;
+            }
+            let url = relay.url;
+            sources.entry(relay.mount).or_insert_with(|| {
+                json!( {
+                    "hidden": hidden,
+                    "relay": url,
+                    "on_demand": true,
+                    "clients": []
+                } )
+            });
+        }
+
+        Ok(Response::json(&json!(sources)))
+    })
+}
+
+// The mounts a slave can relay, along with what it needs to mirror them
+fn admin_streamlist<'a>(
+    server: &'a Arc<RwLock<Server>>,
+    req: &'a Request<'a>,
+) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let serv = server.read().await;
+
+        // Relay users might only be allowed some of the mounts
+        let granted = user.has_permission(Permission::Relay, None)
+            || user
+                .mounts
+                .values()
+                .any(|grants| grants.contains(&Permission::Relay));
+        if !granted {
+            return Ok(missing_permission(Permission::Relay, None));
+        }
+
+        let mut mounts = Vec::new();
+        for (mount, source) in &serv.sources {
+            if !serv.relayable(mount) || !user.has_permission(Permission::Relay, Some(mount)) {
+                continue;
+            }
+            let source = source.read().await;
+            let hidden = source.hidden || serv.hidden(mount);
+            if hidden && !user.has_permission(Permission::Hidden, Some(mount)) {
+                continue;
+            }
+            mounts.push(json!( {
+                "mount": mount,
+                "hidden": hidden,
+                "content_type": source.properties.content_type,
+                "metadata": source.metadata
+            } ));
+        }
+        // On demand relays are listed even while they're disconnected, like in /api/serverinfo
+        for (relay, _) in serv.on_demand_relays() {
+            let mount = relay.mount;
+            if serv.sources.contains_key(&mount)
+                || !serv.relayable(&mount)
+                || !user.has_permission(Permission::Relay, Some(&mount))
+            {
+                continue;
+            }
+            let hidden = serv.hidden(&mount);
+            if hidden && !user.has_permission(Permission::Hidden, Some(&mount)) {
+                continue;
+            }
+            mounts.push(json!( {
+                "mount": mount,
+                "hidden": hidden,
+                "content_type": null,
+                "metadata": null
+            } ));
+        }
+
+        Ok(Response::json(&json!( { "mounts": mounts } )))
+    })
+}
+
+// Reads the config again, the response lists the changed settings that need a restart
+fn admin_reload<'a>(server: &'a Arc<RwLock<Server>>, req: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let user = match authenticate(server, req.headers).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        if !user.has_permission(Permission::Reload, None) {
+            return Ok(missing_permission(Permission::Reload, None));
+        }
+
+        match server::reload(server).await {
+            Ok(restart_required) => Ok(Response::json(&json!( {
+                "restart_required": restart_required
+            } ))),
+            Err(e) => {
+                log_error!("Unable to reload the config: {}", e);
+                Ok(Response::internal_error(Some((
+                    "text/plain; charset=utf-8",
+                    &e,
+                ))))
+            }
+        }
+    })
+}
+
+fn api_serverinfo<'a>(server: &'a Arc<RwLock<Server>>, req: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        // Anyone can ask, but hidden mounts are only listed to users with the hidden permission on them
+        let user = match request::get_header("Authorization", req.headers) {
+            Some(_) => match authenticate(server, req.headers).await {
+                Ok(user) => Some(user),
+                Err(response) => return Ok(response),
+            },
+            None => None,
+        };
+        let listed = |hidden: bool, mount: &str| {
+            !hidden
+                || user
+                    .as_ref()
+                    .is_some_and(|user| user.has_permission(Permission::Hidden, Some(mount)))
+        };
+
+        let serv = server.read().await;
+        let mut mounts = Vec::new();
+        for (mount, source) in &serv.sources {
+            if listed(source.read().await.hidden || serv.hidden(mount), mount) {
+                mounts.push(mount.clone());
+            }
+        }
+        for (relay, _) in serv.on_demand_relays() {
+            if !serv.sources.contains_key(&relay.mount)
+                && listed(serv.hidden(&relay.mount), &relay.mount)
+            {
+                mounts.push(relay.mount);
+            }
+        }
+
+        Ok(Response::json(&json!( {
+            "mounts": mounts,
+            "properties": {
+                "server_id": serv.properties.server_id,
+                "admin": serv.properties.admin,
+                "host": serv.properties.host,
+                "location": serv.properties.location,
+                "description": serv.properties.description
+            },
+            "stats": {
+                "start_time": serv.stats.start_time,
+                "peak_listeners": serv.stats.peak_listeners
+            },
+            "current_listeners": serv.clients.len()
+        } )))
+    })
+}
+
+fn api_mountinfo<'a>(server: &'a Arc<RwLock<Server>>, req: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let queries = match &req.queries {
+            Some(queries) => queries,
+            None => return Ok(invalid_query()),
+        };
+        match request::get_queries_for(vec!["mount"], queries)[..].as_ref() {
+            [Some(mount)] => {
+                let serv = server.read().await;
+                if let Some(source) = serv.sources.get(mount) {
+                    Ok(Response::json(&mount_info(&*source.read().await).await))
+                } else {
+                    Ok(invalid_mount())
+                }
+            }
+            _ => Ok(invalid_query()),
+        }
+    })
+}
+
+/**
+ * Describe a mountpoint, as both /api/mountinfo and /api/events do
+ */
+pub async fn mount_info(source: &Source) -> Value {
+    let properties = &source.properties;
+    let stats = &source.stats.read().await;
+
+    json!( {
+        "metadata": source.metadata,
+        "properties": {
+            "name": properties.name,
+            "description": properties.description,
+            "url": properties.url,
+            "genre": properties.genre,
+            "bitrate": properties.bitrate,
+            "content_type": properties.content_type
+        },
+        "stats": {
+            "start_time": stats.start_time,
+            "peak_listeners": stats.peak_listeners
+        },
+        "current_listeners": source.clients.len()
+    } )
+}
+
+fn api_stats<'a>(server: &'a Arc<RwLock<Server>>, _: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let server = server.read().await;
+        let stats = &server.stats;
+        let mut total_bytes_sent = stats.session_bytes_sent;
+        let mut total_bytes_read = stats.session_bytes_read;
+        for source in server.sources.values() {
+            let source = source.read().await;
+            total_bytes_read += source.stats.read().await.bytes_read;
+            for clients in source.clients.values() {
+                total_bytes_sent += clients.read().await.stats.read().await.bytes_sent;
+            }
+        }
+
+        let epoch = {
+            if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                time.as_secs()
+            } else {
+                0
+            }
+        };
+
+        Ok(Response::json(&json!( {
+            "uptime": epoch - stats.start_time,
+            "peak_listeners": stats.peak_listeners,
+            "session_bytes_read": total_bytes_read,
+            "session_bytes_sent": total_bytes_sent
+        } )))
+    })
+}
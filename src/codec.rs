@@ -0,0 +1,466 @@
+// Splits source data into whole frames, so listeners never start in the middle of one
+// The codec is picked from the content type, anything unknown is passed through as is
+// Ogg streams are split into pages instead
+
+// Framing is given up after this many bytes without finding a frame, rather than starving listeners
+const SYNC_LIMIT: usize = 65536;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Codec {
+    Mpeg,
+    Adts,
+    Ogg,
+    Unknown,
+}
+
+impl Codec {
+    fn from_content_type(content_type: &str) -> Codec {
+        // Ignore parameters like ; codecs=
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+        match mime.as_str() {
+            "audio/mpeg" | "audio/mp3" | "audio/mpeg3" | "audio/x-mpeg" | "audio/mpa" => {
+                Codec::Mpeg
+            }
+            "audio/aac" | "audio/aacp" | "audio/x-aac" | "audio/x-aacp" | "audio/aac-adts" => {
+                Codec::Adts
+            }
+            "application/ogg" | "audio/ogg" | "video/ogg" => Codec::Ogg,
+            _ => Codec::Unknown,
+        }
+    }
+
+    // The least amount of bytes needed to read the frame length
+    fn header_length(&self, data: &[u8]) -> usize {
+        match self {
+            Codec::Mpeg => 4,
+            Codec::Adts => 7,
+            // The segment table follows the fixed part of the page header
+            Codec::Ogg if data.len() >= 27 && data.starts_with(b"OggS") => 27 + data[26] as usize,
+            Codec::Ogg => 27,
+            Codec::Unknown => 0,
+        }
+    }
+
+    fn frame_length(&self, header: &[u8]) -> Option<usize> {
+        match self {
+            Codec::Mpeg => mpeg_frame_length(header),
+            Codec::Adts => adts_frame_length(header),
+            Codec::Ogg => ogg_page_length(header),
+            Codec::Unknown => None,
+        }
+    }
+
+    /**
+     * How many samples a whole frame holds, along with the sample rate
+     */
+    pub fn samples(&self, frame: &[u8]) -> Option<(u32, u32)> {
+        match self {
+            Codec::Mpeg => mpeg_samples(frame),
+            Codec::Adts => adts_samples(frame),
+            Codec::Ogg | Codec::Unknown => None,
+        }
+    }
+}
+
+pub struct Framer {
+    codec: Codec,
+    pending: Vec<u8>,
+    synced: bool,
+    skipped: usize,
+}
+
+impl Framer {
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn new(content_type: &str) -> Framer {
+        Framer {
+            codec: Codec::from_content_type(content_type),
+            pending: Vec::new(),
+            synced: false,
+            skipped: 0,
+        }
+    }
+
+    /**
+     * Returns every whole frame available so far along with their lengths, the rest waits for more data
+     * Bytes that aren't part of a frame are dropped while looking for the next one
+     * The lengths are None if the codec isn't known, in which case the data is returned untouched
+     */
+    pub fn push(&mut self, data: &[u8]) -> (Vec<u8>, Option<Vec<usize>>) {
+        if self.codec == Codec::Unknown {
+            return (data.to_vec(), None);
+        }
+
+        self.pending.extend_from_slice(data);
+        let mut complete = Vec::new();
+        let mut frames = Vec::new();
+        // Everything before this has either been returned or dropped
+        let mut position = 0;
+
+        while self.pending.len() - position >= self.codec.header_length(&self.pending[position..]) {
+            let rest = &self.pending[position..];
+            let length = match self.codec.frame_length(rest) {
+                Some(length) => length,
+                None => {
+                    self.synced = false;
+                    self.skipped += 1;
+                    position += 1;
+                    continue;
+                }
+            };
+
+            if rest.len() < length {
+                break;
+            }
+            if !self.synced {
+                // A lone sync word is easy to come by, so the next frame has to be there too
+                let next = &rest[length..];
+                if next.len() < self.codec.header_length(next) {
+                    break;
+                }
+                if self.codec.frame_length(next).is_none() {
+                    self.skipped += 1;
+                    position += 1;
+                    continue;
+                }
+                self.synced = true;
+            }
+
+            complete.extend_from_slice(&rest[..length]);
+            frames.push(length);
+            position += length;
+        }
+        self.pending.drain(..position);
+
+        if !frames.is_empty() {
+            self.skipped = 0;
+        } else if self.skipped > SYNC_LIMIT {
+            // This doesn't look like the content type it claims to be
+            self.codec = Codec::Unknown;
+            return (std::mem::take(&mut self.pending), None);
+        }
+
+        (complete, Some(frames))
+    }
+}
+
+// Bitrates in kbps, indexed by the bitrate index
+const MPEG1_LAYER1: [u32; 15] = [
+    0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+];
+const MPEG1_LAYER2: [u32; 15] = [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+];
+const MPEG1_LAYER3: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MPEG2_LAYER1: [u32; 15] = [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+];
+const MPEG2_LAYER23: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/**
+ * The length of an MPEG audio frame, including its header
+ * http://www.mp3-tech.org/programmer/frame_header.html
+ */
+fn mpeg_frame_length(header: &[u8]) -> Option<usize> {
+    if header.len() < 4 || header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+
+    // 0 is MPEG 2.5, 2 is MPEG 2 and 3 is MPEG 1
+    let version = (header[1] >> 3) & 0b11;
+    // 1 is layer III, 2 is layer II and 3 is layer I
+    let layer = (header[1] >> 1) & 0b11;
+    let bitrate_index = (header[2] >> 4) as usize;
+    let sample_rate_index = ((header[2] >> 2) & 0b11) as usize;
+    let padding = ((header[2] >> 1) & 1) as u32;
+
+    // Free format frames can't be measured from the header alone
+    if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 {
+        return None;
+    }
+    let sample_rate = mpeg_sample_rate(version, sample_rate_index)?;
+    let bitrate = match (version, layer) {
+        (3, 3) => MPEG1_LAYER1,
+        (3, 2) => MPEG1_LAYER2,
+        (3, _) => MPEG1_LAYER3,
+        (_, 3) => MPEG2_LAYER1,
+        _ => MPEG2_LAYER23,
+    }[bitrate_index]
+        * 1000;
+
+    let length = match (version, layer) {
+        (_, 3) => (12 * bitrate / sample_rate + padding) * 4,
+        (3, _) | (_, 2) => 144 * bitrate / sample_rate + padding,
+        // Layer III in MPEG 2 and 2.5 has half the samples per frame
+        _ => 72 * bitrate / sample_rate + padding,
+    };
+    Some(length as usize)
+}
+
+fn mpeg_sample_rate(version: u8, index: usize) -> Option<u32> {
+    match (version, index) {
+        (_, 3) => None,
+        (3, i) => Some([44100, 48000, 32000][i]),
+        (2, i) => Some([22050, 24000, 16000][i]),
+        (_, i) => Some([11025, 12000, 8000][i]),
+    }
+}
+
+fn mpeg_samples(header: &[u8]) -> Option<(u32, u32)> {
+    mpeg_frame_length(header)?;
+    let version = (header[1] >> 3) & 0b11;
+    let layer = (header[1] >> 1) & 0b11;
+    let sample_rate = mpeg_sample_rate(version, ((header[2] >> 2) & 0b11) as usize)?;
+    let samples = match (version, layer) {
+        (_, 3) => 384,
+        (3, _) | (_, 2) => 1152,
+        _ => 576,
+    };
+    Some((samples, sample_rate))
+}
+
+/**
+ * The length of an AAC ADTS frame, including its header
+ * https://wiki.multimedia.cx/index.php/ADTS
+ */
+fn adts_frame_length(header: &[u8]) -> Option<usize> {
+    if header.len() < 7 || header[0] != 0xFF || header[1] & 0xF6 != 0xF0 {
+        return None;
+    }
+
+    // 13 is the first reserved sampling frequency
+    if (header[2] >> 2) & 0b1111 >= 13 {
+        return None;
+    }
+
+    let length = ((header[3] as usize & 0b11) << 11)
+        | ((header[4] as usize) << 3)
+        | (header[5] as usize >> 5);
+    // The header is 9 bytes with a CRC
+    let header_length = if header[1] & 1 == 0 { 9 } else { 7 };
+    if length < header_length {
+        return None;
+    }
+    Some(length)
+}
+
+fn adts_samples(header: &[u8]) -> Option<(u32, u32)> {
+    adts_frame_length(header)?;
+    const SAMPLE_RATES: [u32; 13] = [
+        96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+    ];
+    let sample_rate = SAMPLE_RATES[((header[2] >> 2) & 0b1111) as usize];
+    // Every raw data block holds 1024 samples
+    let blocks = (header[6] & 0b11) as u32 + 1;
+    Some((1024 * blocks, sample_rate))
+}
+
+/**
+ * The length of an Ogg page, including its header
+ * https://xiph.org/ogg/doc/framing.html
+ */
+fn ogg_page_length(header: &[u8]) -> Option<usize> {
+    // Only version 0 exists
+    if header.len() < 27 || !header.starts_with(b"OggS") || header[4] != 0 {
+        return None;
+    }
+
+    let segments = header[26] as usize;
+    let table = header.get(27..27 + segments)?;
+    Some(27 + segments + table.iter().map(|&lacing| lacing as usize).sum::<usize>())
+}
+
+/**
+ * The CRC-32 used by both Ogg and MPEG-TS, with the polynomial 0x04C11DB7 and no reflection
+ * Ogg starts from zero, MPEG-TS from all ones
+ */
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MPEG 1 layer III, 128 kbps, 44.1 kHz, no CRC
+    const MPEG1_L3: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+
+    fn frame(header: &[u8], length: usize) -> Vec<u8> {
+        let mut frame = header.to_vec();
+        frame.resize(length, 0);
+        frame
+    }
+
+    fn adts_header(protection_absent: bool, length: usize) -> [u8; 7] {
+        [
+            0xFF,
+            0xF0 | protection_absent as u8,
+            // AAC LC at 44.1 kHz
+            0x50,
+            0x80 | (length >> 11) as u8,
+            (length >> 3) as u8,
+            ((length & 0b111) << 5) as u8 | 0x1F,
+            0xFC,
+        ]
+    }
+
+    #[test]
+    fn mpeg1_layer3_frames() {
+        assert_eq!(mpeg_frame_length(&MPEG1_L3), Some(417));
+        // Padded
+        assert_eq!(mpeg_frame_length(&[0xFF, 0xFB, 0x92, 0x00]), Some(418));
+        // 320 kbps at 48 kHz
+        assert_eq!(mpeg_frame_length(&[0xFF, 0xFB, 0xE4, 0x00]), Some(960));
+        assert_eq!(mpeg_samples(&MPEG1_L3), Some((1152, 44100)));
+    }
+
+    #[test]
+    fn mpeg2_layer3_frames() {
+        // 64 kbps at 22.05 kHz
+        let header = [0xFF, 0xF3, 0x80, 0x00];
+        assert_eq!(mpeg_frame_length(&header), Some(208));
+        assert_eq!(mpeg_samples(&header), Some((576, 22050)));
+    }
+
+    #[test]
+    fn invalid_mpeg_headers() {
+        // Free format, the reserved bitrate, sample rate and version
+        assert_eq!(mpeg_frame_length(&[0xFF, 0xFB, 0x00, 0x00]), None);
+        assert_eq!(mpeg_frame_length(&[0xFF, 0xFB, 0xF0, 0x00]), None);
+        assert_eq!(mpeg_frame_length(&[0xFF, 0xFB, 0x9C, 0x00]), None);
+        assert_eq!(mpeg_frame_length(&[0xFF, 0xEB, 0x90, 0x00]), None);
+        assert_eq!(mpeg_frame_length(&[0xFF, 0xFB, 0x90]), None);
+    }
+
+    #[test]
+    fn adts_frames() {
+        assert_eq!(adts_frame_length(&adts_header(true, 371)), Some(371));
+        assert_eq!(adts_samples(&adts_header(true, 371)), Some((1024, 44100)));
+        assert_eq!(adts_frame_length(&adts_header(false, 371)), Some(371));
+
+        // Too short to hold its own header, which is 9 bytes with a CRC
+        assert_eq!(adts_frame_length(&adts_header(true, 8)), Some(8));
+        assert_eq!(adts_frame_length(&adts_header(false, 8)), None);
+        assert_eq!(adts_frame_length(&adts_header(true, 6)), None);
+
+        // Reserved sampling frequency
+        let mut header = adts_header(true, 371);
+        header[2] = 0x74;
+        assert_eq!(adts_frame_length(&header), None);
+    }
+
+    #[test]
+    fn framer_returns_whole_frames() {
+        let mut framer = Framer::new("audio/mpeg");
+        let mut data = frame(&MPEG1_L3, 417);
+        data.extend_from_slice(&frame(&MPEG1_L3, 417));
+        data.extend_from_slice(&MPEG1_L3[..3]);
+
+        let (complete, frames) = framer.push(&data);
+        assert_eq!(frames, Some(vec![417, 417]));
+        assert_eq!(complete, data[..834]);
+
+        // The rest of the third frame completes it
+        let rest = frame(&MPEG1_L3, 417);
+        let (complete, frames) = framer.push(&rest[3..]);
+        assert_eq!(frames, Some(vec![417]));
+        assert_eq!(complete, rest);
+    }
+
+    #[test]
+    fn framer_resyncs_after_garbage() {
+        let mut framer = Framer::new("audio/aacp");
+        // Contains a sync word that isn't followed by another frame
+        let mut data = b"garbage".to_vec();
+        data.extend_from_slice(&adts_header(true, 50));
+        data.extend_from_slice(&[0x12; 13]);
+        let frames: Vec<u8> = (0..3)
+            .flat_map(|_| frame(&adts_header(true, 100), 100))
+            .collect();
+        data.extend_from_slice(&frames);
+
+        let (complete, lengths) = framer.push(&data);
+        assert_eq!(lengths, Some(vec![100, 100, 100]));
+        assert_eq!(complete, frames);
+        assert!(framer.codec() == Codec::Adts);
+    }
+
+    #[test]
+    fn framer_gives_up_without_frames() {
+        let mut framer = Framer::new("audio/mpeg");
+        let (_, frames) = framer.push(&vec![0; SYNC_LIMIT / 2]);
+        assert_eq!(frames, Some(vec![]));
+
+        let (complete, frames) = framer.push(&vec![0; SYNC_LIMIT]);
+        assert_eq!(frames, None);
+        assert!(framer.codec() == Codec::Unknown);
+        // What couldn't be framed is passed on
+        assert!(!complete.is_empty());
+
+        let (complete, frames) = framer.push(b"more");
+        assert_eq!((complete, frames), (b"more".to_vec(), None));
+    }
+
+    #[test]
+    fn unknown_content_types_pass_through() {
+        let mut framer = Framer::new("application/octet-stream");
+        assert_eq!(framer.push(b"data"), (b"data".to_vec(), None));
+    }
+
+    #[test]
+    fn crc32_check_values() {
+        // CRC-32/MPEG-2, as used by MPEG-TS
+        assert_eq!(crc32(0xFFFF_FFFF, b"123456789"), 0x0376_E6E7);
+        // Ogg starts from zero and doesn't invert the result
+        assert_eq!(crc32(0, b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn ogg_page_crc() {
+        // A BOS page holding the packet "hello"
+        let mut page = vec![
+            0x4F, 0x67, 0x67, 0x53, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xB4, 0x1B, 0xFD, 0x44, 0x01, 0x05,
+            0x68, 0x65, 0x6C, 0x6C, 0x6F,
+        ];
+        assert_eq!(ogg_page_length(&page), Some(page.len()));
+        assert_eq!(Codec::Ogg.header_length(&page), 28);
+
+        let stored = u32::from_le_bytes([page[22], page[23], page[24], page[25]]);
+        page[22..26].fill(0);
+        assert_eq!(crc32(0, &page), stored);
+    }
+}
@@ -0,0 +1,287 @@
+use serde_json::{json, Value};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::RwLock;
+
+use crate::api;
+use crate::request::{self, Request};
+use crate::response::{self, Stream};
+use crate::server::Server;
+
+// Changes to mountpoints, pushed to /api/events over either Server-Sent Events or a WebSocket
+
+// How many events a slow subscriber can fall behind by before it misses some
+pub const CAPACITY: usize = 256;
+// Listener counts change too often to send every one
+const LISTENER_INTERVAL: Duration = Duration::from_secs(5);
+// https://datatracker.ietf.org/doc/html/rfc6455#section-1.3
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Clone)]
+pub enum Kind {
+    Connect,
+    // Along with the mount info as it was, since the source is gone by the time it's read
+    Disconnect(Value),
+    Metadata,
+    // The listeners were moved to this fallback
+    Fallback(String),
+}
+
+#[derive(Clone)]
+pub struct Event {
+    pub mount: String,
+    pub kind: Kind,
+}
+
+impl Event {
+    pub fn new(mount: &str, kind: Kind) -> Event {
+        Event {
+            mount: mount.to_string(),
+            kind,
+        }
+    }
+}
+
+// How the events are written out
+enum Channel {
+    Sse,
+    WebSocket,
+}
+
+impl Channel {
+    async fn send(&self, stream: &mut Stream, name: &str, data: &Value) -> std::io::Result<()> {
+        match self {
+            Channel::Sse => {
+                stream
+                    .write_all(format!("event: {}\ndata: {}\n\n", name, data).as_bytes())
+                    .await
+            }
+            Channel::WebSocket => {
+                let text = json!( { "event": name, "data": data } ).to_string();
+                stream
+                    .write_all(&websocket_frame(0x1, text.as_bytes()))
+                    .await
+            }
+        }
+    }
+}
+
+/**
+ * Push every change to a mountpoint until the subscriber goes away
+ * The data of every event is the same as /api/mountinfo, for the mountpoint the event is about
+ */
+pub async fn serve(
+    server: &Arc<RwLock<Server>>,
+    stream: &mut Stream,
+    server_id: &str,
+    req: &Request<'_>,
+) -> Result<(), Box<dyn Error>> {
+    let head = req.method == "HEAD";
+    let mount = match &req.queries {
+        Some(queries) => request::get_queries_for(vec!["mount"], queries)
+            .pop()
+            .flatten(),
+        None => None,
+    };
+    let mount = match mount {
+        Some(mount) => mount,
+        None => {
+            return response::send_response(
+                stream,
+                server_id,
+                &api::invalid_query(),
+                req.version,
+                false,
+                head,
+            )
+            .await;
+        }
+    };
+
+    let upgrade = request::get_header("Upgrade", req.headers)
+        .is_some_and(|value| value.eq_ignore_ascii_case(b"websocket"));
+    let channel = match request::get_header("Sec-WebSocket-Key", req.headers) {
+        Some(key) if upgrade && !head => {
+            let mut accept = key.to_vec();
+            accept.extend_from_slice(WEBSOCKET_GUID.as_bytes());
+            let accept = base64::encode(sha1_smol::Sha1::from(accept).digest().bytes());
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 101 Switching Protocols\r\nServer: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                        server_id, accept
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            Channel::WebSocket
+        }
+        _ => {
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.{} 200 OK\r\nServer: {}\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache, no-store\r\nConnection: close\r\nAccess-Control-Allow-Origin: *\r\n\r\n",
+                        req.version, server_id
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            Channel::Sse
+        }
+    };
+    // HEAD only gets to see what the stream would look like
+    if head {
+        return Ok(());
+    }
+
+    // Subscribe before looking anything up, so nothing is missed in between
+    let mut receiver = server.read().await.events.subscribe();
+    let mut listeners = None;
+    if let Some(info) = mount_info(server, &mount).await {
+        listeners = info["current_listeners"].as_u64();
+        channel.send(stream, "mountinfo", &info).await?;
+    }
+
+    let mut interval = tokio::time::interval(LISTENER_INTERVAL);
+    let mut buf = [0; 1024];
+    let mut incoming = Vec::new();
+    loop {
+        tokio::select! {
+            read = stream.read(&mut buf) => {
+                let read = read?;
+                if read == 0 {
+                    return Ok(());
+                }
+                if let Channel::WebSocket = channel {
+                    incoming.extend_from_slice(&buf[..read]);
+                    if !answer_websocket(stream, &mut incoming).await? {
+                        return Ok(());
+                    }
+                }
+            }
+            event = receiver.recv() => {
+                let event = match event {
+                    Ok(event) if event.mount == mount => event,
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                let (name, info) = match event.kind {
+                    Kind::Connect => ("source_connect", mount_info(server, &mount).await),
+                    Kind::Disconnect(info) => ("source_disconnect", Some(info)),
+                    Kind::Metadata => ("metadata", mount_info(server, &mount).await),
+                    Kind::Fallback(fallback) => ("fallback", mount_info(server, &fallback).await),
+                };
+                if let Some(info) = info {
+                    // The count starts over with every source
+                    if name != "fallback" {
+                        listeners = info["current_listeners"].as_u64();
+                    }
+                    channel.send(stream, name, &info).await?;
+                }
+            }
+            _ = interval.tick() => {
+                if let Some(info) = mount_info(server, &mount).await {
+                    let current = info["current_listeners"].as_u64();
+                    if current != listeners {
+                        listeners = current;
+                        channel.send(stream, "listeners", &info).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn mount_info(server: &Arc<RwLock<Server>>, mount: &str) -> Option<Value> {
+    let serv = server.read().await;
+    let source = serv.sources.get(mount)?.read().await;
+    Some(api::mount_info(&source).await)
+}
+
+/**
+ * Answer whatever whole frames the subscriber sent, returning false once it closes the connection
+ * Anything other than pings and closes is ignored, there's nothing to say to the server
+ */
+async fn answer_websocket(stream: &mut Stream, incoming: &mut Vec<u8>) -> std::io::Result<bool> {
+    loop {
+        if incoming.len() < 2 {
+            return Ok(true);
+        }
+        let opcode = incoming[0] & 0x0F;
+        let masked = incoming[1] & 0x80 != 0;
+        let (length, mut position) = match incoming[1] & 0x7F {
+            126 if incoming.len() >= 4 => {
+                (u16::from_be_bytes([incoming[2], incoming[3]]) as usize, 4)
+            }
+            127 if incoming.len() >= 10 => {
+                let mut length = [0; 8];
+                length.copy_from_slice(&incoming[2..10]);
+                (u64::from_be_bytes(length) as usize, 10)
+            }
+            126 | 127 => return Ok(true),
+            length => (length as usize, 2),
+        };
+        // Nothing that big is expected from a subscriber
+        if length > 1 << 16 {
+            return Ok(false);
+        }
+
+        let mask = if masked {
+            if incoming.len() < position + 4 {
+                return Ok(true);
+            }
+            let mask = [
+                incoming[position],
+                incoming[position + 1],
+                incoming[position + 2],
+                incoming[position + 3],
+            ];
+            position += 4;
+            mask
+        } else {
+            [0; 4]
+        };
+        if incoming.len() < position + length {
+            return Ok(true);
+        }
+
+        let payload: Vec<u8> = incoming[position..position + length]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        incoming.drain(..position + length);
+
+        match opcode {
+            // Close, which is echoed back
+            0x8 => {
+                stream.write_all(&websocket_frame(0x8, &payload)).await?;
+                return Ok(false);
+            }
+            // Ping
+            0x9 => stream.write_all(&websocket_frame(0xA, &payload)).await?,
+            _ => (),
+        }
+    }
+}
+
+// A single unmasked frame, as servers send them
+fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
@@ -0,0 +1,414 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::codec;
+use crate::response::Response;
+use crate::server::{self, Server};
+
+// HTTP Live Streaming for MP3 and AAC mountpoints, with the segments muxed into MPEG-TS and kept in memory
+// https://datatracker.ietf.org/doc/html/rfc8216
+// Titles are carried as timed ID3 metadata on a stream of their own
+// https://developer.apple.com/library/archive/documentation/AudioVideo/Conceptual/HTTP_Live_Streaming_Metadata_Spec/
+
+const PLAYLIST: &str = "playlist.m3u8";
+
+const PACKET_SIZE: usize = 188;
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+const AUDIO_PID: u16 = 0x100;
+const ID3_PID: u16 = 0x101;
+// Frames are gathered into PES packets of about this size
+const PES_SIZE: usize = 4096;
+// Timestamps tick at 90 kHz and wrap around at 33 bits
+const CLOCK: u64 = 90_000;
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+struct Segment {
+    sequence: u64,
+    duration: f64,
+    data: Arc<Vec<u8>>,
+}
+
+pub struct Rendition {
+    codec: codec::Codec,
+    // In 90 kHz ticks
+    target: u64,
+    window: usize,
+    segments: VecDeque<Segment>,
+    // The segment being written, and its start
+    sequence: u64,
+    current: Vec<u8>,
+    start: u64,
+    // Audio waiting to go into a PES packet, and when it starts
+    pes: Vec<u8>,
+    pes_timestamp: u64,
+    // Samples since the last change in sample rate, which is when base was taken
+    base: u64,
+    samples: u64,
+    sample_rate: u32,
+    // Indexed by PAT, PMT, audio and ID3
+    continuity: [u8; 4],
+    title: Option<String>,
+}
+
+impl Rendition {
+    /**
+     * A new rendition if HLS is enabled and the codec can be carried in MPEG-TS
+     */
+    pub fn new(properties: &server::Hls, codec: codec::Codec) -> Option<Rendition> {
+        if !properties.enabled || !matches!(codec, codec::Codec::Mpeg | codec::Codec::Adts) {
+            return None;
+        }
+
+        Some(Rendition {
+            codec,
+            target: properties.segment_duration.max(1) * CLOCK,
+            window: properties.segments.max(1),
+            segments: VecDeque::new(),
+            sequence: 0,
+            current: Vec::new(),
+            start: 0,
+            pes: Vec::new(),
+            pes_timestamp: 0,
+            base: 0,
+            samples: 0,
+            sample_rate: 0,
+            continuity: [0; 4],
+            title: None,
+        })
+    }
+
+    /**
+     * Add a whole frame, starting a new segment first if the current one is long enough
+     */
+    pub fn push(&mut self, frame: &[u8]) {
+        let (samples, sample_rate) = match self.codec.samples(frame) {
+            Some(samples) => samples,
+            None => return,
+        };
+        if sample_rate != self.sample_rate {
+            self.base = self.timestamp();
+            self.samples = 0;
+            self.sample_rate = sample_rate;
+        }
+
+        let timestamp = self.timestamp();
+        if !self.current.is_empty() && timestamp - self.start >= self.target {
+            self.finish_segment(timestamp);
+        }
+        if self.current.is_empty() {
+            // Every segment has to stand on its own
+            self.start = timestamp;
+            self.write_tables();
+            if let Some(title) = self.title.clone() {
+                self.write_id3(&title, timestamp);
+            }
+        }
+
+        if self.pes.is_empty() {
+            self.pes_timestamp = timestamp;
+        }
+        self.pes.extend_from_slice(frame);
+        self.samples += samples as u64;
+        if self.pes.len() >= PES_SIZE {
+            self.flush_pes();
+        }
+    }
+
+    /**
+     * Put a new title in the stream at the current position
+     */
+    pub fn set_title(&mut self, title: Option<String>) {
+        if let Some(title) = &title {
+            if !self.current.is_empty() {
+                self.flush_pes();
+                self.write_id3(title, self.timestamp());
+            }
+        }
+        self.title = title;
+    }
+
+    pub fn playlist(&self) -> String {
+        // The target duration can't be less than any segment, once rounded
+        let target = self
+            .segments
+            .iter()
+            .map(|segment| segment.duration.round() as u64)
+            .fold(self.target / CLOCK, u64::max);
+
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            target,
+            self.segments
+                .front()
+                .map(|segment| segment.sequence)
+                .unwrap_or(self.sequence)
+        );
+        for segment in &self.segments {
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\nseg-{}.ts\n",
+                segment.duration, segment.sequence
+            ));
+        }
+        playlist
+    }
+
+    fn segment(&self, sequence: u64) -> Option<Arc<Vec<u8>>> {
+        self.segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| segment.data.clone())
+    }
+
+    fn timestamp(&self) -> u64 {
+        match self.sample_rate {
+            0 => self.base,
+            rate => self.base + self.samples * CLOCK / rate as u64,
+        }
+    }
+
+    fn finish_segment(&mut self, timestamp: u64) {
+        self.flush_pes();
+        self.segments.push_back(Segment {
+            sequence: self.sequence,
+            duration: (timestamp - self.start) as f64 / CLOCK as f64,
+            data: Arc::new(std::mem::take(&mut self.current)),
+        });
+        self.sequence += 1;
+        while self.segments.len() > self.window {
+            self.segments.pop_front();
+        }
+    }
+
+    fn flush_pes(&mut self) {
+        if self.pes.is_empty() {
+            return;
+        }
+        let payload = std::mem::take(&mut self.pes);
+        let pes = pes_packet(0xC0, self.pes_timestamp, &payload);
+        // The audio carries the clock, since there's nothing else to
+        self.write_packets(AUDIO_PID, &pes, Some(self.pes_timestamp));
+    }
+
+    fn write_id3(&mut self, title: &str, timestamp: u64) {
+        // Private stream 1, as the metadata spec asks for
+        let pes = pes_packet(0xBD, timestamp, &id3_tag(title));
+        self.write_packets(ID3_PID, &pes, None);
+    }
+
+    fn write_tables(&mut self) {
+        let mut pat = vec![0x00, 0xB0, 0x00, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01];
+        pat.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+        self.write_section(PAT_PID, pat);
+
+        let stream_type = match self.codec {
+            codec::Codec::Adts => 0x0F,
+            _ => 0x03,
+        };
+        let mut pmt = vec![0x02, 0xB0, 0x00, 0x00, 0x01, 0xC1, 0x00, 0x00];
+        pmt.extend_from_slice(&(0xE000 | AUDIO_PID).to_be_bytes());
+        // The metadata pointer descriptor, pointing to ID3 in this program
+        let program_info = [
+            0x25, 0x0F, 0xFF, 0xFF, b'I', b'D', b'3', b' ', 0xFF, b'I', b'D', b'3', b' ', 0x00,
+            0x1F, 0x00, 0x01,
+        ];
+        pmt.extend_from_slice(&(0xF000 | program_info.len() as u16).to_be_bytes());
+        pmt.extend_from_slice(&program_info);
+        pmt.push(stream_type);
+        pmt.extend_from_slice(&(0xE000 | AUDIO_PID).to_be_bytes());
+        pmt.extend_from_slice(&[0xF0, 0x00]);
+        // Timed metadata, along with its metadata descriptor
+        let metadata = [
+            0x26, 0x0D, 0xFF, 0xFF, b'I', b'D', b'3', b' ', 0xFF, b'I', b'D', b'3', b' ', 0x00,
+            0x0F,
+        ];
+        pmt.push(0x15);
+        pmt.extend_from_slice(&(0xE000 | ID3_PID).to_be_bytes());
+        pmt.extend_from_slice(&(0xF000 | metadata.len() as u16).to_be_bytes());
+        pmt.extend_from_slice(&metadata);
+        self.write_section(PMT_PID, pmt);
+    }
+
+    // Fill in the length and checksum of a table, then write it in a packet of its own
+    fn write_section(&mut self, pid: u16, mut section: Vec<u8>) {
+        let length = (section.len() - 3 + 4) as u16;
+        section[1] = 0xB0 | (length >> 8) as u8;
+        section[2] = length as u8;
+        let crc = codec::crc32(0xFFFF_FFFF, &section);
+        section.extend_from_slice(&crc.to_be_bytes());
+
+        let mut packet = self.packet_header(pid, true, false);
+        // The pointer field
+        packet.push(0);
+        packet.extend_from_slice(&section);
+        packet.resize(PACKET_SIZE, 0xFF);
+        self.current.extend_from_slice(&packet);
+    }
+
+    fn write_packets(&mut self, pid: u16, data: &[u8], pcr: Option<u64>) {
+        let mut position = 0;
+        while position < data.len() {
+            let first = position == 0;
+            let mut adaptation = match pcr {
+                // Random access, with a PCR
+                Some(pcr) if first => {
+                    let mut field = vec![0x50];
+                    field.extend_from_slice(&encode_pcr(pcr));
+                    Some(field)
+                }
+                _ => None,
+            };
+            let room = PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| 1 + field.len());
+            let length = room.min(data.len() - position);
+            if length < room {
+                // Stuff the adaptation field so the payload ends with the packet
+                let size = PACKET_SIZE - 4 - length - 1;
+                let field = adaptation.get_or_insert_with(Vec::new);
+                if field.is_empty() && size > 0 {
+                    field.push(0x00);
+                }
+                field.resize(size, 0xFF);
+            }
+
+            let mut packet = self.packet_header(pid, first, adaptation.is_some());
+            if let Some(field) = adaptation {
+                packet.push(field.len() as u8);
+                packet.extend_from_slice(&field);
+            }
+            packet.extend_from_slice(&data[position..position + length]);
+            position += length;
+            self.current.extend_from_slice(&packet);
+        }
+    }
+
+    fn packet_header(&mut self, pid: u16, start: bool, adaptation: bool) -> Vec<u8> {
+        let index = match pid {
+            PAT_PID => 0,
+            PMT_PID => 1,
+            AUDIO_PID => 2,
+            _ => 3,
+        };
+        let continuity = self.continuity[index];
+        self.continuity[index] = (continuity + 1) & 0x0F;
+
+        let mut header = Vec::with_capacity(PACKET_SIZE);
+        header.push(0x47);
+        header.push(if start { 0x40 } else { 0x00 } | (pid >> 8) as u8 & 0x1F);
+        header.push(pid as u8);
+        header.push(if adaptation { 0x30 } else { 0x10 } | continuity);
+        header
+    }
+}
+
+fn pes_packet(stream_id: u8, timestamp: u64, payload: &[u8]) -> Vec<u8> {
+    let mut pes = vec![0x00, 0x00, 0x01, stream_id];
+    // The length counts from after itself, which leaves the flags and the timestamp
+    let length = 3 + 5 + payload.len();
+    pes.extend_from_slice(&(length.min(u16::MAX as usize) as u16).to_be_bytes());
+    // Only a presentation timestamp
+    pes.extend_from_slice(&[0x80, 0x80, 0x05]);
+    let ts = timestamp & TIMESTAMP_MASK;
+    pes.extend_from_slice(&[
+        0x21 | ((ts >> 29) & 0x0E) as u8,
+        (ts >> 22) as u8,
+        0x01 | ((ts >> 14) & 0xFE) as u8,
+        (ts >> 7) as u8,
+        0x01 | ((ts << 1) & 0xFE) as u8,
+    ]);
+    pes.extend_from_slice(payload);
+    pes
+}
+
+fn encode_pcr(timestamp: u64) -> [u8; 6] {
+    let base = timestamp & TIMESTAMP_MASK;
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        // The reserved bits, with the extension left at zero
+        ((base & 1) << 7) as u8 | 0x7E,
+        0x00,
+    ]
+}
+
+// An ID3v2.4 tag with the title as its only frame
+fn id3_tag(title: &str) -> Vec<u8> {
+    // UTF-8
+    let mut frame = vec![0x03];
+    frame.extend_from_slice(title.as_bytes());
+
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&synchsafe(10 + frame.len()));
+    tag.extend_from_slice(b"TIT2");
+    tag.extend_from_slice(&synchsafe(frame.len()));
+    tag.extend_from_slice(&[0x00, 0x00]);
+    tag.extend_from_slice(&frame);
+    tag
+}
+
+// Sizes in ID3 only use the lower 7 bits of every byte
+fn synchsafe(size: usize) -> [u8; 4] {
+    [
+        ((size >> 21) & 0x7F) as u8,
+        ((size >> 14) & 0x7F) as u8,
+        ((size >> 7) & 0x7F) as u8,
+        (size & 0x7F) as u8,
+    ]
+}
+
+// The sequence number of a segment, or None inside for the playlist
+fn parse_file(file: &str) -> Option<Option<u64>> {
+    match file {
+        PLAYLIST => Some(None),
+        _ => Some(Some(
+            file.strip_prefix("seg-")?
+                .strip_suffix(".ts")?
+                .parse::<u64>()
+                .ok()?,
+        )),
+    }
+}
+
+/**
+ * Whether a file under a mountpoint is its playlist or one of its segments, so it can't be a mountpoint itself
+ */
+pub fn is_rendition_file(file: &str) -> bool {
+    parse_file(file).is_some()
+}
+
+/**
+ * Serve the playlist and segments found under a mountpoint, like /radio/playlist.m3u8 and /radio/seg-0.ts
+ * Returns None if the path isn't one of them
+ */
+pub async fn serve(server: &Arc<RwLock<Server>>, path: &str) -> Option<Response> {
+    let (mount, file) = path.rsplit_once('/')?;
+    let sequence = parse_file(file)?;
+
+    let serv = server.read().await;
+    let source = serv.sources.get(mount)?.read().await;
+    // Segments can't be tied to a listener the hooks know about
+    if serv.properties.listener_auth.applies_to(mount) {
+        return Some(Response::forbidden(Some((
+            "text/plain; charset=utf-8",
+            "HLS is not available on mountpoints with listener authentication",
+        ))));
+    }
+    let rendition = source.hls.as_ref()?;
+
+    match sequence {
+        None => Some(Response::ok(Some((
+            "application/vnd.apple.mpegurl",
+            &rendition.playlist(),
+        )))),
+        Some(sequence) => match rendition.segment(sequence) {
+            Some(data) => {
+                let mut response = Response::ok(None);
+                response.body = Some(("video/mp2t".to_string(), data.to_vec()));
+                Some(response)
+            }
+            None => Some(Response::not_found()),
+        },
+    }
+}
@@ -1,4 +1,4 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Clone)]
 pub struct Properties {
@@ -12,7 +12,7 @@ pub struct Properties {
     pub content_type: String,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Metadata {
     pub title: Option<String>,
     pub url: Option<String>,
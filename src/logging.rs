@@ -0,0 +1,282 @@
+use serde_json::json;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{PoisonError, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::server::{LogFormat, LogLevel, LogTarget, Logging};
+
+// Diagnostics go through the log_* macros instead of straight to stdout
+// Until the config has been read, everything is printed to stdout as text
+
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::server::LogLevel::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::server::LogLevel::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::server::LogLevel::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::server::LogLevel::Debug, format_args!($($arg)*))
+    };
+}
+
+// Syslog messages are sent with the daemon facility
+const SYSLOG_FACILITY: u8 = 3;
+const SYSLOG_TAG: &str = "rusty-zenith";
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+struct Logger {
+    config: Logging,
+    output: Output,
+    access_log: Option<File>,
+}
+
+enum Output {
+    Stdout,
+    File(File),
+    Syslog(UnixDatagram),
+}
+
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+
+/**
+ * Start logging as configured, replacing whatever was used before
+ */
+pub fn init(config: &Logging) -> io::Result<()> {
+    let output = match config.target {
+        LogTarget::Stdout => Output::Stdout,
+        LogTarget::File => Output::File(open(&config.file)?),
+        LogTarget::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(&config.syslog_socket)?;
+            Output::Syslog(socket)
+        }
+    };
+    let access_log = match &config.access_log {
+        Some(path) => Some(open(path)?),
+        None => None,
+    };
+
+    *LOGGER.write().unwrap_or_else(PoisonError::into_inner) = Some(Logger {
+        config: config.clone(),
+        output,
+        access_log,
+    });
+    Ok(())
+}
+
+/**
+ * Open the log files again, so they can be rotated from under the server
+ */
+pub fn reopen() -> io::Result<()> {
+    let config = match &*LOGGER.read().unwrap_or_else(PoisonError::into_inner) {
+        Some(logger) => logger.config.clone(),
+        None => return Ok(()),
+    };
+    init(&config)
+}
+
+fn open(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+pub fn log(level: LogLevel, args: fmt::Arguments<'_>) {
+    let logger = LOGGER.read().unwrap_or_else(PoisonError::into_inner);
+    let logger = match &*logger {
+        Some(logger) if level > logger.config.level => return,
+        Some(logger) => logger,
+        None => {
+            if level <= LogLevel::Info {
+                print!("{}", line(LogFormat::Text, level, &args.to_string()));
+            }
+            return;
+        }
+    };
+
+    let message = args.to_string();
+    match &logger.output {
+        Output::Stdout => {
+            let line = line(logger.config.format, level, &message);
+            drop(io::stdout().lock().write_all(line.as_bytes()));
+        }
+        Output::File(file) => {
+            let line = line(logger.config.format, level, &message);
+            drop((&*file).write_all(line.as_bytes()));
+        }
+        Output::Syslog(socket) => {
+            // Syslog keeps its own time
+            let message = match logger.config.format {
+                LogFormat::Text => message,
+                LogFormat::Json => {
+                    json!( { "level": name(level), "message": message } ).to_string()
+                }
+            };
+            let packet = format!(
+                "<{}>{}[{}]: {}",
+                SYSLOG_FACILITY * 8 + severity(level),
+                SYSLOG_TAG,
+                std::process::id(),
+                message
+            );
+            if socket.send(packet.as_bytes()).is_err() {
+                // The syslog daemon might have been restarted since
+                drop(
+                    socket
+                        .connect(&logger.config.syslog_socket)
+                        .and_then(|_| socket.send(packet.as_bytes())),
+                );
+            }
+        }
+    }
+}
+
+fn line(format: LogFormat, level: LogLevel, message: &str) -> String {
+    let time = civil_time(now());
+    let time = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        time.0, time.1, time.2, time.3, time.4, time.5
+    );
+    match format {
+        LogFormat::Text => format!("{} {:<5} {}\n", time, name(level).to_uppercase(), message),
+        LogFormat::Json => format!(
+            "{}\n",
+            json!( { "time": time, "level": name(level), "message": message } )
+        ),
+    }
+}
+
+fn name(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Error => "error",
+        LogLevel::Warn => "warn",
+        LogLevel::Info => "info",
+        LogLevel::Debug => "debug",
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc5424#section-6.2.1
+fn severity(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 3,
+        LogLevel::Warn => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug => 7,
+    }
+}
+
+// A finished listener session, as written to the access log
+pub struct Access<'a> {
+    pub ip: Option<IpAddr>,
+    pub user: Option<&'a str>,
+    // The request line, such as GET /radio HTTP/1.1
+    pub request: &'a str,
+    pub status: u16,
+    pub bytes_sent: usize,
+    pub referer: Option<&'a str>,
+    pub agent: Option<&'a str>,
+    // When the session started, in seconds since the epoch
+    pub start_time: u64,
+}
+
+/**
+ * Write a session to the access log in Combined Log Format, followed by its duration in seconds like Icecast
+ */
+pub fn access(access: &Access<'_>) {
+    let logger = LOGGER.read().unwrap_or_else(PoisonError::into_inner);
+    let mut file: &File = match &*logger {
+        Some(Logger {
+            access_log: Some(file),
+            ..
+        }) => file,
+        _ => return,
+    };
+
+    let time = civil_time(access.start_time);
+    let line = format!(
+        "{} - {} [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{}\" {} {} \"{}\" \"{}\" {}\n",
+        access
+            .ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        access.user.unwrap_or("-"),
+        time.2,
+        MONTHS[time.1 as usize - 1],
+        time.0,
+        time.3,
+        time.4,
+        time.5,
+        escape(access.request),
+        access.status,
+        access.bytes_sent,
+        escape(access.referer.unwrap_or("-")),
+        escape(access.agent.unwrap_or("-")),
+        now().saturating_sub(access.start_time)
+    );
+    drop(file.write_all(line.as_bytes()));
+}
+
+// Quotes and control characters would break up the fields
+fn escape(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| match c {
+            '"' => vec!['\\', '"'],
+            '\\' => vec!['\\', '\\'],
+            c if c.is_control() => vec!['?'],
+            c => vec![c],
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/**
+ * Split seconds since the epoch into the year, month, day, hour, minute and second in UTC
+ * http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+ */
+fn civil_time(secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let seconds = secs % 86400;
+    // Shifted so eras start on the 1st of March, 0000
+    let days = secs / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    )
+}
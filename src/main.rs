@@ -2,910 +2,1176 @@ use httpdate::fmt_http_date;
 use httparse::Status;
 use regex::Regex;
 use serde::Deserialize;
-use serde_json::{ json, Value };
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use std::error::Error;
 use std::fs::File;
 use std::io::{ BufWriter, ErrorKind, Write };
 use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::Arc;
-use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{ AsRawFd, FromRawFd, RawFd };
+use std::sync::{ Arc, Weak };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
 use tokio::io::{ AsyncReadExt, AsyncWriteExt };
-use tokio::net::{ TcpListener, TcpStream };
+use tokio::net::{ TcpListener, TcpStream, UnixListener };
+use tokio::signal::unix::{ signal, SignalKind };
 use tokio::sync::RwLock;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::timeout;
-use tokio_native_tls::native_tls::TlsConnector;
+use tokio_native_tls::TlsAcceptor;
+use tokio_native_tls::native_tls::{ Identity, TlsConnector };
 use url::Url;
 use uuid::Uuid;
+// The longest a relay or push target waits before reconnecting, however many attempts have failed
+const MAX_RETRY_INTERVAL: u64 = 300;
+// How many chunks a push target can fall behind before it skips ahead
+const PUSH_QUEUE_LENGTH: usize = 1024;
+
+#[macro_use]
+mod logging;
+mod api;
 mod request;
 mod response;
 mod icy;
 mod stream_decoder;
 mod source;
 mod client;
+mod codec;
+mod ogg;
+mod hls;
+mod events;
+mod metrics;
 mod server;
+mod ultravox;
+mod shutdown;
+
+/**
+ * Read until a full request head is buffered, some of it may already be there from a previous request
+ * Returns None if the connection was closed before a new request started
+ */
+async fn read_request( stream: &mut response::Stream, message: &mut Vec< u8 >, http_max_len: usize ) -> Result< Option< usize >, Box< dyn Error > > {
+    let mut buf = [ 0; 1024 ];
+    loop {
+        if !message.is_empty() {
+            let mut headers = [ httparse::EMPTY_HEADER; 32 ];
+            let mut req = httparse::Request::new( &mut headers );
+            match req.parse( message ) {
+                Ok( Status::Complete( offset ) ) => return Ok( Some( offset ) ),
+                Ok( Status::Partial ) if message.len() > http_max_len => return Err( Box::new( std::io::Error::other( "Request exceeded the maximum allowed length" ) ) ),
+                Ok( Status::Partial ) => (),
+                Err( e ) => return Err( Box::new( std::io::Error::new( ErrorKind::InvalidData, format!( "Received an invalid request: {}", e ) ) ) )
+            }
+        }
+
+        let read = stream.read( &mut buf ).await?;
+        if read == 0 {
+            return Ok( None );
+        }
+        message.extend_from_slice( &buf[ .. read ] );
+    }
+}
+
+/**
+ * Throw away the body of a request that was answered without reading it
+ */
+async fn discard_body( stream: &mut response::Stream, message: &mut Vec< u8 >, mut length: usize ) -> Result< (), Box< dyn Error > > {
+    if message.len() >= length {
+        message.drain( .. length );
+        return Ok( () );
+    }
+
+    length -= message.len();
+    message.clear();
+    let mut buf = [ 0; 1024 ];
+    while length > 0 {
+        let read = stream.read( &mut buf ).await?;
+        if read == 0 {
+            return Err( Box::new( std::io::Error::new( ErrorKind::UnexpectedEof, "Connection closed before the request body was read" ) ) );
+        }
+        if read > length {
+            // The start of the next request
+            message.extend_from_slice( &buf[ length .. read ] );
+            length = 0;
+        } else {
+            length -= read;
+        }
+    }
+
+    Ok( () )
+}
 
-async fn handle_connection( server: Arc< RwLock< server::Server > >, mut stream: TcpStream ) -> Result< (), Box< dyn Error > > {
+async fn handle_connection( server: Arc< RwLock< server::Server > >, mut stream: response::Stream, peer: Option< SocketAddr >, socket: Arc< server::ListenSocket > ) -> Result< (), Box< dyn Error > > {
     let ( server_id, header_timeout, http_max_len ) = {
         let properties = &server.read().await.properties;
         ( properties.server_id.clone(), properties.limits.header_timeout, properties.limits.http_max_length )
     };
 
     let mut message = Vec::new();
-    let mut buf = [ 0; 1024 ];
+    let mut first = true;
 
-    // Add a timeout
-    timeout( Duration::from_millis( header_timeout ), async {
-        loop {
-            let mut headers = [ httparse::EMPTY_HEADER; 32 ];
-            let mut req = httparse::Request::new( &mut headers );
-            let read = stream.read( &mut buf ).await?;
-            message.extend_from_slice( &buf[ .. read ] );
-            match req.parse( &message ) {
-                Ok( Status::Complete( offset ) ) => return Ok( offset ),
-                Ok( Status::Partial ) if message.len() > http_max_len => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Request exceeded the maximum allowed length" ) ) ),
-                Ok( Status::Partial ) => (),
-                Err( e ) => return Err( Box::new( std::io::Error::new( ErrorKind::InvalidData, format!( "Received an invalid request: {}", e ) ) ) )
+    loop {
+        // Idle keep alive connections get the same timeout as new ones
+        let body_offset = match timeout( Duration::from_millis( header_timeout ), read_request( &mut stream, &mut message, http_max_len ) ).await {
+            Ok( Ok( Some( offset ) ) ) => offset,
+            Ok( Ok( None ) ) => return Ok( () ),
+            Ok( Err( e ) ) => return Err( e ),
+            Err( _ ) if !first => return Ok( () ),
+            Err( e ) => return Err( Box::new( e ) )
+        };
+        first = false;
+
+        let mut _headers = [ httparse::EMPTY_HEADER; 32 ];
+        let mut parsed = httparse::Request::new( &mut _headers );
+        parsed.parse( &message )?;
+        let req = request::Request::new( parsed );
+
+        // Pretend anything the socket doesn't serve doesn't exist
+        let role = match req.method {
+            "SOURCE" | "PUT" => server::SocketRole::Source,
+            // Only SHOUTcast encoders update metadata through here
+            _ if req.path == "/admin.cgi" => server::SocketRole::Source,
+            _ if req.path == "/admin" || req.path.starts_with( "/admin/" ) => server::SocketRole::Admin,
+            _ if req.path == "/api" || req.path.starts_with( "/api/" ) || req.path == "/metrics" => server::SocketRole::Api,
+            _ => server::SocketRole::Listener
+        };
+        if !socket.allows( role ) {
+            let response = match role {
+                server::SocketRole::Source => response::Response::forbidden( Some( ( "text/plain; charset=utf-8", "Sources are not accepted on this port" ) ) ),
+                _ => response::Response::not_found()
+            };
+            return response::send_response( &mut stream, &server_id, &response, req.version, false, false ).await;
+        }
+
+        // http://example.com/radio == http://example.com/radio/
+        let source_id = req.path.strip_suffix( '/' ).unwrap_or( &req.path ).to_string();
+
+        let head = req.method == "HEAD";
+        let response = match req.method {
+            // Some info about the protocol is provided here: https://gist.github.com/ePirat/adc3b8ba00d85b7e3870
+            "SOURCE" | "PUT" => return handle_source( &server, &mut stream, &server_id, &req, &message[ body_offset .. ] ).await,
+            "GET" | "HEAD" if listenable( &*server.read().await, &source_id, head ) => {
+                if head {
+                    // Only describe the stream, there's nothing to follow the headers
+                    let serv = server.read().await;
+                    if let Some( source ) = serv.sources.get( &source_id ) {
+                        let meta_enabled = request::get_header( "Icy-MetaData", req.headers ).unwrap_or( b"0" ) == b"1";
+                        response::send_listener_ok( &mut stream, &server_id, &source.read().await.properties, meta_enabled, serv.properties.metaint ).await?;
+                    }
+                    return Ok( () );
+                }
+                return handle_listener( &server, &mut stream, &server_id, peer, &socket, &req, source_id ).await;
             }
+            // Events are pushed for as long as the subscriber stays
+            "GET" | "HEAD" if req.path == "/api/events" => return events::serve( &server, &mut stream, &server_id, &req ).await,
+            "GET" | "HEAD" => match api::find_route( &req.path ) {
+                Some( handler ) => match handler( &server, &req ).await {
+                    Ok( response ) => response,
+                    Err( e ) => {
+                        log_error!( "An error occurred while handling {}: {}", req.path, e );
+                        response::Response::internal_error( None )
+                    }
+                },
+                // HLS playlists and segments live under their mountpoint
+                None => match hls::serve( &server, &req.path ).await {
+                    Some( response ) => response,
+                    None => response::Response::not_found()
+                }
+            },
+            "OPTIONS" => response::Response::options(),
+            _ => response::Response::method_not_allowed()
+        };
+
+        // Bodies are only skipped when their length is known up front
+        let body_length = match ( request::get_header( "Transfer-Encoding", req.headers ), request::get_header( "Content-Length", req.headers ) ) {
+            ( None, Some( value ) ) => std::str::from_utf8( value ).ok().and_then( | value | value.trim().parse::< usize >().ok() ),
+            ( None, None ) => Some( 0 ),
+            _ => None
+        };
+        let keep_alive = req.keep_alive() && body_length.is_some_and( | length | length <= http_max_len );
+
+        response::send_response( &mut stream, &server_id, &response, req.version, keep_alive, head ).await?;
+
+        if !keep_alive {
+            return Ok( () );
         }
-    } ).await??;
 
-    let mut _headers = [ httparse::EMPTY_HEADER; 32 ];
-    let mut req = httparse::Request::new( &mut _headers );
-    let body_offset = req.parse( &message )?.unwrap();
-    let method = req.method.unwrap();
+        // Move on to whatever was sent after this request
+        drop( req );
+        discard_body( &mut stream, &mut message, body_offset + body_length.unwrap_or( 0 ) ).await?;
+    }
+}
 
-    let ( base_path, queries ) = request::extract_queries( req.path.unwrap() );
-    let path = path_clean::clean( base_path );
+async fn handle_source( server: &Arc< RwLock< server::Server > >, stream: &mut response::Stream, server_id: &str, req: &request::Request< '_ >, body: &[ u8 ] ) -> Result< (), Box< dyn Error > > {
+    let method = req.method;
     let headers = req.headers;
 
-    match method {
-        // Some info about the protocol is provided here: https://gist.github.com/ePirat/adc3b8ba00d85b7e3870
-        "SOURCE" | "PUT" => {
-            // Check for authorization
-            if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                if !server::validate_user( &server.read().await.properties, name, pass ) {
-                    // Invalid user/pass provided
-                    return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=urf-8", "Invalid credentials" ) ) ).await;
+    // Check for authorization
+    let user = match api::authenticate( server, headers ).await {
+        Ok( user ) => user,
+        Err( response ) => return response::send_response( stream, server_id, &response, req.version, false, false ).await
+    };
+
+    // Not sure if the trailing '/' is removed client-side prior to sending the request though
+    let path = req.path.strip_suffix( '/' ).unwrap_or( &req.path ).to_string();
+
+    // Check if it is valid
+    let valid = server.read().await.properties.validate_mount( &path );
+    if let Err( message ) = valid {
+        let response = response::Response::forbidden( Some( ( "text/plain; charset=utf-8", &message ) ) );
+        return response::send_response( stream, server_id, &response, req.version, false, false ).await;
+    }
+
+    // Check if the user is allowed to mount here
+    if !user.has_permission( server::Permission::Source, Some( &path ) ) {
+        return response::send_response( stream, server_id, &api::missing_permission( server::Permission::Source, Some( &path ) ), req.version, false, false ).await;
+    }
+
+    // Sources must have a content type
+    // Maybe the type that is served should be checked?
+    let mut properties = match request::get_header( "Content-Type", headers ) {
+        Some( content_type ) => icy::Properties::new( std::str::from_utf8( content_type )?.to_string() ),
+        None => {
+            let response = response::Response::forbidden( Some( ( "text/plain; charset=utf-8", "No Content-type provided" ) ) );
+            return response::send_response( stream, server_id, &response, req.version, false, false ).await;
+        }
+    };
+
+    let decoder: stream_decoder::StreamDecoder;
+
+    if method == "SOURCE" {
+        decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Identity );
+    } else {
+        // Verify that the transfer encoding is identity or not included
+        // No support for chunked or encoding ATM
+        // TODO Add support for transfer encoding options as specified here: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Transfer-Encoding
+        let bad_request = | message | response::Response::bad_request( Some( ( "text/plain; charset=utf-8", message ) ) );
+        match ( request::get_header( "Transfer-Encoding", headers ), request::get_header( "Content-Length", headers ) ) {
+            ( Some( b"identity"), Some( value ) ) | ( None, Some( value ) ) => {
+                // Use content length decoder
+                match std::str::from_utf8( value ) {
+                    Ok( string ) => {
+                        match string.parse::< usize >() {
+                            Ok( length ) => decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Length( length ) ),
+                            Err( _ ) => return response::send_response( stream, server_id, &bad_request( "Invalid Content-Length" ), req.version, false, false ).await
+                        }
+                    }
+                    Err( _ ) => return response::send_response( stream, server_id, &bad_request( "Unknown unicode found in Content-Length" ), req.version, false, false ).await
                 }
-            } else {
-                // No auth, return and close
-                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=urf-8", "You need to authenticate" ) ) ).await;
             }
+            ( Some( b"chunked" ), None ) => {
+                // Use chunked decoder
+                decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Chunked );
+            }
+            ( Some( b"identity" ), None ) | ( None, None ) => {
+                // Use identity
+                decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Identity );
+            }
+            _ => return response::send_response( stream, server_id, &bad_request( "Unsupported transfer encoding" ), req.version, false, false ).await
+        }
 
-            // http://example.com/radio == http://example.com/radio/
-            // Not sure if this is done client-side prior to sending the request though
-            let path = {
-                // Remove the trailing '/'
-                if path.ends_with( '/' ) {
-                    let mut chars = path.chars();
-                    chars.next_back();
-                    chars.collect()
-                } else {
-                    path
+        // Check if client sent Expect: 100-continue in header, if that's the case we will need to return 100 in status code
+        // Without it, it means that client has no body to send, we will stop if that's the case
+        match request::get_header( "Expect", headers ) {
+            Some( b"100-continue" ) => (),
+            Some( _ ) => return response::send_response( stream, server_id, &bad_request( "Expected 100-continue in Expect header" ), req.version, false, false ).await,
+            None => return response::send_response( stream, server_id, &bad_request( "PUT request must come with Expect header" ), req.version, false, false ).await
+        }
+    }
+
+    // Parse the headers for the source properties
+    icy::populate_properties( &mut properties, headers );
+
+    // Asked for with ?hidden, or ?hidden=1
+    let hidden = match &req.queries {
+        Some( queries ) => request::get_queries_for( vec![ "hidden" ], queries )[ 0 ].as_ref().is_some_and( | value | value != "0" && value != "false" ),
+        None => false
+    };
+
+    let arc = match mount_source( server, &path, properties, hidden ).await {
+        Ok( arc ) => arc,
+        Err( message ) => {
+            let response = response::Response::forbidden( Some( ( "text/plain; charset=utf-8", message ) ) );
+            return response::send_response( stream, server_id, &response, req.version, false, false ).await;
+        }
+    };
+
+    // The error is turned into a string so it isn't held across the cleanup
+    let sent = if method == "SOURCE" {
+        // Give an 200 OK response
+        response::send_ok( stream, server_id, None ).await
+    } else {
+        response::send_continue( stream, server_id ).await
+    }.map_err( | e | e.to_string() );
+    if let Err( e ) = sent {
+        // The source still has to be unmounted
+        log_error!( "An error occurred while accepting source {}: {}", path, e );
+        arc.write().await.disconnect_flag = true;
+    }
+
+    log_info!( "Mounted source on {} via {}", path, method );
+
+    broadcast_source( server, stream, &arc, decoder, body ).await;
+
+    if method == "PUT" {
+        // request must end with server 200 OK response
+        response::send_ok( stream, server_id, None ).await.ok();
+    }
+
+    log_info!( "Unmounted source {}", path );
+
+    Ok( () )
+}
+
+/**
+ * Add a new source to the server, if the mountpoint is free and the source limits allow it
+ */
+async fn mount_source( server: &Arc< RwLock< server::Server > >, path: &str, properties: icy::Properties, hidden: bool ) -> Result< Arc< RwLock< source::Source > >, &'static str > {
+    let mut serv = server.write().await;
+    if serv.shutting_down {
+        return Err( "The server is shutting down" );
+    }
+
+    // Check if the mountpoint is already in use
+    if serv.sources.contains_key( path ) {
+        return Err( "Invalid mountpoint" );
+    }
+
+    // Check if the max number of sources has been reached
+    if serv.source_count >= serv.properties.limits.sources || serv.sources.len() >= serv.properties.limits.total_sources {
+        return Err( "Too many sources connected" );
+    }
+
+    // Add to the server
+    let mut source = new_source( &serv, path, properties );
+    source.hidden = hidden;
+    let arc = Arc::new( RwLock::new( source ) );
+    start_pushes( server, &serv.properties, &arc ).await;
+    serv.sources.insert( path.to_string(), arc.clone() );
+    serv.source_count += 1;
+    drop( serv.events.send( events::Event::new( path, events::Kind::Connect ) ) );
+
+    Ok( arc )
+}
+
+/**
+ * A source along with everything it needs from the server to produce its output
+ */
+fn new_source( serv: &server::Server, mount: &str, properties: icy::Properties ) -> source::Source {
+    let mut source = source::Source::new( mount.to_string(), properties, serv.properties.source_limits( mount ) );
+    source.hls = hls::Rendition::new( &serv.properties.hls, source.framer.codec() );
+    source.events = Some( serv.events.clone() );
+    source
+}
+
+/**
+ * Pass along everything a mounted source sends until it disconnects
+ * Its listeners are then moved to the fallback, and the source is removed from the server
+ */
+async fn broadcast_source( server: &Arc< RwLock< server::Server > >, stream: &mut response::Stream, arc: &Arc< RwLock< source::Source > >, mut decoder: stream_decoder::StreamDecoder, body: &[ u8 ] ) {
+    if !body.is_empty() && !arc.read().await.disconnect_flag {
+        let mut data = Vec::new();
+        match decoder.decode( &mut data, body, body.len() ) {
+            Ok( read ) => {
+                if read != 0 {
+                    broadcast_to_clients( arc, data ).await;
+                    arc.read().await.stats.write().await.bytes_read += read;
+                }
+            }
+            Err( e ) => {
+                log_error!( "An error occurred while decoding stream data from source {}: {}", arc.read().await.mountpoint, e );
+                arc.write().await.disconnect_flag = true;
+            }
+        }
+    }
+
+    // Listen for bytes
+    if !decoder.is_finished() && !arc.read().await.disconnect_flag {
+        while {
+            // Read the incoming stream data until it closes
+            let mut buf = [ 0; 1024 ];
+            // Looked up every time, since the config can be reloaded
+            let source_timeout = arc.read().await.limits.source_timeout;
+            let read = match timeout( Duration::from_millis( source_timeout ), stream.read( &mut buf ) ).await {
+                Ok( Ok( n ) ) => n,
+                Ok( Err( e ) ) => {
+                    log_error!( "An error occurred while reading stream data from source {}: {}", arc.read().await.mountpoint, e );
+                    0
+                }
+                Err( _ ) => {
+                    log_warn!( "A source timed out: {}", arc.read().await.mountpoint );
+                    0
                 }
             };
 
-            // Check if the path contains 'admin' or 'api'
-            // TODO Allow for custom stream directory, such as http://example.com/stream/radio
-            if path == "/admin" ||
-                    path.starts_with( "/admin/" ) ||
-                    path == "/api" ||
-                    path.starts_with( "/api/" ) {
-                return response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mountpoint" ) ) ).await;
-            }
-
-            // Check if it is valid
-            // For now this assumes the stream directory is /
-            let dir = Path::new( &path );
-            if let Some( parent ) = dir.parent() {
-                if let Some( parent_str ) = parent.to_str() {
-                    if parent_str != "/" {
-                        return response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mountpoint" ) ) ).await;
+            let mut data = Vec::new();
+            match decoder.decode( &mut data, &buf, read ) {
+                Ok( decode_read ) => {
+                    if decode_read != 0 {
+                        broadcast_to_clients( arc, data ).await;
+                        arc.read().await.stats.write().await.bytes_read += decode_read;
                     }
-                } else {
-                    return response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mountpoint" ) ) ).await;
+
+                    // Check if the source needs to be disconnected
+                    read != 0 && !decoder.is_finished() && !arc.read().await.disconnect_flag
+                }
+                Err( e ) => {
+                    log_error!( "An error occurred while decoding stream data from source {}: {}", arc.read().await.mountpoint, e );
+                    false
                 }
-            } else {
-                return response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mountpoint" ) ) ).await;
             }
+        }  {}
+    }
 
-            // Sources must have a content type
-            // Maybe the type that is served should be checked?
-            let mut properties = match request::get_header( "Content-Type", headers ) {
-                Some( content_type ) => icy::Properties::new( std::str::from_utf8( content_type )?.to_string() ),
-                None => {
-                    return response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "No Content-type provided" ) ) ).await;
-                }
-            };
+    unmount_source( server, arc, false ).await;
+}
 
-            let mut serv = server.write().await;
-            // Check if the mountpoint is already in use
-            if serv.sources.contains_key( &path ) {
-                return response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mountpoint" ) ) ).await;
+/**
+ * Add to what a listener was sent, and to the source it's currently on
+ */
+async fn count_bytes_sent( server: &Arc< RwLock< server::Server > >, arc_client: &Arc< RwLock< client::Client > >, sent: usize ) {
+    let client = arc_client.read().await;
+    client.stats.write().await.bytes_sent += sent;
+    let mount = client.source.read().await.clone();
+    if let Some( source ) = server.read().await.sources.get( &mount ) {
+        source.read().await.stats.write().await.bytes_sent += sent;
+    }
+}
+
+/**
+ * Hand the listeners of a disconnected source to its fallback, then remove it from the server
+ * Relays count towards the relay limit instead of the source limit
+ */
+async fn unmount_source( server: &Arc< RwLock< server::Server > >, arc: &Arc< RwLock< source::Source > >, relay: bool ) {
+    let mut source = arc.write().await;
+    // Taken before the listeners are handed over
+    let info = api::mount_info( &source ).await;
+    let event_sender = server.read().await.events.clone();
+    let fallback = source.fallback.clone();
+    if let Some( fallback_id ) = fallback {
+        if let Some( fallback_source ) = server.read().await.sources.get( &fallback_id ) {
+            log_info!( "Moving listeners from {} to {}", source.mountpoint, fallback_id );
+            let mut fallback = fallback_source.write().await;
+            for ( uuid, client ) in source.clients.drain() {
+                *client.read().await.source.write().await = fallback_id.clone();
+                fallback.clients.insert( uuid, client );
             }
+            drop( event_sender.send( events::Event::new( &source.mountpoint, events::Kind::Fallback( fallback_id.clone() ) ) ) );
+        } else {
+            log_warn!( "No fallback source {} found! Disconnecting listeners on {}", fallback_id, source.mountpoint );
+            for cli in source.clients.values() {
+                // Send an empty vec to signify the channel is closed
+                drop( cli.read().await.sender.write().await.send( Arc::new( Vec::new() ) ) );
+            }
+        }
+    } else {
+        // Disconnect each client by sending an empty buffer
+        log_info!( "Disconnecting listeners on {}", source.mountpoint );
+        for cli in source.clients.values() {
+            // Send an empty vec to signify the channel is closed
+            drop( cli.read().await.sender.write().await.send( Arc::new( Vec::new() ) ) );
+        }
+    }
 
-            // Check if the max number of sources has been reached
-            if serv.source_count >= serv.properties.limits.sources || serv.sources.len() >= serv.properties.limits.total_sources {
-                return response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Too many sources connected" ) ) ).await;
+    // Clean up and remove the source
+    source.forward = None;
+    let mut serv = server.write().await;
+    serv.sources.remove( &source.mountpoint );
+    if relay {
+        serv.relay_count -= 1;
+    } else {
+        serv.source_count -= 1;
+    }
+    drop( serv.events.send( events::Event::new( &source.mountpoint, events::Kind::Disconnect( info ) ) ) );
+    serv.stats.session_bytes_read += source.stats.read().await.bytes_read;
+    serv.stats.session_listeners_dropped += source.stats.read().await.listeners_dropped;
+}
+
+/**
+ * Take the next line out of the buffer, reading more until there is one
+ */
+async fn read_line( stream: &mut response::Stream, message: &mut Vec< u8 >, max_len: usize ) -> Result< String, Box< dyn Error > > {
+    let mut buf = [ 0; 1024 ];
+    loop {
+        if let Some( end ) = message.iter().position( | &byte | byte == b'\n' ) {
+            let line: Vec< u8 > = message.drain( ..= end ).collect();
+            let line = std::str::from_utf8( &line )?;
+            return Ok( line.trim_end_matches( [ '\r', '\n' ] ).to_string() );
+        }
+        if message.len() > max_len {
+            return Err( Box::new( std::io::Error::other( "Request exceeded the maximum allowed length" ) ) );
+        }
+
+        let read = stream.read( &mut buf ).await?;
+        if read == 0 {
+            return Err( Box::new( std::io::Error::new( ErrorKind::UnexpectedEof, "Connection closed during the handshake" ) ) );
+        }
+        message.extend_from_slice( &buf[ .. read ] );
+    }
+}
+
+/**
+ * SHOUTcast v1 encoders send their password on a line of its own, followed by icy headers once it is accepted
+ * There is no mountpoint in the handshake, so the configured one is always used
+ */
+async fn handle_shoutcast_source( server: Arc< RwLock< server::Server > >, mut stream: response::Stream, peer: SocketAddr ) -> Result< (), Box< dyn Error > > {
+    let ( header_timeout, http_max_len, shoutcast ) = {
+        let properties = &server.read().await.properties;
+        ( properties.limits.header_timeout, properties.limits.http_max_length, properties.shoutcast.clone() )
+    };
+
+    let mut message = Vec::new();
+    let mut buf = [ 0; 1024 ];
+    let read = timeout( Duration::from_millis( header_timeout ), stream.read( &mut buf ) ).await??;
+    message.extend_from_slice( &buf[ .. read ] );
+
+    // SHOUTcast v2 sources start with an Ultravox message instead of a password line
+    if ultravox::is_ultravox( &message ) {
+        return handle_ultravox_source( server, stream, peer, message ).await;
+    }
+
+    let password = timeout( Duration::from_millis( header_timeout ), read_line( &mut stream, &mut message, http_max_len ) ).await??;
+
+    // Like SHOUTcast v2 servers, v1 sources can pick a stream ID with password:#id
+    let ( password, stream_id ) = match password.rsplit_once( ":#" ).and_then( | ( password, id ) | Some( ( password.to_string(), id.parse::< u16 >().ok()? ) ) ) {
+        Some( ( password, stream_id ) ) => ( password, stream_id ),
+        None => ( password, 1 )
+    };
+    let mount = match shoutcast.mount_for( stream_id ) {
+        Some( mount ) => mount.to_string(),
+        None => {
+            log_warn!( "SHOUTcast source from {} asked for unknown stream ID {}", peer, stream_id );
+            stream.write_all( b"invalid password\r\n" ).await?;
+            return Ok( () );
+        }
+    };
+
+    let user = server::validate_user( &server, &shoutcast.username, &password ).await;
+    match user {
+        Some( user ) if user.has_permission( server::Permission::Source, Some( &mount ) ) => (),
+        Some( _ ) => {
+            // The protocol has no other way of refusing an encoder
+            log_warn!( "SHOUTcast source from {} is missing the permission to mount {}", peer, mount );
+            stream.write_all( b"invalid password\r\n" ).await?;
+            return Ok( () );
+        }
+        None => {
+            log_warn!( "SHOUTcast source from {} sent an invalid password", peer );
+            stream.write_all( b"invalid password\r\n" ).await?;
+            return Ok( () );
+        }
+    }
+
+    stream.write_all( b"OK2\r\nicy-caps:11\r\n\r\n" ).await?;
+
+    // The icy headers end with an empty line, anything after that is stream data
+    let lines = timeout( Duration::from_millis( header_timeout ), async {
+        let mut lines = Vec::new();
+        let mut length = 0;
+        loop {
+            let line = read_line( &mut stream, &mut message, http_max_len ).await?;
+            if line.is_empty() {
+                return Ok::< _, Box< dyn Error > >( lines );
+            }
+            length += line.len();
+            if length > http_max_len {
+                return Err( Box::new( std::io::Error::other( "Request exceeded the maximum allowed length" ) ) );
             }
+            lines.push( line );
+        }
+    } ).await??;
+    let headers: Vec< httparse::Header > = lines.iter()
+        .filter_map( | line | line.split_once( ':' ) )
+        .map( | ( name, value ) | httparse::Header { name: name.trim(), value: value.trim().as_bytes() } )
+        .collect();
+
+    // Most encoders only send a content type for anything that isn't mp3
+    let content_type = request::get_header( "Content-Type", &headers )
+        .and_then( | value | std::str::from_utf8( value ).ok() )
+        .unwrap_or( "audio/mpeg" )
+        .to_string();
+    let mut properties = icy::Properties::new( content_type );
+    icy::populate_properties( &mut properties, &headers );
+
+    let arc = match mount_source( &server, &mount, properties, false ).await {
+        Ok( arc ) => arc,
+        Err( message ) => {
+            log_error!( "Unable to mount the SHOUTcast source from {} on {}: {}", peer, mount, message );
+            return Ok( () );
+        }
+    };
 
-            let mut decoder: stream_decoder::StreamDecoder;
+    log_info!( "Mounted source on {} via SHOUTcast", mount );
 
-            if method == "SOURCE" {
-                // Give an 200 OK response
-                response::send_ok( &mut stream, &server_id, None ).await?;
+    let decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Identity );
+    broadcast_source( &server, &mut stream, &arc, decoder, &message ).await;
 
-                decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Identity );
-            } else {
-                // Verify that the transfer encoding is identity or not included
-                // No support for chunked or encoding ATM
-                // TODO Add support for transfer encoding options as specified here: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Transfer-Encoding
-                match ( request::get_header( "Transfer-Encoding", headers ), request::get_header( "Content-Length", headers ) ) {
-                    ( Some( b"identity"), Some( value ) ) | ( None, Some( value ) ) => {
-                        // Use content length decoder
-                        match std::str::from_utf8( value ) {
-                            Ok( string ) => {
-                                match string.parse::< usize >() {
-                                    Ok( length ) => decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Length( length ) ),
-                                    Err( _ ) => return response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid Content-Length" ) ) ).await
-                                }
-                            }
-                            Err( _ ) => return response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Unknown unicode found in Content-Length" ) ) ).await
-                        }
+    log_info!( "Unmounted source {}", mount );
+
+    Ok( () )
+}
+
+/**
+ * Take the next Ultravox message out of the buffer, reading more until there is one
+ * Returns None if the connection was closed
+ */
+async fn read_ultravox( stream: &mut response::Stream, message: &mut Vec< u8 > ) -> Result< Option< ultravox::Message >, Box< dyn Error > > {
+    let mut buf = [ 0; 1024 ];
+    loop {
+        if let Some( ( decoded, length ) ) = ultravox::decode( message )? {
+            message.drain( .. length );
+            return Ok( Some( decoded ) );
+        }
+
+        let read = stream.read( &mut buf ).await?;
+        if read == 0 {
+            return Ok( None );
+        }
+        message.extend_from_slice( &buf[ .. read ] );
+    }
+}
+
+/**
+ * SHOUTcast v2 sources negotiate everything over Ultravox messages, then keep sending audio and metadata in them
+ * The stream ID they authenticate with decides the mountpoint
+ */
+async fn handle_ultravox_source( server: Arc< RwLock< server::Server > >, mut stream: response::Stream, peer: SocketAddr, mut message: Vec< u8 > ) -> Result< (), Box< dyn Error > > {
+    let ( header_timeout, shoutcast ) = {
+        let properties = &server.read().await.properties;
+        ( properties.limits.header_timeout, properties.shoutcast.clone() )
+    };
+
+    let mut mount: Option< String > = None;
+    let mut content_type = "audio/mpeg".to_string();
+    let mut icy_headers: Vec< ( &str, String ) > = Vec::new();
+
+    // The handshake ends once the source is mounted in standby
+    let arc = loop {
+        let received = match timeout( Duration::from_millis( header_timeout ), read_ultravox( &mut stream, &mut message ) ).await?? {
+            Some( received ) => received,
+            None => return Ok( () )
+        };
+        let text = received.text();
+
+        let reply = match received.kind {
+            ultravox::CIPHER_KEY => format!( "ACK:{}", shoutcast.cipher_key ),
+            ultravox::AUTHENTICATE => {
+                // 2.1:stream id:user:password, with the user and password encrypted with the cipher key
+                let fields: Vec< &str > = text.splitn( 4, ':' ).collect();
+                let ( stream_id, user, password ) = match fields[ .. ] {
+                    [ "2.1", stream_id, user, password ] => ( stream_id.parse::< u16 >().ok(), ultravox::decrypt( user, &shoutcast.cipher_key ), ultravox::decrypt( password, &shoutcast.cipher_key ) ),
+                    _ => {
+                        stream.write_all( &ultravox::encode( received.kind, "NAK:Version Error" ) ).await?;
+                        return Ok( () );
                     }
-                    ( Some( b"chunked" ), None ) => {
-                        // Use chunked decoder
-                        decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Chunked );
+                };
+
+                let path = match stream_id.and_then( | stream_id | shoutcast.mount_for( stream_id ) ) {
+                    Some( path ) => path,
+                    None => {
+                        log_warn!( "SHOUTcast v2 source from {} asked for an unknown stream ID", peer );
+                        stream.write_all( &ultravox::encode( received.kind, "NAK:2.1:Stream ID Error" ) ).await?;
+                        return Ok( () );
                     }
-                    ( Some( b"identity" ), None ) | ( None, None ) => {
-                        // Use identity
-                        decoder = stream_decoder::StreamDecoder::new( stream_decoder::TransferEncoding::Identity );
+                };
+
+                let allowed = match ( user, password ) {
+                    ( Some( user ), Some( password ) ) => {
+                        let username = if user.is_empty() { &shoutcast.username } else { &user };
+                        server::validate_user( &server, username, &password ).await
+                            .is_some_and( | user | user.has_permission( server::Permission::Source, Some( path ) ) )
                     }
-                    _ => return response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Unsupported transfer encoding" ) ) ).await
+                    _ => false
+                };
+                if !allowed {
+                    log_warn!( "SHOUTcast v2 source from {} was denied access to {}", peer, path );
+                    stream.write_all( &ultravox::encode( received.kind, "NAK:2.1:Deny" ) ).await?;
+                    return Ok( () );
                 }
 
-                // Check if client sent Expect: 100-continue in header, if that's the case we will need to return 100 in status code
-                // Without it, it means that client has no body to send, we will stop if that's the case
-                match request::get_header( "Expect", headers ) {
-                    Some( b"100-continue" ) => response::send_continue( &mut stream, &server_id ).await?,
-                    Some( _ ) => return response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Expected 100-continue in Expect header" ) ) ).await,
-                    None => return response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "PUT request must come with Expect header" ) ) ).await
-                }
+                mount = Some( path.to_string() );
+                "ACK:2.1:Allow".to_string()
             }
-
-            // Parse the headers for the source properties
-            icy::populate_properties( &mut properties, headers );
-
-            let source = source::Source::new( path.clone(), properties );
-
-            let queue_size = serv.properties.limits.queue_size;
-            let ( burst_size, source_timeout ) =  {
-                if let Some( limit ) = serv.properties.limits.source_limits.get( &path ) {
-                    ( limit.burst_size, limit.source_timeout )
-                } else {
-                    ( serv.properties.limits.burst_size, serv.properties.limits.header_timeout )
+            ultravox::TERMINATE => return Ok( () ),
+            // Nothing else is accepted before authenticating
+            _ if mount.is_none() => "NAK:Sequence Error".to_string(),
+            ultravox::MIME_TYPE => {
+                content_type = text;
+                "ACK".to_string()
+            }
+            ultravox::SETUP => {
+                // avg bitrate:max bitrate, some encoders send bits instead of kilobits
+                if let Some( Ok( bitrate ) ) = text.split( ':' ).next().map( | avg | avg.parse::< usize >() ) {
+                    icy_headers.push( ( "icy-br", if bitrate >= 1000 { bitrate / 1000 } else { bitrate }.to_string() ) );
                 }
-            };
-
-            // Add to the server
-            let arc = Arc::new( RwLock::new( source ) );
-            serv.sources.insert( path, arc.clone() );
-            serv.source_count += 1;
-            drop( serv );
-
-            println!( "Mounted source on {} via {}", arc.read().await.mountpoint, method );
-
-            if message.len() > body_offset {
-                let slice = &message[ body_offset .. ];
-                let mut data = Vec::new();
-                match decoder.decode( &mut data, slice, message.len() - body_offset ) {
-                    Ok( read ) => {
-                        if read != 0 {
-                            broadcast_to_clients( &arc, data, queue_size, burst_size ).await;
-                            arc.read().await.stats.write().await.bytes_read += read;
-                        }
+                "ACK".to_string()
+            }
+            ultravox::BUFFER_SIZE => format!( "ACK:{}", text.split( ':' ).next().unwrap_or( "0" ) ),
+            ultravox::MAX_PAYLOAD_SIZE => {
+                let desired = text.split( ':' ).next().and_then( | desired | desired.parse::< usize >().ok() ).unwrap_or( ultravox::MAX_PAYLOAD );
+                format!( "ACK:{}", std::cmp::min( desired, ultravox::MAX_PAYLOAD ) )
+            }
+            ultravox::ICY_NAME => {
+                icy_headers.push( ( "icy-name", text ) );
+                "ACK".to_string()
+            }
+            ultravox::ICY_GENRE => {
+                icy_headers.push( ( "icy-genre", text ) );
+                "ACK".to_string()
+            }
+            ultravox::ICY_URL => {
+                icy_headers.push( ( "icy-url", text ) );
+                "ACK".to_string()
+            }
+            ultravox::ICY_PUB => {
+                icy_headers.push( ( "icy-pub", text ) );
+                "ACK".to_string()
+            }
+            ultravox::STANDBY => {
+                let path = mount.clone().unwrap_or_default();
+                let headers: Vec< httparse::Header > = icy_headers.iter()
+                    .map( | ( name, value ) | httparse::Header { name, value: value.as_bytes() } )
+                    .collect();
+                let mut properties = icy::Properties::new( content_type.clone() );
+                icy::populate_properties( &mut properties, &headers );
+
+                match mount_source( &server, &path, properties, false ).await {
+                    Ok( arc ) => {
+                        stream.write_all( &ultravox::encode( received.kind, "ACK:Data transfer mode" ) ).await?;
+                        break arc;
                     }
                     Err( e ) => {
-                        println!( "An error occurred while decoding stream data from source {}: {}", arc.read().await.mountpoint, e );
-                        arc.write().await.disconnect_flag = true;
+                        log_error!( "Unable to mount the SHOUTcast v2 source from {} on {}: {}", peer, path, e );
+                        stream.write_all( &ultravox::encode( received.kind, &format!( "NAK:{}", e ) ) ).await?;
+                        return Ok( () );
                     }
                 }
             }
+            _ => "ACK".to_string()
+        };
 
-            // Listen for bytes
-            if !decoder.is_finished() && !arc.read().await.disconnect_flag {
-                while {
-                    // Read the incoming stream data until it closes
-                    let mut buf = [ 0; 1024 ];
-                    let read = match timeout( Duration::from_millis( source_timeout ), stream.read( &mut buf ) ).await {
-                        Ok( Ok( n ) ) => n,
-                        Ok( Err( e ) ) => {
-                            println!( "An error occurred while reading stream data from source {}: {}", arc.read().await.mountpoint, e );
-                            0
-                        }
-                        Err( _ ) => {
-                            println!( "A source timed out: {}", arc.read().await.mountpoint );
-                            0
-                        }
-                    };
+        stream.write_all( &ultravox::encode( received.kind, &reply ) ).await?;
+    };
 
-                    let mut data = Vec::new();
-                    match decoder.decode( &mut data, &buf, read ) {
-                        Ok( decode_read ) => {
-                            if decode_read != 0 {
-                                broadcast_to_clients( &arc, data, queue_size, burst_size ).await;
-                                arc.read().await.stats.write().await.bytes_read += decode_read;
-                            }
+    let mount = arc.read().await.mountpoint.clone();
+    log_info!( "Mounted source on {} via SHOUTcast v2", mount );
+
+    let mut xml = ultravox::MetadataBuffer::new();
+    while !arc.read().await.disconnect_flag {
+        let source_timeout = arc.read().await.limits.source_timeout;
+        // The error is turned into a string so it isn't held across the cleanup
+        let received = match timeout( Duration::from_millis( source_timeout ), read_ultravox( &mut stream, &mut message ) ).await.map( | res | res.map_err( | e | e.to_string() ) ) {
+            Ok( Ok( Some( received ) ) ) => received,
+            Ok( Ok( None ) ) => break,
+            Ok( Err( e ) ) => {
+                log_error!( "An error occurred while reading stream data from source {}: {}", mount, e );
+                break;
+            }
+            Err( _ ) => {
+                log_warn!( "A source timed out: {}", mount );
+                break;
+            }
+        };
 
-                            // Check if the source needs to be disconnected
-                            read != 0 && !decoder.is_finished() && !arc.read().await.disconnect_flag
-                        }
-                        Err( e ) => {
-                            println!( "An error occurred while decoding stream data from source {}: {}", arc.read().await.mountpoint, e );
-                            false
-                        }
-                    }
-                }  {}
-            }
-
-            let mut source = arc.write().await;
-            let fallback = source.fallback.clone();
-            if let Some( fallback_id ) = fallback {
-                if let Some( fallback_source ) = server.read().await.sources.get( &fallback_id ) {
-                    println!( "Moving listeners from {} to {}", source.mountpoint, fallback_id );
-                    let mut fallback = fallback_source.write().await;
-                    for ( uuid, client ) in source.clients.drain() {
-                        *client.read().await.source.write().await = fallback_id.clone();
-                        fallback.clients.insert( uuid, client );
-                    }
-                } else {
-                    println!( "No fallback source {} found! Disconnecting listeners on {}", fallback_id, source.mountpoint );
-                    for cli in source.clients.values() {
-                        // Send an empty vec to signify the channel is closed
-                        drop( cli.read().await.sender.write().await.send( Arc::new( Vec::new() ) ) );
-                    }
-                }
-            } else {
-                // Disconnect each client by sending an empty buffer
-                println!( "Disconnecting listeners on {}", source.mountpoint );
-                for cli in source.clients.values() {
-                    // Send an empty vec to signify the channel is closed
-                    drop( cli.read().await.sender.write().await.send( Arc::new( Vec::new() ) ) );
-                }
+        let metadata = match ( received.class(), received.kind ) {
+            ( ultravox::CLASS_MP3, _ ) | ( ultravox::CLASS_AAC, _ ) => {
+                let read = received.payload.len();
+                broadcast_to_clients( &arc, received.payload ).await;
+                arc.read().await.stats.write().await.bytes_read += read;
+                continue;
+            }
+            ( _, ultravox::SHOUTCAST_METADATA ) => ultravox::parse_shoutcast_metadata( &received.text() ),
+            ( _, ultravox::XML_METADATA ) => match xml.push( &received.payload ) {
+                Some( document ) => ultravox::parse_xml_metadata( &document ),
+                None => continue
+            },
+            ( _, ultravox::FLUSH_METADATA ) => {
+                stream.write_all( &ultravox::encode( received.kind, "ACK" ) ).await.ok();
+                None
             }
+            ( _, ultravox::TERMINATE ) => break,
+            // Anything else, like album art, isn't passed on
+            _ => continue
+        };
 
-            // Clean up and remove the source
-            let mut serv = server.write().await;
-            serv.sources.remove( &source.mountpoint );
-            serv.source_count -= 1;
-            serv.stats.session_bytes_read += source.stats.read().await.bytes_read;
+        if let Some( metadata ) = &metadata {
+            log_info!( "Updated source {} metadata with title '{}' and url '{}'", mount, metadata.title.as_deref().unwrap_or( "" ), metadata.url.as_deref().unwrap_or( "" ) );
+        }
+        arc.write().await.set_metadata( metadata );
+    }
 
-            if method == "PUT" {
-                // request must end with server 200 OK response
-                response::send_ok( &mut stream, &server_id, None ).await.ok();
-            }
+    unmount_source( &server, &arc, false ).await;
+
+    log_info!( "Unmounted source {}", mount );
 
-            println!( "Unmounted source {}", source.mountpoint );
+    Ok( () )
+}
+
+// Mounts with a source, or an on demand relay that a listener can start
+fn listenable( serv: &server::Server, mount: &str, head: bool ) -> bool {
+    serv.sources.contains_key( mount ) || ( !head && serv.on_demand_relay( mount ).is_some() )
+}
+
+/**
+ * Start the on demand relay for a mount, if it has one, and hold the listener until it has something to send
+ * Only called once the listener has been let in, the mount has no source if the relay failed
+ */
+async fn connect_on_demand( server: &Arc< RwLock< server::Server > >, mount: &str ) {
+    let header_timeout = {
+        let mut serv = server.write().await;
+        if serv.shutting_down {
+            return;
         }
-        "GET" => {
-            let source_id = path.to_owned();
-            let source_id = {
-                // Remove the trailing '/'
-                if source_id.ends_with( '/' ) {
-                    let mut chars = source_id.chars();
-                    chars.next_back();
-                    chars.collect()
-                } else {
-                    source_id
-                }
-            };
+        let ( relay, relay_limit ) = match serv.on_demand_relay( mount ) {
+            Some( relay ) => relay,
+            None => return
+        };
 
-            let mut serv = server.write().await;
-            let source_option = serv.sources.get( &source_id );
-            // Check if the source is valid
-            if let Some( source_lock ) = source_option {
-                let mut source = source_lock.write().await;
-
-                // Check if the max number of listeners has been reached
-                let too_many_clients = {
-                    if let Some( limit ) = serv.properties.limits.source_limits.get( &source_id ) {
-                        source.clients.len() >= limit.clients
-                    } else {
-                        false
-                    }
-                };
-                if serv.clients.len() >= serv.properties.limits.clients || too_many_clients {
-                    response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Too many listeners connected" ) ) ).await?;
-                    return Ok( () )
+        // Only the first listener starts the relay, the rest wait along with it
+        if serv.on_demand_running.insert( relay.mount.clone() ) {
+            log_info!( "Connecting on demand relay {} from {}", relay.mount, relay.url );
+            let server = server.clone();
+            tokio::spawn( async move {
+                if let Err( e ) = relay_mountpoint( server.clone(), relay.clone(), relay_limit, None ).await.map_err( | e | e.to_string() ) {
+                    log_error!( "An error occurred while relaying {} from {}: {}", relay.mount, relay.url, e );
                 }
+                server.write().await.on_demand_running.remove( &relay.mount );
+            } );
+        }
+        serv.properties.limits.header_timeout
+    };
 
-                // Check if metadata is enabled
-                let meta_enabled = request::get_header( "Icy-MetaData", headers ).unwrap_or( b"0" ) == b"1";
+    let deadline = Instant::now() + Duration::from_millis( header_timeout );
+    loop {
+        {
+            let serv = server.read().await;
+            if let Some( source ) = serv.sources.get( mount ) {
+                if source.read().await.stats.read().await.bytes_read > 0 || Instant::now() >= deadline {
+                    return;
+                }
+            } else if !serv.on_demand_running.contains( mount ) || Instant::now() >= deadline {
+                // The relay failed to connect
+                return;
+            }
+        }
+        tokio::time::sleep( Duration::from_millis( 50 ) ).await;
+    }
+}
 
-                // Reply with a 200 OK
-                response::send_listener_ok( &mut stream, &server_id, &source.properties, meta_enabled, serv.properties.metaint ).await?;
+async fn handle_listener( server: &Arc< RwLock< server::Server > >, stream: &mut response::Stream, server_id: &str, peer: Option< SocketAddr >, socket: &server::ListenSocket, req: &request::Request< '_ >, source_id: String ) -> Result< (), Box< dyn Error > > {
+    let headers = req.headers;
 
-                // Create a client
-                // Get a valid UUID
-                let client_id = {
-                    let mut unique = Uuid::new_v4();
-                    // Hopefully this doesn't take until the end of time
-                    while serv.clients.contains_key( &unique ) {
-                        unique = Uuid::new_v4();
-                    }
-                    unique
-                };
+    // Ask the authentication hook before locking anything, since it waits on another server
+    let listener_auth = {
+        let serv = server.read().await;
+        let auth = &serv.properties.listener_auth;
+        if auth.applies_to( &source_id ) {
+            let port = socket.address.parse::< SocketAddr >().map( | address | address.port().to_string() ).unwrap_or_default();
+            Some( ( auth.clone(), serv.properties.host.clone(), port ) )
+        } else {
+            None
+        }
+    };
+    let ( auth_user, auth_pass ) = request::get_basic_auth( headers ).unwrap_or_default();
+    let ip = peer.map( | addr | addr.ip().to_string() ).unwrap_or_default();
+    let uagent = request::get_header( "User-Agent", headers ).and_then( | arr | std::str::from_utf8( arr ).ok() ).unwrap_or( "" ).to_string();
+    let client_id = Uuid::new_v4();
+    let client_str = client_id.to_string();
+    let ( host, port ) = listener_auth.as_ref().map( | ( _, host, port ) | ( host.as_str(), port.as_str() ) ).unwrap_or_default();
+    let add_fields = [
+        ( "action", "listener_add" ),
+        ( "server", host ),
+        ( "port", port ),
+        ( "client", &client_str ),
+        ( "mount", &source_id ),
+        ( "user", &auth_user ),
+        ( "pass", &auth_pass ),
+        ( "ip", &ip ),
+        ( "agent", &uagent ),
+        ( "query", req.raw_query )
+    ];
+
+    // Don't ask the hook about listeners that would be turned away anyway
+    if let Some( response ) = listener_rejection( &*server.read().await, &source_id ).await {
+        return response::send_response( stream, server_id, &response, req.version, false, false ).await;
+    }
 
-                let ( sender, receiver ) = unbounded_channel::< Arc< Vec< u8 > > >();
-                let properties = client::Properties {
-                    id: client_id,
-                    uagent: {
-                        if let Some( arr ) = request::get_header( "User-Agent", headers ) {
-                            if let Ok( parsed ) = std::str::from_utf8( arr ) {
-                                Some( parsed.to_string() )
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    },
-                    metadata: meta_enabled
-                };
-                let stats = client::Stats {
-                    start_time: {
-                        if let Ok( time ) = SystemTime::now().duration_since( UNIX_EPOCH ) {
-                            time.as_secs()
-                        } else {
-                            0
-                        }
-                    },
-                    bytes_sent: 0
-                };
-                let client = client::Client {
-                    source: RwLock::new( source_id ),
-                    sender: RwLock::new( sender ),
-                    receiver: RwLock::new( receiver ),
-                    buffer_size: RwLock::new( 0 ),
-                    properties: properties.clone(),
-                    stats: RwLock::new( stats )
-                };
+    if let Some( ( auth, .. ) ) = &listener_auth {
+        // The error is turned into a string so it isn't held across the response
+        match listener_auth_request( server, &auth.listener_add, &auth.auth_header, &add_fields ).await.map_err( | e | e.to_string() ) {
+            Ok( ( true, _ ) ) => (),
+            Ok( ( false, message ) ) => {
+                log_warn!( "Listener authentication rejected {} on {}", ip, source_id );
+                let message = message.unwrap_or_else( || "Rejected by listener authentication".to_string() );
+                let response = response::Response::unauthorized( Some( ( "text/plain; charset=utf-8", &message ) ) );
+                return response::send_response( stream, server_id, &response, req.version, false, false ).await;
+            }
+            Err( e ) => {
+                log_error!( "An error occurred while authenticating a listener on {}: {}", source_id, e );
+                let response = response::Response::forbidden( Some( ( "text/plain; charset=utf-8", "Listener authentication failed" ) ) );
+                return response::send_response( stream, server_id, &response, req.version, false, false ).await;
+            }
+        }
+    }
 
+    // On demand relays are only started for listeners that made it this far
+    if !server.read().await.sources.contains_key( &source_id ) {
+        connect_on_demand( server, &source_id ).await;
+    }
 
-                if let Some( agent ) = &client.properties.uagent {
-                    println!( "User {} started listening on {} with user-agent {}", client_id, client.source.read().await, agent );
+    let mut serv = server.write().await;
+    // Check again after waiting on the hook, which has to hear about the listener leaving if it accepted it
+    let rejection = match serv.sources.contains_key( &source_id ) {
+        true => listener_rejection( &serv, &source_id ).await,
+        false => Some( response::Response::not_found() )
+    };
+    if let Some( response ) = rejection {
+        drop( serv );
+        if let Some( ( auth, .. ) ) = &listener_auth {
+            listener_removed( server, auth, client_id, &listener_remove_fields( &add_fields, &source_id, "0" ) ).await;
+        }
+        return response::send_response( stream, server_id, &response, req.version, false, false ).await;
+    }
+    let mut source = serv.sources[ &source_id ].write().await;
+
+    // Check if metadata is enabled
+    let meta_enabled = request::get_header( "Icy-MetaData", headers ).unwrap_or( b"0" ) == b"1";
+
+    // Reply with a 200 OK
+    response::send_listener_ok( stream, server_id, &source.properties, meta_enabled, serv.properties.metaint ).await?;
+
+    // Create a client
+    let ( sender, receiver ) = unbounded_channel::< Arc< Vec< u8 > > >();
+    let properties = client::Properties {
+        id: client_id,
+        uagent: {
+            if let Some( arr ) = request::get_header( "User-Agent", headers ) {
+                if let Ok( parsed ) = std::str::from_utf8( arr ) {
+                    Some( parsed.to_string() )
                 } else {
-                    println!( "User {} started listening on {}", client_id, client.source.read().await );
-                }
-                if meta_enabled {
-                    println!( "User {} has icy metadata enabled", client_id );
+                    None
                 }
+            } else {
+                None
+            }
+        },
+        metadata: meta_enabled
+    };
+    let stats = client::Stats {
+        start_time: {
+            if let Ok( time ) = SystemTime::now().duration_since( UNIX_EPOCH ) {
+                time.as_secs()
+            } else {
+                0
+            }
+        },
+        bytes_sent: 0
+    };
+    let client = client::Client {
+        source: RwLock::new( source_id.clone() ),
+        sender: RwLock::new( sender ),
+        receiver: RwLock::new( receiver ),
+        buffer_size: RwLock::new( 0 ),
+        properties: properties.clone(),
+        stats: RwLock::new( stats )
+    };
 
-                // Get the metaint
-                let metalen = serv.properties.metaint;
-
-                // Keep track of how many bytes have been sent
-                let mut sent_count = 0;
 
-                // Get a copy of the burst buffer and metadata
-                let burst_buf = source.burst_buffer.clone();
-                let metadata_copy = source.metadata_vec.clone();
+    if let Some( agent ) = &client.properties.uagent {
+        log_info!( "User {} started listening on {} with user-agent {}", client_id, client.source.read().await, agent );
+    } else {
+        log_info!( "User {} started listening on {}", client_id, client.source.read().await );
+    }
+    if meta_enabled {
+        log_debug!( "User {} has icy metadata enabled", client_id );
+    }
 
-                let arc_client = Arc::new( RwLock::new( client ) );
-                // Add the client id to the list of clients attached to the source
-                source.clients.insert( client_id, arc_client.clone() );
+    // Get the metaint
+    let metalen = serv.properties.metaint;
 
-                {
-                    let mut source_stats = source.stats.write().await;
-                    source_stats.peak_listeners = std::cmp::max( source_stats.peak_listeners, source.clients.len() );
-                }
+    // Keep track of how many bytes have been sent
+    let mut sent_count = 0;
 
-                // No more need for source
-                drop( source );
+    // Get a copy of the burst buffer and metadata
+    // Ogg listeners can't decode anything without the headers, so they go first
+    let mut burst_buf = match &source.ogg_headers {
+        Some( headers ) => headers.pages().to_vec(),
+        None => Vec::new()
+    };
+    burst_buf.extend_from_slice( &source.burst_buffer );
+    let metadata_copy = source.metadata_vec.clone();
 
-                // Add our client
-                serv.clients.insert( client_id, properties );
+    let arc_client = Arc::new( RwLock::new( client ) );
+    // Add the client id to the list of clients attached to the source
+    source.clients.insert( client_id, arc_client.clone() );
 
-                // Set the max amount of listeners
-                serv.stats.peak_listeners = std::cmp::max( serv.stats.peak_listeners, serv.clients.len() );
+    {
+        let mut source_stats = source.stats.write().await;
+        source_stats.peak_listeners = std::cmp::max( source_stats.peak_listeners, source.clients.len() );
+    }
 
-                drop( serv );
+    // No more need for source
+    drop( source );
 
-                // Send the burst on connect buffer
-                let burst_success = {
-                    if !burst_buf.is_empty() {
-                        match {
-                            if meta_enabled {
-                                response::write_to_client( &mut stream, &mut sent_count, metalen, &burst_buf, &metadata_copy ).await
-                            } else {
-                                stream.write_all( &burst_buf ).await
-                            }
-                        } {
-                            Ok( _ ) => {
-                                arc_client.read().await.stats.write().await.bytes_sent += burst_buf.len();
-                                true
-                            }
-                            Err( _ ) => false,
-                        }
-                    } else {
-                        true
-                    }
-                };
-                drop( metadata_copy );
-                drop( burst_buf );
-
-                if burst_success {
-                    loop {
-                        // Receive whatever bytes, then send to the client
-                        let client = arc_client.read().await;
-                        let res = client.receiver.write().await.recv().await;
-                        // Check if the channel is still alive
-                        if let Some( read ) = res {
-                            // If an empty buffer has been sent, then disconnect the client
-                            if read.len() > 0 {
-                                // Decrease the internal buffer
-                                *client.buffer_size.write().await -= read.len();
-                                match {
-                                    if meta_enabled {
-                                        let meta_vec = {
-                                            let serv = server.read().await;
-                                            if let Some( source_lock ) = serv.sources.get( &*client.source.read().await ) {
-                                                let source = source_lock.read().await;
-
-                                                source.metadata_vec.clone()
-                                            } else {
-                                                vec![ 0 ]
-                                            }
-                                        };
-
-                                        response::write_to_client( &mut stream, &mut sent_count, metalen, &read.to_vec(), &meta_vec ).await
-                                    } else {
-                                        stream.write_all( &read.to_vec() ).await
-                                    }
-                                } {
-                                    Ok( _ ) => arc_client.read().await.stats.write().await.bytes_sent += read.len(),
-                                    Err( _ ) => break,
-                                }
-                            } else {
-                                break;
-                            }
-                        } else {
-                            // The sender has been dropped
-                            // The listener has been kicked
-                            break;
-                        }
-                    }
-                }
+    // Add our client
+    serv.clients.insert( client_id, properties );
 
-                // Close the message queue
-                arc_client.read().await.receiver.write().await.close();
+    // Set the max amount of listeners
+    serv.stats.peak_listeners = std::cmp::max( serv.stats.peak_listeners, serv.clients.len() );
 
-                println!( "User {} has disconnected", client_id );
+    drop( serv );
 
-                let mut serv = server.write().await;
-                // Remove the client information from the list of clients
-                serv.clients.remove( &client_id );
-                serv.stats.session_bytes_sent += arc_client.read().await.stats.read().await.bytes_sent;
-                drop( serv );
+    // Send the burst on connect buffer
+    let burst_success = {
+        if !burst_buf.is_empty() {
+            let res = if meta_enabled {
+                response::write_to_client( stream, &mut sent_count, metalen, &burst_buf, &metadata_copy ).await
             } else {
-                // Figure out what the request wants
-                // /admin/metadata for updating the metadata
-                // /admin/listclients for viewing the clients for a particular source
-                // /admin/fallbacks for setting the fallback of a particular source
-                // /admin/moveclients for moving listeners from one source to another
-                // /admin/killclient for disconnecting a client
-                // /admin/killsource for disconnecting a source
-                // /admin/listmounts for listing all mounts available
-                // Anything else is not vanilla or unimplemented
-                // Return a 404 otherwise
-
-                // Drop the write lock
-                drop( serv );
-
-                // Paths
-                match path.as_str() {
-                    "/admin/metadata" => {
-                        let serv = server.read().await;
-                        // Check for authorization
-                        if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                            // For testing purposes right now
-                            // TODO Add proper configuration
-                            if !server::validate_user( &serv.properties, name, pass ) {
-                                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid credentials" ) ) ).await;
-                            }
-                        } else {
-                            // No auth, return and close
-                            return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "You need to authenticate" ) ) ).await;
-                        }
-
-                        // Authentication passed
-                        // Now check the query fields
-                        // Takes in mode, mount, song and url
-                        if let Some( queries ) = queries {
-                            match request::get_queries_for( vec![ "mode", "mount", "song", "url" ], &queries )[ .. ].as_ref() {
-                                [ Some( mode ), Some( mount ), song, url ] if mode == "updinfo" => {
-                                    match serv.sources.get( mount ) {
-                                        Some( source ) => {
-                                            println!( "Updated source {} metadata with title '{}' and url '{}'", mount, song.as_ref().unwrap_or( &"".to_string() ), url.as_ref().unwrap_or( &"".to_string() ) );
-                                            let mut source = source.write().await;
-                                            source.metadata = match ( song, url ) {
-                                                ( None, None ) => None,
-                                                _ => Some( icy::Metadata {
-                                                    title: song.clone(),
-                                                    url: url.clone()
-                                                } ),
-                                            };
-                                            source.metadata_vec = icy::get_metadata_vec( &source.metadata );
-                                            response::send_ok( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Success" ) ) ).await?;
-                                        }
-                                        None => response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mount" ) ) ).await?,
-                                    }
-                                }
-                                _ => response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?,
-                            }
-                        } else {
-                            // Bad request
-                            response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?;
-                        }
-                    }
-                    "/admin/listclients" => {
-                        let serv = server.read().await;
-                        // Check for authorization
-                        if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                            // For testing purposes right now
-                            // TODO Add proper configuration
-                            if !server::validate_user( &serv.properties, name, pass ) {
-                                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid credentials" ) ) ).await;
-                            }
-                        } else {
-                            // No auth, return and close
-                            return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "You need to authenticate" ) ) ).await;
-                        }
-
-                        if let Some( queries ) = queries {
-                            match request::get_queries_for( vec![ "mount" ], &queries )[ .. ].as_ref() {
-                                [ Some( mount ) ] => {
-                                    if let Some( source ) = serv.sources.get( mount ) {
-                                        let mut clients: HashMap< Uuid, Value > = HashMap::new();
-
-                                        for client in source.read().await.clients.values() {
-                                            let client = client.read().await;
-                                            let properties = client.properties.clone();
-
-                                            let value = json!( {
-                                                "user_agent": properties.uagent,
-                                                "metadata_enabled": properties.metadata,
-                                                "stats": &*client.stats.read().await
-                                            } );
-
-                                            clients.insert( properties.id, value );
-                                        }
-
-                                        if let Ok( serialized ) = serde_json::to_string( &clients ) {
-                                            response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", &serialized ) ) ).await?;
-                                        } else {
-                                            response::send_internal_error( &mut stream, &server_id, None ).await?;
-                                        }
-                                    } else {
-                                        response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mount" ) ) ).await?;
-                                    }
-                                }
-                                _ => response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?,
-                            }
-                        } else {
-                            // Bad request
-                            response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?;
-                        }
-                    }
-                    "/admin/fallbacks" => {
-                        let serv = server.read().await;
-                        // Check for authorization
-                        if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                            // For testing purposes right now
-                            // TODO Add proper configuration
-                            if !server::validate_user( &serv.properties, name, pass ) {
-                                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid credentials" ) ) ).await;
-                            }
-                        } else {
-                            // No auth, return and close
-                            return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "You need to authenticate" ) ) ).await;
-                        }
-
-                        if let Some( queries ) = queries {
-                            match request::get_queries_for( vec![ "mount", "fallback" ], &queries )[ .. ].as_ref() {
-                                [ Some( mount ), fallback ] => {
-                                    if let Some( source ) = serv.sources.get( mount ) {
-                                        source.write().await.fallback = fallback.clone();
-
-                                        if let Some( fallback ) = fallback {
-                                            println!( "Set the fallback for {} to {}", mount, fallback );
-                                        } else {
-                                            println!( "Unset the fallback for {}", mount );
-                                        }
-                                        response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", "Success" ) ) ).await?;
-                                    } else {
-                                        response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mount" ) ) ).await?;
-                                    }
-                                }
-                                _ => response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?,
-                            }
-                        } else {
-                            // Bad request
-                            response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?;
-                        }
-                    }
-                    "/admin/moveclients" => {
-                        let serv = server.read().await;
-                        // Check for authorization
-                        if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                            // For testing purposes right now
-                            // TODO Add proper configuration
-                            if !server::validate_user( &serv.properties, name, pass ) {
-                                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid credentials" ) ) ).await;
-                            }
-                        } else {
-                            // No auth, return and close
-                            return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "You need to authenticate" ) ) ).await;
-                        }
-
-                        if let Some( queries ) = queries {
-                            match request::get_queries_for( vec![ "mount", "destination" ], &queries )[ .. ].as_ref() {
-                                [ Some( mount ), Some( dest ) ] => {
-                                    match ( serv.sources.get( mount ), serv.sources.get( dest ) ) {
-                                        ( Some( source ), Some( destination ) ) => {
-                                            let mut from = source.write().await;
-                                            let mut to = destination.write().await;
-
-                                            for ( uuid, client ) in from.clients.drain() {
-                                                *client.read().await.source.write().await = to.mountpoint.clone();
-                                                to.clients.insert( uuid, client );
-                                            }
-
-                                            println!( "Moved clients from {} to {}", mount, dest );
-                                            response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", "Success" ) ) ).await?;
-                                        }
-                                        _ => response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mount" ) ) ).await?,
-                                    }
-                                }
-                                _ => response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?,
-                            }
-                        } else {
-                            // Bad request
-                            response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?;
-                        }
-                    }
-                    "/admin/killclient" => {
-                        let serv = server.read().await;
-                        // Check for authorization
-                        if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                            // For testing purposes right now
-                            // TODO Add proper configuration
-                            if !server::validate_user( &serv.properties, name, pass ) {
-                                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid credentials" ) ) ).await;
-                            }
-                        } else {
-                            // No auth, return and close
-                            return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "You need to authenticate" ) ) ).await;
-                        }
+                stream.write_all( &burst_buf ).await
+            };
+            match res {
+                Ok( _ ) => {
+                    count_bytes_sent( server, &arc_client, burst_buf.len() ).await;
+                    true
+                }
+                Err( _ ) => false,
+            }
+        } else {
+            true
+        }
+    };
+    drop( metadata_copy );
+    drop( burst_buf );
 
-                        if let Some( queries ) = queries {
-                            match request::get_queries_for( vec![ "mount", "id" ], &queries )[ .. ].as_ref() {
-                                [ Some( mount ), Some( uuid_str ) ] => {
-                                    match ( serv.sources.get( mount ), Uuid::parse_str( uuid_str ) ) {
-                                        ( Some( source ), Ok( uuid ) ) => {
-                                            if let Some( client ) = source.read().await.clients.get( &uuid ) {
-                                                drop( client.read().await.sender.write().await.send( Arc::new( Vec::new() ) ) );
-                                                println!( "Killing client {}", uuid );
-                                                response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", "Success" ) ) ).await?;
-                                            } else {
-                                                response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid id" ) ) ).await?;
-                                            }
-                                        }
-                                        ( None, _ ) => response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mount" ) ) ).await?,
-                                        ( Some( _ ), Err( _ ) ) => response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid id" ) ) ).await?,
-                                    }
-                                }
-                                _ => response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?,
-                            }
-                        } else {
-                            // Bad request
-                            response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?;
-                        }
-                    }
-                    "/admin/killsource" => {
-                        let serv = server.read().await;
-                        // Check for authorization
-                        if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                            // For testing purposes right now
-                            // TODO Add proper configuration
-                            if !server::validate_user( &serv.properties, name, pass ) {
-                                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid credentials" ) ) ).await;
+    if burst_success {
+        loop {
+            // Receive whatever bytes, then send to the client
+            let client = arc_client.read().await;
+            let res = client.receiver.write().await.recv().await;
+            // Check if the channel is still alive
+            if let Some( read ) = res {
+                // If an empty buffer has been sent, then disconnect the client
+                if !read.is_empty() {
+                    // Decrease the internal buffer
+                    *client.buffer_size.write().await -= read.len();
+                    let res = if meta_enabled {
+                        let meta_vec = {
+                            let serv = server.read().await;
+                            if let Some( source_lock ) = serv.sources.get( &*client.source.read().await ) {
+                                let source = source_lock.read().await;
+
+                                source.metadata_vec.clone()
+                            } else {
+                                vec![ 0 ]
                             }
-                        } else {
-                            // No auth, return and close
-                            return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "You need to authenticate" ) ) ).await;
-                        }
+                        };
 
-                        if let Some( queries ) = queries {
-                            match request::get_queries_for( vec![ "mount" ], &queries )[ .. ].as_ref() {
-                                [ Some( mount ) ] => {
-                                    if let Some( source ) = serv.sources.get( mount ) {
-                                        source.write().await.disconnect_flag = true;
-
-                                        println!( "Killing source {}", mount );
-                                        response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", "Success" ) ) ).await?;
-                                    } else {
-                                        response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mount" ) ) ).await?;
-                                    }
-                                }
-                                _ => response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?,
-                            }
-                        } else {
-                            // Bad request
-                            response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?;
-                        }
+                        response::write_to_client( stream, &mut sent_count, metalen, &read.to_vec(), &meta_vec ).await
+                    } else {
+                        stream.write_all( &read.to_vec() ).await
+                    };
+                    match res {
+                        Ok( _ ) => count_bytes_sent( server, &arc_client, read.len() ).await,
+                        Err( _ ) => break,
                     }
-                    "/admin/listmounts" => {
-                        let serv = server.read().await;
-                        // Check for authorization
-                        if let Some( ( name, pass ) ) = request::get_basic_auth( headers ) {
-                            // For testing purposes right now
-                            // TODO Add proper configuration
-                            if !server::validate_user( &serv.properties, name, pass ) {
-                                return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid credentials" ) ) ).await;
-                            }
-                        } else {
-                            // No auth, return and close
-                            return response::send_unauthorized( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "You need to authenticate" ) ) ).await;
-                        }
-
-                        let mut sources: HashMap< String, Value > = HashMap::new();
-
-                        for source in serv.sources.values() {
-                            let source = source.read().await;
+                } else {
+                    break;
+                }
+            } else {
+                // The sender has been dropped
+                // The listener has been kicked
+                break;
+            }
+        }
+    }
 
-                            let value = json!( {
-                                "fallback": source.fallback,
-                                "metadata": source.metadata,
-                                "properties": source.properties,
-                                "stats": &*source.stats.read().await,
-                                "clients": source.clients.keys().cloned().collect::< Vec< Uuid > >()
-                            } );
+    // Close the message queue
+    arc_client.read().await.receiver.write().await.close();
 
-                            sources.insert( source.mountpoint.clone(), value );
-                        }
+    log_info!( "User {} has disconnected", client_id );
 
-                        if let Ok( serialized ) = serde_json::to_string( &sources ) {
-                            response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", &serialized ) ) ).await?;
-                        } else {
-                            response::send_internal_error( &mut stream, &server_id, None ).await?;
-                        }
-                    }
-                    "/api/serverinfo" => {
-                        let serv = server.read().await;
-
-                        let info = json!( {
-                            "mounts": serv.sources.keys().cloned().collect::< Vec< String > >(),
-                            "properties": {
-                                "server_id": serv.properties.server_id,
-                                "admin": serv.properties.admin,
-                                "host": serv.properties.host,
-                                "location": serv.properties.location,
-                                "description": serv.properties.description
-                            },
-                            "stats": {
-                                "start_time": serv.stats.start_time,
-                                "peak_listeners": serv.stats.peak_listeners
-                            },
-                            "current_listeners": serv.clients.len()
-                        } );
-
-                        if let Ok( serialized ) = serde_json::to_string( &info ) {
-                            response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", &serialized ) ) ).await?;
-                        } else {
-                            response::send_internal_error( &mut stream, &server_id, None ).await?;
-                        }
-                    }
-                    "/api/mountinfo" => {
-                        if let Some( queries ) = queries {
-                            match request::get_queries_for( vec![ "mount" ], &queries )[ .. ].as_ref() {
-                                [ Some( mount ) ] => {
-                                    let serv = server.read().await;
-                                    if let Some( source ) = serv.sources.get( mount ) {
-                                        let source = source.read().await;
-                                        let properties = &source.properties;
-                                        let stats = &source.stats.read().await;
-
-                                        let info = json!( {
-                                            "metadata": source.metadata,
-                                            "properties": {
-                                                "name": properties.name,
-                                                "description": properties.description,
-                                                "url": properties.url,
-                                                "genre": properties.genre,
-                                                "bitrate": properties.bitrate,
-                                                "content_type": properties.content_type
-                                            },
-                                            "stats": {
-                                                "start_time": stats.start_time,
-                                                "peak_listeners": stats.peak_listeners
-                                            },
-                                            "current_listeners": source.clients.len()
-                                        } );
-
-                                        if let Ok( serialized ) = serde_json::to_string( &info ) {
-                                            response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", &serialized ) ) ).await?;
-                                        } else {
-                                            response::send_internal_error( &mut stream, &server_id, None ).await?;
-                                        }
-                                    } else {
-                                        response::send_forbidden( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid mount" ) ) ).await?;
-                                    }
-                                }
-                                _ => response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?,
-                            }
-                        } else {
-                            // Bad request
-                            response::send_bad_request( &mut stream, &server_id, Some( ( "text/plain; charset=utf-8", "Invalid query" ) ) ).await?;
-                        }
-                    }
-                    "/api/stats" => {
-                        let server = server.read().await;
-                        let stats = &server.stats;
-                        let mut total_bytes_sent = stats.session_bytes_sent;
-                        let mut total_bytes_read = stats.session_bytes_read;
-                        for source in server.sources.values(){
-                            let source = source.read().await;
-                            total_bytes_read += source.stats.read().await.bytes_read;
-                            for clients in source.clients.values(){
-                                total_bytes_sent += clients.read().await.stats.read().await.bytes_sent;
-                            }
-                        }
+    let mut serv = server.write().await;
+    // Remove the client information from the list of clients
+    serv.clients.remove( &client_id );
+    serv.stats.session_bytes_sent += arc_client.read().await.stats.read().await.bytes_sent;
+    drop( serv );
+
+    // Every listener session ends up in the access log, like with Icecast
+    {
+        let stats = arc_client.read().await.stats.read().await.clone();
+        let request = match req.raw_query {
+            "" => format!( "{} {} HTTP/1.{}", req.method, req.path, req.version ),
+            query => format!( "{} {}?{} HTTP/1.{}", req.method, req.path, query, req.version )
+        };
+        logging::access( &logging::Access {
+            ip: peer.map( | addr | addr.ip() ),
+            user: Some( auth_user.as_str() ).filter( | user | !user.is_empty() ),
+            request: &request,
+            status: 200,
+            bytes_sent: stats.bytes_sent,
+            referer: request::get_header( "Referer", headers ).and_then( | value | std::str::from_utf8( value ).ok() ),
+            agent: Some( uagent.as_str() ).filter( | agent | !agent.is_empty() ),
+            start_time: stats.start_time
+        } );
+    }
 
-                        let epoch = {
-                            if let Ok( time ) = SystemTime::now().duration_since( UNIX_EPOCH ) {
-                                time.as_secs()
-                            } else {
-                                0
-                            }
-                        };
+    // Let the authentication hook know the listener is gone
+    if let Some( ( auth, .. ) ) = &listener_auth {
+        let client = arc_client.read().await;
+        let mount = client.source.read().await.clone();
+        let duration = {
+            let start_time = client.stats.read().await.start_time;
+            match SystemTime::now().duration_since( UNIX_EPOCH ) {
+                Ok( time ) => time.as_secs().saturating_sub( start_time ),
+                Err( _ ) => 0
+            }
+        }.to_string();
+        listener_removed( server, auth, client_id, &listener_remove_fields( &add_fields, &mount, &duration ) ).await;
+    }
 
-                        let response = json!( {
-                            "uptime": epoch - stats.start_time,
-                            "peak_listeners": stats.peak_listeners,
-                            "session_bytes_read": total_bytes_read,
-                            "session_bytes_sent": total_bytes_sent
-                        } );
+    Ok( () )
+}
 
-                        response::send_ok( &mut stream, &server_id, Some( ( "application/json; charset=utf-8", &response.to_string() ) ) ).await?;
+/**
+ * The response to turn a listener away with, if the source is gone or it has as many listeners as it can take
+ */
+async fn listener_rejection( serv: &server::Server, source_id: &str ) -> Option< response::Response > {
+    let source_clients = match serv.sources.get( source_id ) {
+        Some( source ) => source.read().await.clients.len(),
+        // Not connected until a listener is let in
+        None if serv.on_demand_relay( source_id ).is_some() => 0,
+        None => return Some( response::Response::not_found() )
+    };
 
-                    }
-                    // Return 404
-                    _ => response::send_not_found( &mut stream, &server_id, Some( ( "text/html; charset=utf-8", "<html><head><title>Error 404</title></head><body><b>404 - The file you requested could not be found</b></body></html>" ) ) ).await?,
-                }
-            }
-        }
-        _ => {
-            // Unknown
-            stream.write_all( b"HTTP/1.0 405 Method Not Allowed\r\n" ).await?;
-            stream.write_all( ( format!( "Server: {}\r\n", server_id ) ).as_bytes() ).await?;
-            stream.write_all( b"Connection: Close\r\n" ).await?;
-            stream.write_all( b"Allow: GET, SOURCE\r\n" ).await?;
-            stream.write_all( ( format!( "Date: {}\r\n", fmt_http_date( SystemTime::now() ) ) ).as_bytes() ).await?;
-            stream.write_all( b"Cache-Control: no-cache, no-store\r\n" ).await?;
-            stream.write_all( b"Expires: Mon, 26 Jul 1997 05:00:00 GMT\r\n" ).await?;
-            stream.write_all( b"Pragma: no-cache\r\n" ).await?;
-            stream.write_all( b"Access-Control-Allow-Origin: *\r\n\r\n" ).await?;
+    // Check if the max number of listeners has been reached
+    let too_many_clients = {
+        if let Some( limit ) = serv.properties.limits.source_limits.get( source_id ) {
+            source_clients >= limit.clients
+        } else {
+            false
         }
+    };
+    if serv.clients.len() >= serv.properties.limits.clients || too_many_clients {
+        Some( response::Response::forbidden( Some( ( "text/plain; charset=utf-8", "Too many listeners connected" ) ) ) )
+    } else {
+        None
     }
+}
 
-    Ok( () )
+/**
+ * Let the authentication hook know a listener it accepted is gone
+ */
+async fn listener_removed( server: &Arc< RwLock< server::Server > >, auth: &server::ListenerAuth, client_id: Uuid, fields: &[ ( &str, &str ) ] ) {
+    if let Some( url ) = &auth.listener_remove {
+        if let Err( e ) = listener_auth_request( server, url, &auth.auth_header, fields ).await {
+            log_error!( "An error occurred while notifying the authentication hook about {}: {}", client_id, e );
+        }
+    }
 }
 
-async fn connect_and_redirect( url: String, headers: Vec< String >, max_len: usize, max_redirects: usize ) -> Result< ( response::Stream, Vec< u8 > ), Box< dyn Error > > {
+async fn connect_and_redirect( url: String, method: &str, headers: Vec< String >, body: &[ u8 ], max_len: usize, max_redirects: usize ) -> Result< ( response::Stream, Vec< u8 > ), Box< dyn Error > > {
     let mut str_url = url;
     let mut remaining_redirects = max_redirects;
     loop {
@@ -942,7 +1208,7 @@ async fn connect_and_redirect( url: String, headers: Vec< String >, max_len: usi
 
             // Write the message
             let mut req_buf = Vec::new();
-            req_buf.extend_from_slice( format!( "GET {} HTTP/1.1\r\n", path ).as_bytes() );
+            req_buf.extend_from_slice( format!( "{} {} HTTP/1.1\r\n", method, path ).as_bytes() );
             let mut auth_included = false;
             for header in &headers {
                 req_buf.extend_from_slice( header.as_bytes() );
@@ -956,7 +1222,11 @@ async fn connect_and_redirect( url: String, headers: Vec< String >, max_len: usi
                     req_buf.extend_from_slice( format!( "Authorization: Basic {}\r\n", encoded ).as_bytes() );
                 }
             }
+            if !body.is_empty() {
+                req_buf.extend_from_slice( format!( "Content-Length: {}\r\n", body.len() ).as_bytes() );
+            }
             req_buf.extend_from_slice( b"\r\n" );
+            req_buf.extend_from_slice( body );
             stream.write_all( &req_buf ).await?;
 
             let mut buf = Vec::new();
@@ -968,7 +1238,7 @@ async fn connect_and_redirect( url: String, headers: Vec< String >, max_len: usi
 
             // Second time parsing the response
             if res.parse( &buf )? == Status::Partial {
-                return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Received an incomplete response" ) ) );
+                return Err( Box::new( std::io::Error::other( "Received an incomplete response" ) ) );
             }
 
             match res.code {
@@ -976,7 +1246,7 @@ async fn connect_and_redirect( url: String, headers: Vec< String >, max_len: usi
                     if code / 100 == 3 || code == 201 {
                         if remaining_redirects == 0 {
                             // Reached maximum number of redirects!
-                            return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Maximum redirects reached" ) ) );
+                            return Err( Box::new( std::io::Error::other( "Maximum redirects reached" ) ) );
                         } else if let Some( location ) = request::get_header( "Location", res.headers ) {
                             // Try parsing it into a URL first
                             let loc_str = std::str::from_utf8( location )?;
@@ -994,13 +1264,13 @@ async fn connect_and_redirect( url: String, headers: Vec< String >, max_len: usi
 
                             remaining_redirects -= 1;
                         } else {
-                            return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Invalid Location" ) ) );
+                            return Err( Box::new( std::io::Error::other( "Invalid Location" ) ) );
                         }
                     } else {
                         return Ok( ( stream, buf ) );
                     }
                 }
-                None => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Missing response code" ) ) )
+                None => return Err( Box::new( std::io::Error::other( "Missing response code" ) ) )
             }
         } else {
             return Err( Box::new( std::io::Error::new( ErrorKind::AddrNotAvailable, format!( "Invalid URL provided: {}", str_url ) ) ) );
@@ -1008,7 +1278,56 @@ async fn connect_and_redirect( url: String, headers: Vec< String >, max_len: usi
     }
 }
 
-async fn master_server_mountpoints( server: &Arc< RwLock< server::Server > >, master_info: &server::Master ) -> Result< Vec<String>, Box< dyn Error > > {
+/**
+ * Posts a listener event to a URL authentication hook
+ * Returns whether the hook accepted the listener and the message it gave, if any
+ */
+async fn listener_auth_request( server: &Arc< RwLock< server::Server > >, url: &str, auth_header: &str, fields: &[ ( &str, &str ) ] ) -> Result< ( bool, Option< String > ), Box< dyn Error > > {
+    let ( server_id, header_timeout, http_max_len ) = {
+        let properties = &server.read().await.properties;
+        ( properties.server_id.clone(), properties.limits.header_timeout, properties.limits.http_max_length )
+    };
+
+    let body = url::form_urlencoded::Serializer::new( String::new() ).extend_pairs( fields ).finish();
+    let headers = vec![ format!( "User-Agent: {}", server_id ), "Connection: Close".to_string(), "Content-Type: application/x-www-form-urlencoded".to_string() ];
+    // Redirects aren't followed, the hook has to answer the POST itself
+    let ( _, message ) = timeout( Duration::from_millis( header_timeout ), connect_and_redirect( url.to_string(), "POST", headers, body.as_bytes(), http_max_len, 0 ) ).await??;
+
+    let mut headers = [ httparse::EMPTY_HEADER; 32 ];
+    let mut res = httparse::Response::new( &mut headers );
+    res.parse( &message )?;
+
+    let header_str = | name: &str | request::get_header( name, res.headers ).and_then( | value | std::str::from_utf8( value ).ok() ).map( | value | value.trim().to_string() );
+    let accepted = res.code == Some( 200 ) && header_str( auth_header ).as_deref() == Some( "1" );
+
+    Ok( ( accepted, header_str( "icecast-auth-message" ) ) )
+}
+
+/**
+ * Turns the fields of a listener_add event into the ones of its listener_remove
+ * The mount is the one the listener was on last, since it may have been moved
+ */
+fn listener_remove_fields< 'a >( add_fields: &[ ( &'a str, &'a str ) ], mount: &'a str, duration: &'a str ) -> Vec< ( &'a str, &'a str ) > {
+    let mut fields: Vec< _ > = add_fields.iter().filter_map( | &( name, value ) | match name {
+        "action" => Some( ( name, "listener_remove" ) ),
+        "mount" => Some( ( name, mount ) ),
+        "query" => None,
+        _ => Some( ( name, value ) )
+    } ).collect();
+    fields.push( ( "duration", duration ) );
+    fields
+}
+
+// A mount listed by the master's /admin/streamlist, or just the name from its /api/serverinfo
+#[ derive( Deserialize ) ]
+struct MasterMount {
+    mount: String,
+    metadata: Option< icy::Metadata >,
+    #[ serde( default ) ]
+    hidden: bool
+}
+
+async fn master_server_mountpoints( server: &Arc< RwLock< server::Server > >, master_info: &server::Master ) -> Result< Vec< MasterMount >, Box< dyn Error > > {
     // Get all master mountpoints
     let ( server_id, header_timeout, http_max_len, http_max_redirects ) = {
         let properties = &server.read().await.properties;
@@ -1016,15 +1335,24 @@ async fn master_server_mountpoints( server: &Arc< RwLock< server::Server > >, ma
     };
 
     // read headers from client
-    let headers = vec![ format!( "User-Agent: {}", server_id ), "Connection: Closed".to_string() ];
-    let ( mut sock, message ) = timeout( Duration::from_millis( header_timeout ), connect_and_redirect( format!( "{}/api/serverinfo", master_info.url ), headers, http_max_len, http_max_redirects ) ).await??;
+    let mut headers = vec![ format!( "User-Agent: {}", server_id ), "Connection: Closed".to_string() ];
+    // Without a relay user, the public listing is all the master offers
+    let endpoint = match &master_info.username {
+        Some( username ) => {
+            let encoded = base64::encode( format!( "{}:{}", username, master_info.password.as_deref().unwrap_or( "" ) ) );
+            headers.push( format!( "Authorization: Basic {}", encoded ) );
+            "/admin/streamlist"
+        }
+        None => "/api/serverinfo"
+    };
+    let ( mut sock, message ) = timeout( Duration::from_millis( header_timeout ), connect_and_redirect( format!( "{}{}", master_info.url, endpoint ), "GET", headers, &[], http_max_len, http_max_redirects ) ).await??;
 
     let mut headers = [ httparse::EMPTY_HEADER; 32 ];
     let mut res = httparse::Response::new( &mut headers );
 
     let body_offset = match res.parse( &message )? {
         Status::Complete( offset ) => offset,
-        Status::Partial => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Received an incomplete response" ) ) )
+        Status::Partial => return Err( Box::new( std::io::Error::other( "Received an incomplete response" ) ) )
     };
 
     let mut len = match request::get_header( "Content-Length", res.headers ) {
@@ -1032,13 +1360,13 @@ async fn master_server_mountpoints( server: &Arc< RwLock< server::Server > >, ma
             let parsed = std::str::from_utf8( val )?;
             parsed.parse::< usize >()?
         },
-        None => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "No Content-Length specified" ) ) )
+        None => return Err( Box::new( std::io::Error::other( "No Content-Length specified" ) ) )
     };
 
     match res.code {
         Some( 200 ) => (),
-        Some( code ) => return Err( Box::new( std::io::Error::new( ErrorKind::Other, format!( "Invalid response: {} {}", code, res.reason.unwrap() ) ) ) ),
-        None => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Missing response code" ) ) )
+        Some( code ) => return Err( Box::new( std::io::Error::other( format!( "Invalid response: {} {}", code, res.reason.unwrap() ) ) ) ),
+        None => return Err( Box::new( std::io::Error::other( "Missing response code" ) ) )
     }
 
     let source_timeout = server.read().await.properties.limits.source_timeout;
@@ -1066,44 +1394,86 @@ async fn master_server_mountpoints( server: &Arc< RwLock< server::Server > >, ma
     }
 
     #[ derive( Deserialize ) ]
-    struct MasterMounts { mounts: Vec< String > }
+    struct MasterMounts { mounts: Vec< MasterMount > }
+    #[ derive( Deserialize ) ]
+    struct PublicMounts { mounts: Vec< String > }
 
     // we either will found mounts or client is not an icecast node?
+    if master_info.username.is_none() {
+        let mounts = serde_json::from_slice::< PublicMounts >( &json_slice )?.mounts;
+        return Ok( mounts.into_iter().map( | mount | MasterMount { mount, metadata: None, hidden: false } ).collect() );
+    }
     Ok( serde_json::from_slice::< MasterMounts >( &json_slice )?.mounts )
 }
 
-#[ allow( clippy::map_entry ) ]
-#[ allow( clippy::blocks_in_if_conditions ) ]
-async fn relay_mountpoint( server: Arc< RwLock< server::Server > >, master_server: server::Master, mount: String ) -> Result< (), Box< dyn Error > > {
+// Takes the ICY metadata out of a relayed stream
+struct MetaParser {
+    metaint: usize,
+    vec: Vec< u8 >,
+    remaining: usize
+}
+
+// A connection to one of the upstreams of a relay
+struct Upstream {
+    url: String,
+    sock: response::Stream,
+    decoder: stream_decoder::StreamDecoder,
+    properties: icy::Properties,
+    meta_info: MetaParser,
+    // Whatever was received along with the response headers
+    initial: Vec< u8 >
+}
+
+// Why a relay stopped reading from an upstream
+enum UpstreamEnd {
+    // The relay was told to disconnect
+    Stopped,
+    // The upstream went away, timed out or went silent
+    Failed,
+    // The relay was on a backup, and the primary upstream is back
+    Primary( Box< Upstream > )
+}
+
+/**
+ * Connect to an upstream of a relay, and check that it's actually serving a stream
+ */
+async fn connect_upstream( server: &Arc< RwLock< server::Server > >, relay: &server::Relay, url: &str ) -> Result< Upstream, Box< dyn Error > > {
     let ( server_id, header_timeout, http_max_len, http_max_redirects ) = {
         let properties = &server.read().await.properties;
         ( properties.server_id.clone(), properties.limits.header_timeout, properties.limits.http_max_length, properties.limits.http_max_redirects )
     };
 
     // read headers from server
-    let headers = vec![ format!( "User-Agent: {}", server_id ), "Connection: Closed".to_string(), "Icy-Metadata:1".to_string() ];
-    let ( mut sock, buf ) = timeout( Duration::from_millis( header_timeout ), connect_and_redirect( format!( "{}{}", master_server.url, mount ), headers, http_max_len, http_max_redirects ) ).await??;
+    let mut headers = vec![ format!( "User-Agent: {}", server_id ), "Connection: Closed".to_string() ];
+    if relay.icy_metadata {
+        headers.push( "Icy-Metadata:1".to_string() );
+    }
+    if let Some( username ) = &relay.username {
+        let encoded = base64::encode( format!( "{}:{}", username, relay.password.as_deref().unwrap_or( "" ) ) );
+        headers.push( format!( "Authorization: Basic {}", encoded ) );
+    }
+    let ( sock, buf ) = timeout( Duration::from_millis( header_timeout ), connect_and_redirect( url.to_string(), "GET", headers, &[], http_max_len, http_max_redirects ) ).await??;
 
     let mut headers = [ httparse::EMPTY_HEADER; 32 ];
     let mut res = httparse::Response::new( &mut headers );
 
     let body_offset = match res.parse( &buf )? {
         Status::Complete( offset )=> offset,
-        Status::Partial => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Received an incomplete response" ) ) )
+        Status::Partial => return Err( Box::new( std::io::Error::other( "Received an incomplete response" ) ) )
     };
 
     match res.code {
         Some( 200 ) => (),
-        Some( code ) => return Err( Box::new( std::io::Error::new( ErrorKind::Other, format!( "Invalid response: {}", code ) ) ) ),
-        None => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Missing response code" ) ) )
+        Some( code ) => return Err( Box::new( std::io::Error::other( format!( "Invalid response: {}", code ) ) ) ),
+        None => return Err( Box::new( std::io::Error::other( "Missing response code" ) ) )
     }
 
     // checking if our peer is really an icecast server
     if request::get_header( "icy-name", res.headers ).is_none() {
-        return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Is this a valid icecast stream?" ) ) );
+        return Err( Box::new( std::io::Error::other( "Is this a valid icecast stream?" ) ) );
     }
 
-    let mut decoder = match ( request::get_header( "Transfer-Encoding", res.headers ), request::get_header( "Content-Length", res.headers ) ) {
+    let decoder = match ( request::get_header( "Transfer-Encoding", res.headers ), request::get_header( "Content-Length", res.headers ) ) {
         ( Some( b"identity"), Some( value ) ) | ( None, Some( value ) ) => {
             // Use content length decoder
             match std::str::from_utf8( value ) {
@@ -1124,359 +1494,329 @@ async fn relay_mountpoint( server: Arc< RwLock< server::Server > >, master_serve
     // Sources must have a content type
     let mut properties = match request::get_header( "Content-Type", res.headers ) {
         Some( content_type ) => icy::Properties::new( std::str::from_utf8( content_type )?.to_string() ),
-        None => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "No Content-Type provided" ) ) )
+        None => return Err( Box::new( std::io::Error::other( "No Content-Type provided" ) ) )
     };
 
     // Parse the headers for the source properties
     icy::populate_properties( &mut properties, res.headers );
     properties.uagent = Some( server_id );
 
-    let source = source::Source::new( mount.to_string(), properties );
+    let metaint = match request::get_header( "Icy-Metaint", res.headers ) {
+        Some( val ) => std::str::from_utf8( val )?.parse::< usize >()?,
+        None => 0
+    };
+
+    Ok( Upstream {
+        url: url.to_string(),
+        initial: buf[ body_offset .. ].to_vec(),
+        sock,
+        decoder,
+        properties,
+        meta_info: MetaParser {
+            metaint,
+            vec: Vec::new(),
+            remaining: metaint
+        }
+    } )
+}
+
+/**
+ * Try the upstreams of a relay in order, starting from the given one, until one of them connects
+ * Returns the upstream along with where it is in the list
+ */
+async fn connect_upstreams( server: &Arc< RwLock< server::Server > >, relay: &server::Relay, start: usize, content_type: Option< &str > ) -> Result< ( Upstream, usize ), String > {
+    let urls = relay.urls();
+    let mut last_error = "There is no other upstream to switch to".to_string();
+    for ( index, url ) in urls.iter().enumerate().skip( start ) {
+        last_error = match connect_upstream( server, relay, url ).await.map_err( | e | e.to_string() ) {
+            // Listeners can't follow a change of format in the middle of the stream
+            Ok( upstream ) if content_type.is_some_and( | content_type | content_type != upstream.properties.content_type ) => format!( "{} is not serving {}", url, content_type.unwrap_or_default() ),
+            Ok( upstream ) => return Ok( ( upstream, index ) ),
+            Err( e ) => e
+        };
+        if index + 1 < urls.len() {
+            log_warn!( "Unable to relay {} from {}, trying the next upstream: {}", relay.mount, url, last_error );
+        } else if urls.len() > 1 {
+            last_error = format!( "The last upstream {} failed too: {}", url, last_error );
+        }
+    }
+    Err( last_error )
+}
+
+#[ allow( clippy::map_entry ) ]
+async fn relay_mountpoint( server: Arc< RwLock< server::Server > >, relay: server::Relay, relay_limit: Option< usize >, metadata: Option< icy::Metadata > ) -> Result< (), Box< dyn Error > > {
+    // No need to connect if the relay can't be mounted anyway
+    {
+        let serv = server.read().await;
+        serv.properties.validate_mount( &relay.mount ).map_err( std::io::Error::other )?;
+        if serv.sources.contains_key( &relay.mount ) {
+            return Err( Box::new( std::io::Error::other( "A source with the same mountpoint already exists" ) ) );
+        }
+    }
+
+    let ( mut upstream, mut index ) = connect_upstreams( &server, &relay, 0, None ).await.map_err( std::io::Error::other )?;
+    let content_type = upstream.properties.content_type.clone();
 
     // TODO This code is almost an exact replica of the one used for regular source handling, although with a few differences
     let mut serv = server.write().await;
+    let mut source = new_source( &serv, &relay.mount, upstream.properties.clone() );
+    source.relay = Some( upstream.url.clone() );
     // Check if the mountpoint is already in use
     let path = source.mountpoint.clone();
     // Not sure what clippy wants, https://rust-lang.github.io/rust-clippy/master/#map_entry
     // The source is not needed if the map already has one. TODO Try using try_insert if it's stable in the future
     if serv.sources.contains_key( &path ) {
         // The error handling in this program is absolutely awful
-        Err( Box::new( std::io::Error::new( ErrorKind::Other, "A source with the same mountpoint already exists" ) ) )
+        Err( Box::new( std::io::Error::other( "A source with the same mountpoint already exists" ) ) )
     } else {
-        if serv.relay_count >= master_server.relay_limit {
-            return Err( Box::new( std::io::Error::new( ErrorKind::Other, "The server relay limit has been reached" ) ) );
+        if serv.shutting_down {
+            return Err( Box::new( std::io::Error::other( "The server is shutting down" ) ) );
+        } else if relay_limit.is_some_and( | limit | serv.relay_count >= limit ) {
+            return Err( Box::new( std::io::Error::other( "The server relay limit has been reached" ) ) );
         } else if serv.sources.len() >= serv.properties.limits.total_sources {
-            return Err( Box::new( std::io::Error::new( ErrorKind::Other, "The server total source limit has been reached" ) ) );
+            return Err( Box::new( std::io::Error::other( "The server total source limit has been reached" ) ) );
         }
 
-        let queue_size = serv.properties.limits.queue_size;
-        let ( burst_size, source_timeout ) =  {
-            if let Some( limit ) = serv.properties.limits.source_limits.get( &path ) {
-                ( limit.burst_size, limit.source_timeout )
-            } else {
-                ( serv.properties.limits.burst_size, serv.properties.limits.header_timeout )
-            }
-        };
-
         // Add to the server
         let arc = Arc::new( RwLock::new( source ) );
+        start_pushes( &server, &serv.properties, &arc ).await;
+        drop( serv.events.send( events::Event::new( &path, events::Kind::Connect ) ) );
         serv.sources.insert( path, arc.clone() );
         serv.relay_count += 1;
         drop( serv );
-
-        struct MetaParser {
-            metaint: usize,
-            vec: Vec< u8 >,
-            remaining: usize
+        // What the upstream was playing when it was listed, until its stream says otherwise
+        if metadata.is_some() {
+            arc.write().await.set_metadata( metadata );
         }
 
-        let metaint = match request::get_header( "Icy-Metaint", res.headers ) {
-            Some( val ) => std::str::from_utf8( val )?.parse::< usize >()?,
-            None => 0
-        };
-        let mut meta_info = MetaParser {
-            metaint,
-            vec: Vec::new(),
-            remaining: metaint
-        };
+        log_info!( "Mounted relay on {} from {}", relay.mount, upstream.url );
+
+        // Listeners stay on the mount while the relay switches between upstreams
+        loop {
+            match relay_upstream( &server, &relay, &arc, &mut upstream, index ).await {
+                UpstreamEnd::Stopped => break,
+                UpstreamEnd::Primary( primary ) => {
+                    log_info!( "Switching relay {} back to {}", relay.mount, primary.url );
+                    upstream = *primary;
+                    index = 0;
+                }
+                UpstreamEnd::Failed => match connect_upstreams( &server, &relay, index + 1, Some( &content_type ) ).await {
+                    Ok( ( next, next_index ) ) => {
+                        log_warn!( "Switching relay {} over to {}", relay.mount, next.url );
+                        upstream = next;
+                        index = next_index;
+                    }
+                    Err( e ) => {
+                        if index + 1 < relay.urls().len() {
+                            log_warn!( "Unable to switch relay {} to another upstream: {}", relay.mount, e );
+                        }
+                        break;
+                    }
+                }
+            }
+            arc.write().await.relay = Some( upstream.url.clone() );
+        }
 
-        println!( "Mounted relay on {}", arc.read().await.mountpoint );
+        unmount_source( &server, &arc, true ).await;
 
-        if buf.len() > body_offset {
-            let slice = &buf[ body_offset .. ];
-            let mut data = Vec::new();
-            // This bit of code is really ugly, but oh well
-            match decoder.decode( &mut data, slice, buf.len() - body_offset ) {
-                Ok( read ) => {
-                    if read != 0 {
-                        // Process any metadata, if it exists
-                        let data = {
-                            if meta_info.metaint != 0 {
-                                let mut trimmed = Vec::new();
-                                let mut position = 0;
-                                let mut last_full: Option< Vec< u8 > > = None;
-                                while position < read {
-                                    // Either reading in regular stream data
-                                    // Reading in the length of the metadata
-                                    // Or reading the metadata directly
-                                    if meta_info.remaining != 0 {
-                                        let frame_length = std::cmp::min( read - position, meta_info.remaining );
-                                        if frame_length != 0 {
-                                            trimmed.extend_from_slice( &data[ position .. position + frame_length ] );
-                                            meta_info.remaining -= frame_length;
-                                            position += frame_length;
-                                        }
-                                    } else if meta_info.vec.is_empty() {
-                                        // Reading the length of the metadata segment
-                                        meta_info.vec.push( data[ position ] );
-                                        position += 1;
-                                    } else {
-                                        // Reading in metadata
-                                        let size = 1 + ( ( meta_info.vec[ 0 ] as usize ) << 4 );
-                                        let remaining_metadata = std::cmp::min( read - position, size - meta_info.vec.len() );
-                                        meta_info.vec.extend_from_slice( &data[ position .. position + remaining_metadata ] );
-                                        position += remaining_metadata;
-
-                                        // If it's reached the max size, then copy it over to last_full
-                                        if meta_info.vec.len() == size {
-                                            meta_info.remaining = meta_info.metaint;
-                                            last_full = Some( meta_info.vec.clone() );
-                                            meta_info.vec.clear();
-                                        }
-                                    }
-                                }
+        log_info!( "Unmounted relay {}", relay.mount );
 
-                                // Update the source's metadata
-                                if let Some( metadata_vec ) = last_full {
-                                    if !{
-                                        let serv_vec = &arc.read().await.metadata_vec;
-                                        serv_vec.len() == metadata_vec.len() && serv_vec.iter().eq( metadata_vec.iter() )
-                                    } {
-                                        if metadata_vec[ .. ] == [ 1; 0 ] {
-                                            let mut serv = arc.write().await;
-                                            println!( "Updated relay {} metadata with no title and url", serv.mountpoint );
-                                            serv.metadata_vec = vec![ 0 ];
-                                            serv.metadata = None;
-                                        } else {
-                                            let cut = {
-                                                let mut last = metadata_vec.len();
-                                                while metadata_vec[ last - 1 ] == 0 {
-                                                    last -= 1;
-                                                }
-                                                last
-                                            };
-                                            if let Ok( meta_str ) = std::str::from_utf8( &metadata_vec[ 1 .. cut ] ) {
-                                                let reg = Regex::new( r"^StreamTitle='(.+?)';StreamUrl='(.+?)';$" ).unwrap();
-                                                if let Some( captures ) = reg.captures( meta_str ) {
-                                                    let metadata = icy::Metadata {
-                                                        title: {
-                                                            let m_str = captures.get( 1 ).unwrap().as_str();
-                                                            if m_str.is_empty() {
-                                                                None
-                                                            } else {
-                                                                Some( m_str.to_string() )
-                                                            }
-                                                        },
-                                                        url: {
-                                                            let m_str = captures.get( 2 ).unwrap().as_str();
-                                                            if m_str.is_empty() {
-                                                                None
-                                                            } else {
-                                                                Some( m_str.to_string() )
-                                                            }
-                                                        }
-                                                    };
-
-                                                    let mut serv = arc.write().await;
-                                                    println!( "Updated relay {} metadata with title '{}' and url '{}'", serv.mountpoint, metadata.title.as_ref().unwrap_or( &"".to_string() ), metadata.url.as_ref().unwrap_or( &"".to_string() ) );
-                                                    serv.metadata_vec = metadata_vec;
-                                                    serv.metadata = Some( metadata );
-                                                } else {
-                                                    println!( "Unknown metadata format received from relay {}: `{}`", arc.read().await.mountpoint, meta_str );
-                                                    arc.write().await.disconnect_flag = true;
-                                                }
-                                            } else {
-                                                println!( "Invalid metadata parsed from relay {}", arc.read().await.mountpoint );
-                                                arc.write().await.disconnect_flag = true;
-                                            }
-                                        }
-                                    }
-                                }
+        Ok( () )
+    }
+}
 
-                                trimmed
-                            } else {
-                                data
-                            }
-                        };
+/**
+ * Pass along everything an upstream sends, until it fails or the relay is stopped
+ * While on a backup, the primary is tried every retry_interval, so the relay can switch back to it
+ */
+async fn relay_upstream( server: &Arc< RwLock< server::Server > >, relay: &server::Relay, arc: &Arc< RwLock< source::Source > >, upstream: &mut Upstream, index: usize ) -> UpstreamEnd {
+    // Icecast leaves out StreamUrl when there isn't one
+    let reg = Regex::new( r"^StreamTitle='(.*?)';(?:StreamUrl='(.*?)';)?$" ).unwrap();
+    let mut last_listened = Instant::now();
+    let mut last_data = Instant::now();
+    let mut last_probe = Instant::now();
+    let mut probe: Option< JoinHandle< Result< Upstream, String > > > = None;
+    let mut received = std::mem::take( &mut upstream.initial );
+    let mut buf = [ 0; 1024 ];
+    loop {
+        let ( source_timeout, listened, disconnect, content_type ) = {
+            let source = arc.read().await;
+            ( source.limits.source_timeout, !source.clients.is_empty(), source.disconnect_flag, source.properties.content_type.clone() )
+        };
+        if disconnect {
+            return UpstreamEnd::Stopped;
+        }
+        if listened {
+            last_listened = Instant::now();
+        } else if relay.on_demand && last_listened.elapsed() >= Duration::from_secs( relay.idle_timeout ) {
+            log_info!( "Disconnecting relay {} after {} seconds without listeners", relay.mount, relay.idle_timeout );
+            arc.write().await.disconnect_flag = true;
+            return UpstreamEnd::Stopped;
+        }
+        // Waiting on a read stops early once the upstream has been silent for too long
+        let mut wait = Duration::from_millis( source_timeout );
+        if relay.silence_timeout != 0 {
+            let silence = Duration::from_secs( relay.silence_timeout );
+            if last_data.elapsed() >= silence {
+                log_warn!( "Relay {} received nothing from {} for {} seconds", relay.mount, upstream.url, relay.silence_timeout );
+                return UpstreamEnd::Failed;
+            }
+            wait = wait.min( silence.saturating_sub( last_data.elapsed() ) );
+        }
 
-                        if !data.is_empty() {
-                            arc.read().await.stats.write().await.bytes_read += data.len();
-                            broadcast_to_clients( &arc, data, queue_size, burst_size ).await;
+        // The primary is connected to in the background, so the stream isn't held up
+        if index != 0 {
+            match &probe {
+                Some( handle ) if handle.is_finished() => {
+                    if let Some( handle ) = probe.take() {
+                        match handle.await {
+                            Ok( Ok( primary ) ) if primary.properties.content_type == content_type => return UpstreamEnd::Primary( Box::new( primary ) ),
+                            Ok( Ok( _ ) ) => log_debug!( "The primary upstream of relay {} is not serving {}", relay.mount, content_type ),
+                            Ok( Err( e ) ) => log_debug!( "The primary upstream of relay {} is still unavailable: {}", relay.mount, e ),
+                            Err( _ ) => ()
                         }
                     }
+                    last_probe = Instant::now();
                 }
-                Err( e ) => {
-                    println!( "An error occurred while decoding stream data from relay {}: {}", arc.read().await.mountpoint, e );
-                    arc.write().await.disconnect_flag = true;
+                None if last_probe.elapsed() >= Duration::from_secs( relay.retry_interval ) => {
+                    let ( server, relay ) = ( server.clone(), relay.clone() );
+                    probe = Some( tokio::spawn( async move {
+                        connect_upstream( &server, &relay, &relay.url ).await.map_err( | e | e.to_string() )
+                    } ) );
                 }
+                _ => ()
             }
         }
 
-        // Listen for bytes
-        if !decoder.is_finished() && !arc.read().await.disconnect_flag {
-            while {
-                // Read the incoming stream data until it closes
-                let mut buf = [ 0; 1024 ];
-                let read = match timeout( Duration::from_millis( source_timeout ), sock.read( &mut buf ) ).await {
-                    Ok( Ok( n ) ) => n,
-                    Ok( Err( e ) ) => {
-                        println!( "An error occurred while reading stream data from relay {}: {}", arc.read().await.mountpoint, e );
-                        0
-                    }
-                    Err( _ ) => {
-                        println!( "A relay timed out: {}", arc.read().await.mountpoint );
-                        0
-                    }
-                };
+        // Read the incoming stream data until it closes
+        if received.is_empty() {
+            match timeout( wait, upstream.sock.read( &mut buf ) ).await {
+                Ok( Ok( 0 ) ) => {
+                    log_info!( "The upstream {} of relay {} closed the connection", upstream.url, relay.mount );
+                    return UpstreamEnd::Failed;
+                }
+                Ok( Ok( read ) ) => received.extend_from_slice( &buf[ .. read ] ),
+                Ok( Err( e ) ) => {
+                    log_error!( "An error occurred while reading stream data from relay {}: {}", relay.mount, e );
+                    return UpstreamEnd::Failed;
+                }
+                Err( _ ) if wait < Duration::from_millis( source_timeout ) => continue,
+                Err( _ ) => {
+                    log_warn!( "A relay timed out: {}", relay.mount );
+                    return UpstreamEnd::Failed;
+                }
+            }
+        }
 
-                let mut data = Vec::new();
-                match decoder.decode( &mut data, &buf, read ) {
-                    Ok( decode_read ) => {
-                        if decode_read != 0 {
-                            // Process any metadata, if it exists
-                            let data = {
-                                if meta_info.metaint != 0 {
-                                    let mut trimmed = Vec::new();
-                                    let mut position = 0;
-                                    let mut last_full: Option< Vec< u8 > > = None;
-                                    while position < decode_read {
-                                        // Either reading in regular stream data
-                                        // Reading in the length of the metadata
-                                        // Or reading the metadata directly
-                                        if meta_info.remaining != 0 {
-                                            let frame_length = std::cmp::min( decode_read - position, meta_info.remaining );
-                                            trimmed.extend_from_slice( &data[ position .. position + frame_length ] );
-                                            meta_info.remaining -= frame_length;
-                                            position += frame_length;
-                                        } else if meta_info.vec.is_empty() {
-                                            // Reading the length of the metadata segment
-                                            meta_info.vec.push( data[ position ] );
-                                            position += 1;
-                                        } else {
-                                            // Reading in metadata
-                                            let size = 1 + ( ( meta_info.vec[ 0 ] as usize ) << 4 );
-                                            let remaining_metadata = std::cmp::min( decode_read - position, size - meta_info.vec.len() );
-                                            meta_info.vec.extend_from_slice( &data[ position .. position + remaining_metadata ] );
-                                            position += remaining_metadata;
-
-                                            // If it's reached the max size, then copy it over to last_full
-                                            if meta_info.vec.len() == size {
-                                                meta_info.remaining = meta_info.metaint;
-                                                last_full = Some( meta_info.vec.clone() );
-                                                meta_info.vec.clear();
-                                            }
-                                        }
-                                    }
-
-                                    // Update the source's metadata
-                                    if let Some( metadata_vec ) = last_full {
-                                        if !{
-                                            let serv_vec = &arc.read().await.metadata_vec;
-                                            serv_vec.len() == metadata_vec.len() && serv_vec.iter().eq( metadata_vec.iter() )
-                                        } {
-                                            if metadata_vec[ .. ] == [ 1; 0 ] {
-                                                let mut serv = arc.write().await;
-                                                println!( "Updated relay {} metadata with no title and url", serv.mountpoint );
-                                                serv.metadata_vec = vec![ 0 ];
-                                                serv.metadata = None;
-                                            } else {
-                                                let cut = {
-                                                    let mut last = metadata_vec.len();
-                                                    while metadata_vec[ last - 1 ] == 0 {
-                                                        last -= 1;
-                                                    }
-                                                    last
-                                                };
-                                                if let Ok( meta_str ) = std::str::from_utf8( &metadata_vec[ 1 .. cut ] ) {
-                                                    let reg = Regex::new( r"^StreamTitle='(.*?)';StreamUrl='(.*?)';$" ).unwrap();
-                                                    if let Some( captures ) = reg.captures( meta_str ) {
-                                                        let metadata = icy::Metadata {
-                                                            title: {
-                                                                let m_str = captures.get( 1 ).unwrap().as_str();
-                                                                if m_str.is_empty() {
-                                                                    None
-                                                                } else {
-                                                                    Some( m_str.to_string() )
-                                                                }
-                                                            },
-                                                            url: {
-                                                                let m_str = captures.get( 2 ).unwrap().as_str();
-                                                                if m_str.is_empty() {
-                                                                    None
-                                                                } else {
-                                                                    Some( m_str.to_string() )
-                                                                }
-                                                            }
-                                                        };
-
-                                                        let mut serv = arc.write().await;
-                                                        println!( "Updated relay {} metadata with title '{}' and url '{}'", serv.mountpoint, metadata.title.as_ref().unwrap_or( &"".to_string() ), metadata.url.as_ref().unwrap_or( &"".to_string() ) );
-                                                        serv.metadata_vec = metadata_vec;
-                                                        serv.metadata = Some( metadata );
-                                                    } else {
-                                                        println!( "Unknown metadata format received from relay {}: `{}`", arc.read().await.mountpoint, meta_str );
-                                                        arc.write().await.disconnect_flag = true;
-                                                    }
-                                                } else {
-                                                    println!( "Invalid metadata parsed from relay {}", arc.read().await.mountpoint );
-                                                    arc.write().await.disconnect_flag = true;
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    trimmed
-                                } else {
-                                    data
-                                }
-                            };
+        let data = relay_chunk( arc, &reg, upstream, &received ).await;
+        received.clear();
+        match data {
+            Ok( data ) => {
+                if !data.is_empty() {
+                    last_data = Instant::now();
+                    arc.read().await.stats.write().await.bytes_read += data.len();
+                    broadcast_to_clients( arc, data ).await;
+                }
+            }
+            Err( e ) => {
+                log_error!( "An error occurred while decoding stream data from relay {}: {}", relay.mount, e );
+                return UpstreamEnd::Failed;
+            }
+        }
 
-                            if !data.is_empty() {
-                                arc.read().await.stats.write().await.bytes_read += data.len();
-                                broadcast_to_clients( &arc, data, queue_size, burst_size ).await;
-                            }
-                        }
+        if upstream.decoder.is_finished() {
+            return UpstreamEnd::Failed;
+        }
+    }
+}
+
+/**
+ * Decode what an upstream sent and take out the ICY metadata, updating the relay's metadata with it
+ * Returns the stream data
+ */
+async fn relay_chunk( arc: &Arc< RwLock< source::Source > >, reg: &Regex, upstream: &mut Upstream, received: &[ u8 ] ) -> Result< Vec< u8 >, Box< dyn Error + Send > > {
+    let mut data = Vec::new();
+    let read = upstream.decoder.decode( &mut data, received, received.len() )?;
+    let meta_info = &mut upstream.meta_info;
+    if read == 0 || meta_info.metaint == 0 {
+        return Ok( data );
+    }
+
+    let mut trimmed = Vec::new();
+    let mut position = 0;
+    let mut last_full: Option< Vec< u8 > > = None;
+    while position < read {
+        // Either reading in regular stream data
+        // Reading in the length of the metadata
+        // Or reading the metadata directly
+        if meta_info.remaining != 0 {
+            let frame_length = std::cmp::min( read - position, meta_info.remaining );
+            trimmed.extend_from_slice( &data[ position .. position + frame_length ] );
+            meta_info.remaining -= frame_length;
+            position += frame_length;
+        } else if meta_info.vec.is_empty() {
+            // Reading the length of the metadata segment
+            meta_info.vec.push( data[ position ] );
+            position += 1;
+        } else {
+            // Reading in metadata
+            let size = 1 + ( ( meta_info.vec[ 0 ] as usize ) << 4 );
+            let remaining_metadata = std::cmp::min( read - position, size - meta_info.vec.len() );
+            meta_info.vec.extend_from_slice( &data[ position .. position + remaining_metadata ] );
+            position += remaining_metadata;
+        }
 
-                        // Check if the source needs to be disconnected
-                        read != 0 && !decoder.is_finished() && !arc.read().await.disconnect_flag
+        // If it's reached the max size, then copy it over to last_full
+        if meta_info.remaining == 0 && !meta_info.vec.is_empty() && meta_info.vec.len() == 1 + ( ( meta_info.vec[ 0 ] as usize ) << 4 ) {
+            meta_info.remaining = meta_info.metaint;
+            last_full = Some( meta_info.vec.clone() );
+            meta_info.vec.clear();
+        }
+    }
+
+    // Update the source's metadata
+    if let Some( metadata_vec ) = last_full {
+        if arc.read().await.metadata_vec != metadata_vec {
+            if metadata_vec[ .. ] == [ 0 ] {
+                let mut serv = arc.write().await;
+                log_info!( "Updated relay {} metadata with no title and url", serv.mountpoint );
+                serv.set_metadata( None );
+            } else {
+                let cut = {
+                    let mut last = metadata_vec.len();
+                    while last > 1 && metadata_vec[ last - 1 ] == 0 {
+                        last -= 1;
                     }
-                    Err( e ) => {
-                        println!( "An error occurred while decoding stream data from relay {}: {}", arc.read().await.mountpoint, e );
-                        false
+                    last
+                };
+                if let Ok( meta_str ) = std::str::from_utf8( &metadata_vec[ 1 .. cut ] ) {
+                    if let Some( captures ) = reg.captures( meta_str ) {
+                        let metadata = icy::Metadata {
+                            title: Some( captures[ 1 ].to_string() ).filter( | title | !title.is_empty() ),
+                            url: captures.get( 2 ).map( | url | url.as_str().to_string() ).filter( | url | !url.is_empty() )
+                        };
+
+                        let mut serv = arc.write().await;
+                        log_info!( "Updated relay {} metadata with title '{}' and url '{}'", serv.mountpoint, metadata.title.as_deref().unwrap_or( "" ), metadata.url.as_deref().unwrap_or( "" ) );
+                        serv.set_metadata( Some( metadata ) );
+                        // Kept as the upstream sent it, to tell when it changes
+                        serv.metadata_vec = metadata_vec;
+                    } else {
+                        log_warn!( "Unknown metadata format received from relay {}: `{}`", arc.read().await.mountpoint, meta_str );
+                        arc.write().await.disconnect_flag = true;
                     }
+                } else {
+                    log_warn!( "Invalid metadata parsed from relay {}", arc.read().await.mountpoint );
+                    arc.write().await.disconnect_flag = true;
                 }
-            }  {}
-        }
-
-        let mut source = arc.write().await;
-        let fallback = source.fallback.clone();
-        if let Some( fallback_id ) = fallback {
-            if let Some( fallback_source ) = server.read().await.sources.get( &fallback_id ) {
-                println!( "Moving listeners from {} to {}", source.mountpoint, fallback_id );
-                let mut fallback = fallback_source.write().await;
-                for ( uuid, client ) in source.clients.drain() {
-                    *client.read().await.source.write().await = fallback_id.clone();
-                    fallback.clients.insert( uuid, client );
-                }
-            } else {
-                println!( "No fallback source {} found! Disconnecting listeners on {}", fallback_id, source.mountpoint );
-                for cli in source.clients.values() {
-                    // Send an empty vec to signify the channel is closed
-                    drop( cli.read().await.sender.write().await.send( Arc::new( Vec::new() ) ) );
-                }
-            }
-        } else {
-            // Disconnect each client by sending an empty buffer
-            println!( "Disconnecting listeners on {}", source.mountpoint );
-            for cli in source.clients.values() {
-                // Send an empty vec to signify the channel is closed
-                drop( cli.read().await.sender.write().await.send( Arc::new( Vec::new() ) ) );
             }
         }
-
-        // Clean up and remove the source
-        let mut serv = server.write().await;
-        serv.sources.remove( &source.mountpoint );
-        serv.relay_count -= 1;
-        serv.stats.session_bytes_read += source.stats.read().await.bytes_read;
-
-        println!( "Unmounted relay {}", source.mountpoint );
-
-        Ok( () )
     }
+
+    Ok( trimmed )
 }
 
-async fn slave_node( server: Arc< RwLock< server::Server > >, master_server: server::Master ) {
+async fn slave_node( server: Arc< RwLock< server::Server > > ) {
     /*
         Master-slave polling
         We will retrieve mountpoints from master node every update_interval and mount them in slave node.
@@ -1484,14 +1824,36 @@ async fn slave_node( server: Arc< RwLock< server::Server > >, master_server: ser
         then we will ignore that mountpoint from master
     */
     loop {
+        // Read every time, since the config can be reloaded
+        let ( master_server, shutting_down ) = {
+            let serv = server.read().await;
+            ( serv.properties.master_server.clone(), serv.shutting_down )
+        };
+        if shutting_down {
+            return;
+        }
+        if !master_server.enabled {
+            tokio::time::sleep( tokio::time::Duration::from_secs( master_server.update_interval.max( 1 ) ) ).await;
+            continue;
+        }
+
         // first we retrieve mountpoints from master
         let mut mounts = Vec::new();
-        match master_server_mountpoints( &server, &master_server ).await {
-            Ok( v ) => mounts.extend( v ),
-            Err( e ) => println!( "Error while fetching mountpoints from {}: {}", master_server.url, e )
-        }
+        let fetched = match master_server_mountpoints( &server, &master_server ).await {
+            Ok( v ) => {
+                mounts.extend( v );
+                true
+            }
+            Err( e ) => {
+                log_error!( "Error while fetching mountpoints from {}: {}", master_server.url, e );
+                false
+            }
+        };
+        // Mounts relayed on demand are only listed, until a listener asks for one
+        let mut listed = Vec::new();
+        let mut hidden = HashSet::new();
 
-        for mount in mounts {
+        for MasterMount { mount, metadata, hidden: listed_hidden } in mounts {
             let path = {
                 // Remove the trailing '/'
                 if mount.ends_with( '/' ) {
@@ -1503,13 +1865,18 @@ async fn slave_node( server: Arc< RwLock< server::Server > >, master_server: ser
                 }
             };
 
-            // Check if the path contains 'admin' or 'api'
-            // TODO Allow for custom stream directory, such as http://example.com/stream/radio
-            if path == "/admin" ||
-                    path.starts_with( "/admin/" ) ||
-                    path == "/api" ||
-                    path.starts_with( "/api/" ) {
-                println!( "Attempted to mount a relay at an invalid mountpoint: {}", path );
+            let valid = server.read().await.properties.validate_mount( &path );
+            if let Err( e ) = valid {
+                log_warn!( "Unable to relay {} from {}: {}", path, master_server.url, e );
+                continue;
+            }
+
+            if listed_hidden {
+                hidden.insert( path.clone() );
+            }
+
+            listed.push( path.clone() );
+            if master_server.on_demand {
                 continue;
             }
 
@@ -1527,49 +1894,348 @@ async fn slave_node( server: Arc< RwLock< server::Server > >, master_server: ser
             let server_clone = server.clone();
             let master_clone = master_server.clone();
             tokio::spawn( async move {
-                let url = master_clone.url.clone();
-                if let Err( e ) = relay_mountpoint( server_clone, master_clone, path.clone() ).await {
-                    println!( "An error occurred while relaying {} from {}: {}", path, url, e );
+                let relay = server::Relay::from_master( &master_clone, &path );
+                if let Err( e ) = relay_mountpoint( server_clone, relay, Some( master_clone.relay_limit ), metadata ).await {
+                    log_error!( "An error occurred while relaying {} from {}: {}", path, master_clone.url, e );
                 }
             } );
         }
 
+        if fetched {
+            let mut serv = server.write().await;
+            serv.master_mounts = listed;
+            serv.hidden_master_mounts = hidden;
+        }
+
         // update interval
         tokio::time::sleep( tokio::time::Duration::from_secs( master_server.update_interval ) ).await;
     }
 }
 
-async fn broadcast_to_clients( source: &Arc< RwLock< source::Source > >, data: Vec< u8 >, queue_size: usize, burst_size: usize ) {
+/**
+ * Keeps the relays from the config running, starting and stopping them as the config is reloaded
+ */
+async fn static_relays( server: Arc< RwLock< server::Server > > ) {
+    // Dropping a stop sender also stops its relay
+    let mut running: HashMap< String, ( server::Relay, watch::Sender< bool >, JoinHandle< () > ) > = HashMap::new();
+    loop {
+        // On demand relays are started by their listeners instead
+        let ( relays, shutting_down ) = {
+            let serv = server.read().await;
+            ( serv.properties.relays.iter().filter( | relay | !relay.on_demand ).cloned().collect::< Vec< server::Relay > >(), serv.shutting_down )
+        };
+        if shutting_down {
+            return;
+        }
+
+        // Relays that were changed are started again once the old one is done
+        for ( relay, stop, _ ) in running.values() {
+            if !relays.contains( relay ) {
+                stop.send( true ).ok();
+            }
+        }
+        running.retain( | _, ( _, _, handle ) | !handle.is_finished() );
+        for relay in relays {
+            if !running.contains_key( &relay.mount ) {
+                let ( stop, stopped ) = watch::channel( false );
+                let handle = tokio::spawn( keep_relaying( server.clone(), relay.clone(), stopped ) );
+                running.insert( relay.mount.clone(), ( relay, stop, handle ) );
+            }
+        }
+
+        tokio::time::sleep( Duration::from_secs( 1 ) ).await;
+    }
+}
+
+/**
+ * Relays a stream until told to stop, reconnecting with an exponential backoff whenever it fails
+ */
+async fn keep_relaying( server: Arc< RwLock< server::Server > >, relay: server::Relay, mut stopped: watch::Receiver< bool > ) {
+    let mut failures = 0;
+    loop {
+        // The error is turned into a string so it isn't held across the wait
+        let mut relaying = Box::pin( async {
+            relay_mountpoint( server.clone(), relay.clone(), None, None ).await.map_err( | e | e.to_string() )
+        } );
+        let result = tokio::select! {
+            result = &mut relaying => result,
+            _ = stopped.changed() => {
+                // The relay might still be connecting, so keep asking until it's gone
+                loop {
+                    if let Some( source ) = server.read().await.sources.get( &relay.mount ) {
+                        let mut source = source.write().await;
+                        if source.relay.is_some() {
+                            source.disconnect_flag = true;
+                        }
+                    }
+                    if timeout( Duration::from_millis( 100 ), &mut relaying ).await.is_ok() {
+                        break;
+                    }
+                }
+                log_info!( "Stopped relaying {} to {}", relay.url, relay.mount );
+                return;
+            }
+        };
+
+        let delay = match result {
+            Ok( () ) => {
+                failures = 0;
+                relay.retry_interval
+            }
+            Err( e ) => {
+                log_error!( "An error occurred while relaying {} from {}: {}", relay.mount, relay.url, e );
+                let delay = retry_delay( relay.retry_interval, failures );
+                failures = ( failures + 1 ).min( 16 );
+                delay
+            }
+        };
+        log_debug!( "Reconnecting relay {} in {} seconds", relay.mount, delay );
+
+        tokio::select! {
+            _ = tokio::time::sleep( Duration::from_secs( delay ) ) => (),
+            _ = stopped.changed() => {
+                log_info!( "Stopped relaying {} to {}", relay.url, relay.mount );
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * How long to wait before reconnecting after the given number of failed attempts
+ * Doubled every time until it reaches the limit, which is never below the retry interval itself
+ */
+fn retry_delay( retry_interval: u64, failures: u32 ) -> u64 {
+    retry_interval.saturating_mul( 1 << failures ).min( MAX_RETRY_INTERVAL.max( retry_interval ) )
+}
+
+/**
+ * Start forwarding a newly mounted source to the push targets of its mount
+ */
+async fn start_pushes( server: &Arc< RwLock< server::Server > >, properties: &server::Properties, arc: &Arc< RwLock< source::Source > > ) {
+    let mut source = arc.write().await;
+    let targets = match properties.mounts.get( &source.mountpoint ) {
+        Some( mount ) if !mount.push.is_empty() => &mount.push,
+        _ => return
+    };
+
+    let ( forward, _ ) = broadcast::channel( PUSH_QUEUE_LENGTH );
+    for target in targets {
+        tokio::spawn( keep_pushing( server.clone(), Arc::downgrade( arc ), target.clone(), forward.subscribe() ) );
+    }
+    source.forward = Some( forward );
+}
+
+/**
+ * Forwards a source to a push target until the source disconnects, reconnecting with an exponential backoff whenever the target fails
+ * A target that falls behind skips ahead on its own, without holding up the listeners or the other targets
+ */
+async fn keep_pushing( server: Arc< RwLock< server::Server > >, weak: Weak< RwLock< source::Source > >, target: server::PushTarget, mut feed: broadcast::Receiver< source::Forward > ) {
+    let mut failures = 0;
+    let mut resumed = false;
+    loop {
+        // Every connection is a new source on the target, so it needs what the source started with
+        let ( mount, properties, pages, metadata ) = match weak.upgrade() {
+            Some( arc ) => {
+                let source = arc.read().await;
+                let pages = source.ogg_headers.as_ref().map( | headers | headers.pages().to_vec() ).unwrap_or_default();
+                ( source.mountpoint.clone(), source.properties.clone(), pages, source.metadata.clone() )
+            }
+            None => return
+        };
+
+        // The error is turned into a string so it isn't held across the wait
+        let pushed = async {
+            // The first connection gets the headers and title through the feed instead
+            let pages = if resumed { &pages[ .. ] } else { &[] };
+            let stream = connect_push( &server, &target, &properties, pages ).await?;
+            log_info!( "Pushing {} to {}", mount, target.url );
+            failures = 0;
+            if resumed && metadata.is_some() {
+                if let Err( e ) = push_metadata( &server, &target, &metadata ).await {
+                    log_warn!( "Unable to update the metadata of push target {} for {}: {}", target.url, mount, e );
+                }
+            }
+            push_feed( &server, &target, &mount, stream, &mut feed ).await
+        }.await.map_err( | e | e.to_string() );
+
+        match pushed {
+            Ok( () ) => {
+                log_info!( "Stopped pushing {} to {}", mount, target.url );
+                return;
+            }
+            Err( e ) => log_error!( "An error occurred while pushing {} to {}: {}", mount, target.url, e )
+        }
+
+        let delay = retry_delay( target.retry_interval, failures );
+        failures = ( failures + 1 ).min( 16 );
+        log_debug!( "Reconnecting push target {} for {} in {} seconds", target.url, mount, delay );
+
+        // Nothing is kept while waiting, but the source might disconnect in the meantime
+        let wait = tokio::time::sleep( Duration::from_secs( delay ) );
+        tokio::pin!( wait );
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                received = feed.recv() => if let Err( RecvError::Closed ) = received {
+                    log_info!( "Stopped pushing {} to {}", mount, target.url );
+                    return;
+                }
+            }
+        }
+        feed = feed.resubscribe();
+        resumed = true;
+    }
+}
+
+/**
+ * Mount a source on a push target, and send it any codec headers the stream needs first
+ */
+async fn connect_push( server: &Arc< RwLock< server::Server > >, target: &server::PushTarget, properties: &icy::Properties, pages: &[ u8 ] ) -> Result< response::Stream, Box< dyn Error > > {
+    let ( server_id, header_timeout, http_max_len, http_max_redirects ) = {
+        let properties = &server.read().await.properties;
+        ( properties.server_id.clone(), properties.limits.header_timeout, properties.limits.http_max_length, properties.limits.http_max_redirects )
+    };
+
+    let encoded = base64::encode( format!( "{}:{}", target.username, target.password ) );
+    let mut headers = vec![
+        format!( "User-Agent: {}", server_id ),
+        format!( "Authorization: Basic {}", encoded ),
+        format!( "Content-Type: {}", properties.content_type ),
+        format!( "Ice-Public: {}", properties.public as u8 )
+    ];
+    let described = [
+        ( "Ice-Name", &properties.name ),
+        ( "Ice-Description", &properties.description ),
+        ( "Ice-Url", &properties.url ),
+        ( "Ice-Genre", &properties.genre ),
+        ( "Ice-Bitrate", &properties.bitrate )
+    ];
+    for ( name, value ) in described {
+        if let Some( value ) = value {
+            headers.push( format!( "{}: {}", name, value ) );
+        }
+    }
+    let method = match target.method {
+        server::PushMethod::Put => {
+            headers.push( "Expect: 100-continue".to_string() );
+            "PUT"
+        }
+        server::PushMethod::Source => "SOURCE"
+    };
+
+    let ( mut stream, buf ) = timeout( Duration::from_millis( header_timeout ), connect_and_redirect( target.url.clone(), method, headers, &[], http_max_len, http_max_redirects ) ).await??;
+
+    let mut headers = [ httparse::EMPTY_HEADER; 32 ];
+    let mut res = httparse::Response::new( &mut headers );
+    res.parse( &buf )?;
+    match res.code {
+        Some( 100 ) | Some( 200 ) => (),
+        Some( code ) => return Err( Box::new( std::io::Error::other( format!( "Invalid response: {} {}", code, res.reason.unwrap_or( "" ) ) ) ) ),
+        None => return Err( Box::new( std::io::Error::other( "Missing response code" ) ) )
+    }
+
+    stream.write_all( pages ).await?;
+    Ok( stream )
+}
+
+/**
+ * Send a push target everything the source does, until the source disconnects
+ */
+async fn push_feed( server: &Arc< RwLock< server::Server > >, target: &server::PushTarget, mount: &str, mut stream: response::Stream, feed: &mut broadcast::Receiver< source::Forward > ) -> Result< (), Box< dyn Error > > {
+    loop {
+        match feed.recv().await {
+            Ok( source::Forward::Data( data ) ) => {
+                // A target that can't take anything for this long is treated as gone
+                let write_timeout = server.read().await.properties.limits.header_timeout;
+                timeout( Duration::from_millis( write_timeout ), stream.write_all( &data ) ).await??;
+            }
+            Ok( source::Forward::Metadata( metadata ) ) => {
+                if let Err( e ) = push_metadata( server, target, &metadata ).await {
+                    log_warn!( "Unable to update the metadata of push target {} for {}: {}", target.url, mount, e );
+                }
+            }
+            Err( RecvError::Lagged( skipped ) ) => log_warn!( "Push target {} for {} fell behind, skipping {} chunks", target.url, mount, skipped ),
+            Err( RecvError::Closed ) => return Ok( () )
+        }
+    }
+}
+
+/**
+ * Update the title on a push target, the same way a source client would
+ */
+async fn push_metadata( server: &Arc< RwLock< server::Server > >, target: &server::PushTarget, metadata: &Option< icy::Metadata > ) -> Result< (), Box< dyn Error > > {
+    let ( server_id, header_timeout, http_max_len, http_max_redirects ) = {
+        let properties = &server.read().await.properties;
+        ( properties.server_id.clone(), properties.limits.header_timeout, properties.limits.http_max_length, properties.limits.http_max_redirects )
+    };
+
+    let mut url = Url::parse( &target.url )?;
+    let mount = url.path().to_string();
+    url.set_path( "/admin/metadata" );
+    {
+        let mut query = url.query_pairs_mut();
+        query.clear()
+            .append_pair( "mode", "updinfo" )
+            .append_pair( "mount", &mount )
+            .append_pair( "song", metadata.as_ref().and_then( | metadata | metadata.title.as_deref() ).unwrap_or( "" ) );
+        if let Some( stream_url ) = metadata.as_ref().and_then( | metadata | metadata.url.as_deref() ) {
+            query.append_pair( "url", stream_url );
+        }
+    }
+
+    let encoded = base64::encode( format!( "{}:{}", target.username, target.password ) );
+    let headers = vec![ format!( "User-Agent: {}", server_id ), format!( "Authorization: Basic {}", encoded ), "Connection: Close".to_string() ];
+    let ( _, buf ) = timeout( Duration::from_millis( header_timeout ), connect_and_redirect( url.to_string(), "GET", headers, &[], http_max_len, http_max_redirects ) ).await??;
+
+    let mut headers = [ httparse::EMPTY_HEADER; 32 ];
+    let mut res = httparse::Response::new( &mut headers );
+    res.parse( &buf )?;
+    match res.code {
+        Some( 200 ) => Ok( () ),
+        Some( code ) => Err( Box::new( std::io::Error::other( format!( "Invalid response: {}", code ) ) ) ),
+        None => Err( Box::new( std::io::Error::other( "Missing response code" ) ) )
+    }
+}
+
+async fn broadcast_to_clients( source: &Arc< RwLock< source::Source > >, data: Vec< u8 > ) {
     // Remove these later
     let mut dropped: Vec< Uuid > = Vec::new();
 
+    // Keep the write lock for the duration of the function, since a race condition with the burst on connect buffer is not wanted
+    let mut locked = source.write().await;
+    let source::ActiveLimits { queue_size, burst_size, .. } = locked.limits;
+
+    // Only whole frames are sent on, so every chunk starts on a frame boundary
+    let ( data, frames ) = locked.framer.push( &data );
+    // Fill the burst on connect buffer, and keep track of any codec headers
+    let data = locked.add_frames( data, frames, burst_size );
+    if data.is_empty() {
+        return;
+    }
     let read = data.len();
     let arc_slice = Arc::new( data );
 
-    // Keep the write lock for the duration of the function, since a race condition with the burst on connect buffer is not wanted
-    let mut locked = source.write().await;
+    // Push targets have queues of their own
+    if let Some( forward ) = &locked.forward {
+        drop( forward.send( source::Forward::Data( arc_slice.clone() ) ) );
+    }
 
     // Broadcast to all listeners
     for ( uuid, cli ) in &locked.clients {
         let client = cli.read().await;
         let mut buf_size = client.buffer_size.write().await;
         let queue = client.sender.write().await;
-        if read + ( *buf_size ) > queue_size || queue.send( arc_slice.clone() ).is_err() {
+        if read + ( *buf_size ) > queue_size {
+            dropped.push( *uuid );
+            locked.stats.write().await.listeners_dropped += 1;
+        } else if queue.send( arc_slice.clone() ).is_err() {
             dropped.push( *uuid );
         } else {
             ( *buf_size ) += read;
         }
     }
 
-    // Fill the burst on connect buffer
-    if burst_size > 0 {
-        let burst_buf = &mut locked.burst_buffer;
-        burst_buf.extend_from_slice( &arc_slice );
-        // Trim it if it's larger than the allowed size
-        if burst_buf.len() > burst_size {
-            burst_buf.drain( .. burst_buf.len() - burst_size );
-        }
-    }
 
     // Remove clients who have been kicked or disconnected
     for uuid in dropped {
@@ -1579,14 +2245,174 @@ async fn broadcast_to_clients( source: &Arc< RwLock< source::Source > >, data: V
     }
 }
 
+enum SocketListener {
+    Tcp( TcpListener ),
+    Unix( UnixListener )
+}
+
+/**
+ * Bind to a listen socket, or take over the one handed down by the previous process when upgrading
+ */
+async fn bind_socket( socket: &server::ListenSocket, tls: &server::Tls, inherited: Option< RawFd > ) -> Result< ( SocketListener, Option< TlsAcceptor > ), Box< dyn Error > > {
+    let acceptor = if socket.tls {
+        if socket.unix_path().is_some() {
+            return Err( Box::new( std::io::Error::new( ErrorKind::InvalidInput, "TLS is not supported on unix sockets" ) ) );
+        }
+
+        let certificate = std::fs::read( &tls.certificate )?;
+        let key = std::fs::read( &tls.key )?;
+        let identity = Identity::from_pkcs8( &certificate, &key )?;
+        Some( TlsAcceptor::from( tokio_native_tls::native_tls::TlsAcceptor::new( identity )? ) )
+    } else {
+        None
+    };
+
+    let listener = match ( socket.unix_path(), inherited ) {
+        ( Some( _ ), Some( fd ) ) => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd( fd ) };
+            listener.set_nonblocking( true )?;
+            SocketListener::Unix( UnixListener::from_std( listener )? )
+        }
+        ( None, Some( fd ) ) => SocketListener::Tcp( inherited_tcp( fd )? ),
+        ( Some( path ), None ) => {
+            // Remove the socket left behind by a previous run, but nothing else
+            if let Ok( metadata ) = std::fs::metadata( path ) {
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file( path )?;
+                }
+            }
+            SocketListener::Unix( UnixListener::bind( path )? )
+        }
+        ( None, None ) => SocketListener::Tcp( TcpListener::bind( socket.address.parse::< SocketAddr >()? ).await? )
+    };
+
+    Ok( ( listener, acceptor ) )
+}
+
+fn inherited_tcp( fd: RawFd ) -> std::io::Result< TcpListener > {
+    let listener = unsafe { std::net::TcpListener::from_raw_fd( fd ) };
+    listener.set_nonblocking( true )?;
+    TcpListener::from_std( listener )
+}
+
+impl SocketListener {
+    fn as_raw_fd( &self ) -> RawFd {
+        match self {
+            SocketListener::Tcp( listener ) => listener.as_raw_fd(),
+            SocketListener::Unix( listener ) => listener.as_raw_fd()
+        }
+    }
+}
+
+/**
+ * Accepts connections forever, doing the TLS handshake first if an acceptor is given
+ */
+async fn accept_connections( server: Arc< RwLock< server::Server > >, listener: SocketListener, acceptor: Option< TlsAcceptor >, socket: Arc< server::ListenSocket > ) {
+    loop {
+        let accepted = match &listener {
+            SocketListener::Tcp( listener ) => listener.accept().await.map( | ( stream, addr ) | ( response::Stream::Plain( stream ), Some( addr ) ) ),
+            SocketListener::Unix( listener ) => listener.accept().await.map( | ( stream, _ ) | ( response::Stream::Unix( stream ), None ) )
+        };
+
+        match accepted {
+            Ok( ( stream, addr ) ) => {
+                let server_clone = server.clone();
+                let acceptor = acceptor.clone();
+                let socket = socket.clone();
+                let peer = match addr {
+                    Some( addr ) => addr.to_string(),
+                    None => socket.address.clone()
+                };
+
+                tokio::spawn( async move {
+                    let stream = match ( acceptor, stream ) {
+                        ( Some( acceptor ), response::Stream::Plain( tcp ) ) => {
+                            // The handshake counts towards the time a client has to send its request
+                            let header_timeout = server_clone.read().await.properties.limits.header_timeout;
+                            match timeout( Duration::from_millis( header_timeout ), acceptor.accept( tcp ) ).await {
+                                Ok( Ok( stream ) ) => response::Stream::Tls( Box::new( stream ) ),
+                                Ok( Err( e ) ) => {
+                                    log_error!( "An error occurred during the TLS handshake with {}: {}", peer, e );
+                                    return;
+                                }
+                                Err( _ ) => {
+                                    log_warn!( "The TLS handshake with {} timed out", peer );
+                                    return;
+                                }
+                            }
+                        }
+                        ( _, stream ) => stream
+                    };
+
+                    if let Err( e ) = handle_connection( server_clone, stream, addr, socket ).await {
+                        log_error!( "An error occurred while handling a connection from {}: {}", peer, e );
+                    }
+                } );
+            }
+            Err( e ) => log_error!( "An error occurred while accepting a connection on {}: {}", socket.address, e )
+        }
+    }
+}
+
+/**
+ * Accepts SHOUTcast v1 encoders forever
+ */
+async fn accept_shoutcast_sources( server: Arc< RwLock< server::Server > >, listener: TcpListener ) {
+    loop {
+        match listener.accept().await {
+            Ok( ( stream, addr ) ) => {
+                let server_clone = server.clone();
+                tokio::spawn( async move {
+                    if let Err( e ) = handle_shoutcast_source( server_clone, response::Stream::Plain( stream ), addr ).await {
+                        log_error!( "An error occurred while handling a SHOUTcast source from {}: {}", addr, e );
+                    }
+                } );
+            }
+            Err( e ) => log_error!( "An error occurred while accepting a SHOUTcast source: {}", e )
+        }
+    }
+}
+
+/**
+ * Reads a password from stdin and prints its hash, for use in the users section of the config
+ */
+fn hash_password_command() {
+    let mut password = String::new();
+    if let Err( e ) = std::io::stdin().read_line( &mut password ) {
+        eprintln!( "An error occurred while reading the password: {}", e );
+        std::process::exit( 1 );
+    }
+
+    // Only remove the line ending, in case the password itself has whitespace
+    let password = password.strip_suffix( '\n' ).unwrap_or( &password );
+    let password = password.strip_suffix( '\r' ).unwrap_or( password );
+    if password.is_empty() {
+        eprintln!( "No password was provided" );
+        std::process::exit( 1 );
+    }
+
+    match server::hash_password( password ) {
+        Ok( hash ) => println!( "{}", hash ),
+        Err( e ) => {
+            eprintln!( "An error occurred while hashing the password: {}", e );
+            std::process::exit( 1 );
+        }
+    }
+}
+
 // Serde default deserialization values
 
 #[ tokio::main ]
 async fn main() {
     // TODO Log everything somehow or something
+    let args: Vec< String > = std::env::args().collect();
+    if args.get( 1 ).map( | arg | arg.as_str() ) == Some( "hash-password" ) {
+        hash_password_command();
+        return;
+    }
+
     let mut properties = server::Properties::new();
 
-    let args: Vec< String > = std::env::args().collect();
     let config_location = {
         if args.len() > 1 {
             args[ 1 ].clone()
@@ -1605,20 +2431,20 @@ async fn main() {
             }
         }
     };
-    println!( "Using config path {}", config_location );
+    log_info!( "Using config path {}", config_location );
 
     match std::fs::read_to_string( &config_location ) {
         Ok( contents ) => {
-            println!( "Attempting to parse the config" );
-            match serde_json::from_str( &contents.as_str() ) {
+            log_debug!( "Attempting to parse the config" );
+            match serde_json::from_str( contents.as_str() ) {
                 Ok( prop ) => properties = prop,
-                Err( e ) => println!( "An error occurred while parsing the config: {}", e ),
+                Err( e ) => log_error!( "An error occurred while parsing the config: {}", e ),
             }
         }
         Err( e ) if e.kind() == std::io::ErrorKind::NotFound => {
-            println!( "The config file was not found! Attempting to save to file" );
+            log_info!( "The config file was not found! Attempting to save to file" );
         }
-        Err( e ) => println!( "An error occurred while trying to read the config: {}", e ),
+        Err( e ) => log_error!( "An error occurred while trying to read the config: {}", e ),
     }
 
     // Create or update the current config
@@ -1628,91 +2454,182 @@ async fn main() {
                 Ok( config ) => {
                     let mut writer = BufWriter::new( file );
                     if let Err( e ) = writer.write_all( config.as_bytes() ) {
-                        println!( "An error occurred while writing to the config file: {}", e );
+                        log_error!( "An error occurred while writing to the config file: {}", e );
                     }
                 }
-                Err( e ) => println!( "An error occurred while trying to serialize the server properties: {}", e ),
-            }
-        }
-        Err( e ) => println!( "An error occurred while to create the config file: {}", e ),
-    }
-
-    println!( "Using ADDRESS            : {}", properties.address );
-    println!( "Using PORT               : {}", properties.port );
-    println!( "Using METAINT            : {}", properties.metaint );
-    println!( "Using SERVER ID          : {}", properties.server_id );
-    println!( "Using ADMIN              : {}", properties.admin );
-    println!( "Using HOST               : {}", properties.host );
-    println!( "Using LOCATION           : {}", properties.location );
-    println!( "Using DESCRIPTION        : {}", properties.description );
-    println!( "Using CLIENT LIMIT       : {}", properties.limits.clients );
-    println!( "Using SOURCE LIMIT       : {}", properties.limits.sources );
-    println!( "Using QUEUE SIZE         : {}", properties.limits.queue_size );
-    println!( "Using BURST SIZE         : {}", properties.limits.burst_size );
-    println!( "Using HEADER TIMEOUT     : {}", properties.limits.header_timeout );
-    println!( "Using SOURCE TIMEOUT     : {}", properties.limits.source_timeout );
-    println!( "Using HTTP MAX LENGTH    : {}", properties.limits.http_max_length );
-    println!( "Using HTTP MAX REDIRECTS : {}", properties.limits.http_max_length );
+                Err( e ) => log_error!( "An error occurred while trying to serialize the server properties: {}", e ),
+            }
+        }
+        Err( e ) => log_error!( "An error occurred while to create the config file: {}", e ),
+    }
+
+    if let Err( e ) = logging::init( &properties.logging ) {
+        log_error!( "Unable to open the logs: {}", e );
+        return;
+    }
+
+    for socket in properties.listen_sockets() {
+        let roles = socket.roles.iter().map( | role | format!( "{:?}", role ).to_lowercase() ).collect::< Vec< String > >().join( ", " );
+        log_info!( "Using LISTEN SOCKET      : {}{} ({})", socket.address, if socket.tls { " with TLS" } else { "" }, roles );
+    }
+    if let ( true, Some( address ) ) = ( properties.shoutcast.enabled, properties.shoutcast_address() ) {
+        log_info!( "Using SHOUTCAST SOURCE   : {} ({})", address, properties.shoutcast.mount );
+    }
+    if properties.hls.enabled {
+        log_info!( "Using HLS SEGMENTS       : {} of {}s", properties.hls.segments, properties.hls.segment_duration );
+    }
+    log_info!( "Using METAINT            : {}", properties.metaint );
+    log_info!( "Using SERVER ID          : {}", properties.server_id );
+    log_info!( "Using ADMIN              : {}", properties.admin );
+    log_info!( "Using HOST               : {}", properties.host );
+    log_info!( "Using LOCATION           : {}", properties.location );
+    log_info!( "Using DESCRIPTION        : {}", properties.description );
+    log_info!( "Using CLIENT LIMIT       : {}", properties.limits.clients );
+    log_info!( "Using SOURCE LIMIT       : {}", properties.limits.sources );
+    log_info!( "Using QUEUE SIZE         : {}", properties.limits.queue_size );
+    log_info!( "Using BURST SIZE         : {}", properties.limits.burst_size );
+    log_info!( "Using HEADER TIMEOUT     : {}", properties.limits.header_timeout );
+    log_info!( "Using SOURCE TIMEOUT     : {}", properties.limits.source_timeout );
+    log_info!( "Using HTTP MAX LENGTH    : {}", properties.limits.http_max_length );
+    log_info!( "Using HTTP MAX REDIRECTS : {}", properties.limits.http_max_length );
     if properties.master_server.enabled {
-        println!( "Using a master server:" );
-        println!( "      URL                : {}", properties.master_server.url );
-        println!( "      UPDATE INTERVAL    : {} seconds", properties.master_server.update_interval );
-        println!( "      RELAY LIMIT        : {}", properties.master_server.relay_limit );
+        log_info!( "Using a master server:" );
+        log_info!( "      URL                : {}", properties.master_server.url );
+        log_info!( "      UPDATE INTERVAL    : {} seconds", properties.master_server.update_interval );
+        log_info!( "      RELAY LIMIT        : {}", properties.master_server.relay_limit );
     }
     for ( mount, limit ) in &properties.limits.source_limits {
-        println!( "Using limits for {}:", mount );
-        println!( "      CLIENT LIMIT       : {}", limit.clients );
-        println!( "      SOURCE TIMEOUT     : {}", limit.source_timeout );
-        println!( "      BURST SIZE         : {}", limit.burst_size );
+        log_info!( "Using limits for {}:", mount );
+        log_info!( "      CLIENT LIMIT       : {}", limit.clients );
+        log_info!( "      SOURCE TIMEOUT     : {}", limit.source_timeout );
+        log_info!( "      BURST SIZE         : {}", limit.burst_size );
     }
 
-    if properties.users.is_empty() {
-        println!( "At least one user must be configured in the config!" );
+    if let Err( e ) = properties.validate() {
+        log_error!( "{}", e );
     } else {
-        println!( "{} users registered", properties.users.len() );
-        match format!( "{}:{}", properties.address, properties.port ).parse::< SocketAddr >() {
-            Ok( address ) => {
-                println!( "Attempting to bind to {}:{}", properties.address, properties.port );
-                match TcpListener::bind( address ).await {
-                    Ok( listener ) => {
-                        let server = Arc::new( RwLock::new( server::Server::new( properties ) ) );
-
-                        if let Ok( time ) = SystemTime::now().duration_since( UNIX_EPOCH ) {
-                            println!( "The server has started on {}", fmt_http_date( SystemTime::now() ) );
-                            server.write().await.stats.start_time = time.as_secs();
-                        } else {
-                            println!( "Unable to capture when the server started!" );
-                        }
+        log_info!( "{} users registered", properties.users.len() );
+        for user in &properties.users {
+            if !user.is_hashed() {
+                log_warn!( "The password of user {} is stored in plaintext, use the hash-password command to hash it", user.username );
+            }
+        }
+        // Bind everything first, so a missing socket doesn't go unnoticed
+        let inherited = shutdown::inherited_sockets();
+        let mut listeners = Vec::new();
+        for socket in properties.listen_sockets() {
+            log_info!( "Attempting to bind to {}", socket.address );
+            match bind_socket( &socket, &properties.tls, inherited.get( &socket.address ).copied() ).await {
+                Ok( ( listener, acceptor ) ) => listeners.push( ( listener, acceptor, Arc::new( socket ) ) ),
+                Err( e ) => {
+                    log_error!( "Unable to bind to {}: {}", socket.address, e );
+                    return;
+                }
+            }
+        }
 
-                        let master_server = server.read().await.properties.master_server.clone();
-                        if master_server.enabled {
-                            // Start our slave node
-                            let server_clone = server.clone();
-                            tokio::spawn( async move {
-                                slave_node( server_clone, master_server ).await;
-                            } );
-                        }
+        let shoutcast_listener = if properties.shoutcast.enabled {
+            let address = match properties.shoutcast_address() {
+                Some( address ) => address,
+                None => {
+                    log_error!( "Unable to find an address for SHOUTcast sources, a TCP listen socket or shoutcast.port is needed" );
+                    return;
+                }
+            };
+            log_info!( "Attempting to bind to {} for SHOUTcast sources", address );
+            let bound = match inherited.get( &address.to_string() ) {
+                Some( fd ) => inherited_tcp( *fd ),
+                None => TcpListener::bind( address ).await
+            };
+            match bound {
+                Ok( listener ) => Some( listener ),
+                Err( e ) => {
+                    log_error!( "Unable to bind to {}: {}", address, e );
+                    return;
+                }
+            }
+        } else {
+            None
+        };
 
-                        println!( "Listening..." );
-                        loop {
-                            match listener.accept().await {
-                                Ok( ( socket, addr ) ) => {
-                                    let server_clone = server.clone();
-
-                                    tokio::spawn( async move {
-                                        if let Err( e ) = handle_connection( server_clone, socket ).await {
-                                            println!( "An error occurred while handling a connection from {}: {}", addr, e );
-                                        }
-                                    } );
-                                }
-                                Err( e ) => println!( "An error occurred while accepting a connection: {}", e )
-                            }
-                        }
+        let server = Arc::new( RwLock::new( server::Server::new( properties ) ) );
+        server.write().await.config_location = config_location;
+
+        if let Ok( time ) = SystemTime::now().duration_since( UNIX_EPOCH ) {
+            log_info!( "The server has started on {}", fmt_http_date( SystemTime::now() ) );
+            server.write().await.stats.start_time = time.as_secs();
+        } else {
+            log_error!( "Unable to capture when the server started!" );
+        }
+
+        // Start our slave node, which waits for a master server to be configured
+        let server_clone = server.clone();
+        tokio::spawn( async move {
+            slave_node( server_clone ).await;
+        } );
+
+        tokio::spawn( static_relays( server.clone() ) );
+
+        // Reload the config, which also lets logrotate move the log files out from under the server
+        let server_clone = server.clone();
+        tokio::spawn( async move {
+            let mut hangup = match signal( SignalKind::hangup() ) {
+                Ok( hangup ) => hangup,
+                Err( e ) => {
+                    log_error!( "Unable to listen for SIGHUP: {}", e );
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                if let Err( e ) = server::reload( &server_clone ).await {
+                    log_error!( "Unable to reload the config: {}", e );
+                    // The log files still have to be reopened
+                    if let Err( e ) = logging::reopen() {
+                        log_error!( "Unable to reopen the log files: {}", e );
                     }
-                    Err( e ) => println!( "Unable to bind to port: {}", e )
                 }
             }
-            Err( e ) => println!( "Could not parse the address: {}", e )
+        } );
+
+        let ( mut terminate, mut upgrade ) = match ( signal( SignalKind::terminate() ), signal( SignalKind::user_defined2() ) ) {
+            ( Ok( terminate ), Ok( upgrade ) ) => ( terminate, upgrade ),
+            ( Err( e ), _ ) | ( _, Err( e ) ) => {
+                log_error!( "Unable to listen for signals: {}", e );
+                return;
+            }
+        };
+
+        log_info!( "Listening..." );
+        // Kept to be handed over when upgrading
+        let mut sockets = Vec::new();
+        let mut handles = Vec::new();
+        for ( listener, acceptor, socket ) in listeners {
+            sockets.push( ( socket.address.clone(), listener.as_raw_fd() ) );
+            handles.push( tokio::spawn( accept_connections( server.clone(), listener, acceptor, socket ) ) );
+        }
+        if let Some( listener ) = shoutcast_listener {
+            if let Ok( address ) = listener.local_addr() {
+                sockets.push( ( address.to_string(), listener.as_raw_fd() ) );
+            }
+            handles.push( tokio::spawn( accept_shoutcast_sources( server.clone(), listener ) ) );
+        }
+        shutdown::finish_upgrade();
+
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => break,
+                _ = upgrade.recv() => match shutdown::upgrade( &sockets ) {
+                    // The new process stops this one once it's ready
+                    Ok( pid ) => log_info!( "Started process {} to take over", pid ),
+                    Err( e ) => log_error!( "Unable to start a new process: {}", e )
+                }
+            }
+        }
+
+        // Stop accepting before anything else
+        for handle in handles {
+            handle.abort();
         }
+        shutdown::graceful( &server ).await;
     }
 }
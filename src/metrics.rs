@@ -0,0 +1,299 @@
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+use crate::api::{self, HandlerFuture};
+use crate::request::{self, Request};
+use crate::response::Response;
+use crate::server::{Permission, Server};
+
+// Server and mount stats in the Prometheus text format, for scraping
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+
+// Upper bounds in bytes of the listener queue histogram, queues over the last one only count towards +Inf
+const QUEUE_BUCKETS: [usize; 7] = [1024, 4096, 16384, 65536, 131072, 262144, 1048576];
+
+// A metric with one sample per mount
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: String,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Family {
+        Family {
+            name,
+            kind,
+            help,
+            samples: String::new(),
+        }
+    }
+
+    fn add(&mut self, labels: &str, value: impl Display) {
+        self.add_suffixed("", labels, value);
+    }
+
+    // Histograms are made of several series named after the metric
+    fn add_suffixed(&mut self, suffix: &str, labels: &str, value: impl Display) {
+        self.samples.push_str(&format!(
+            "{}{}{{{}}} {}\n",
+            self.name, suffix, labels, value
+        ));
+    }
+
+    fn write(&self, out: &mut String) {
+        header(out, self.name, self.kind, self.help);
+        out.push_str(&self.samples);
+    }
+}
+
+pub fn metrics<'a>(server: &'a Arc<RwLock<Server>>, req: &'a Request<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let metrics = server.read().await.properties.metrics.clone();
+        if !metrics.enabled {
+            return Ok(Response::not_found());
+        }
+        // Credentials are only needed for a permission, but hidden mounts are listed to users sending them
+        let user = if metrics.permission.is_some()
+            || request::get_header("Authorization", req.headers).is_some()
+        {
+            match api::authenticate(server, req.headers).await {
+                Ok(user) => Some(user),
+                Err(response) => return Ok(response),
+            }
+        } else {
+            None
+        };
+        if let Some(permission) = metrics.permission {
+            if !user
+                .as_ref()
+                .is_some_and(|user| user.has_permission(permission, None))
+            {
+                return Ok(api::missing_permission(permission, None));
+            }
+        }
+        let listed = |hidden: bool, mount: &str| {
+            !hidden
+                || user
+                    .as_ref()
+                    .is_some_and(|user| user.has_permission(Permission::Hidden, Some(mount)))
+        };
+        let serv = server.read().await;
+
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(time) => time.as_secs(),
+            Err(_) => 0,
+        };
+
+        let mut listeners = Family::new(
+            "rusty_zenith_mount_listeners",
+            "gauge",
+            "Listeners currently connected to the mount",
+        );
+        let mut peak_listeners = Family::new(
+            "rusty_zenith_mount_peak_listeners",
+            "gauge",
+            "Most listeners connected to the mount at once",
+        );
+        let mut bytes_read = Family::new(
+            "rusty_zenith_mount_bytes_read_total",
+            "counter",
+            "Bytes received from the source",
+        );
+        let mut bytes_sent = Family::new(
+            "rusty_zenith_mount_bytes_sent_total",
+            "counter",
+            "Bytes sent to listeners of the mount",
+        );
+        let mut uptime = Family::new(
+            "rusty_zenith_mount_uptime_seconds",
+            "gauge",
+            "How long the source has been connected",
+        );
+        let mut dropped = Family::new(
+            "rusty_zenith_mount_listeners_dropped_total",
+            "counter",
+            "Listeners disconnected for falling more than queue_size bytes behind",
+        );
+        let mut relays = Family::new(
+            "rusty_zenith_relay_up",
+            "gauge",
+            "Whether the relay is connected to its upstream",
+        );
+        let mut queue = Family::new(
+            "rusty_zenith_mount_listener_queue_bytes",
+            "histogram",
+            "Bytes queued for each listener, waiting to be sent",
+        );
+
+        let mut total_bytes_read = serv.stats.session_bytes_read;
+        let mut total_bytes_sent = serv.stats.session_bytes_sent;
+        let mut total_dropped = serv.stats.session_listeners_dropped;
+        let mut mounts: Vec<&String> = serv.sources.keys().collect();
+        mounts.sort();
+        for mount in mounts {
+            let source = serv.sources[mount].read().await;
+            let stats = source.stats.read().await;
+            let mut buckets = [0; QUEUE_BUCKETS.len()];
+            let mut sum = 0;
+            for client in source.clients.values() {
+                let client = client.read().await;
+                let queued = *client.buffer_size.read().await;
+                for (bucket, bound) in buckets.iter_mut().zip(QUEUE_BUCKETS.iter()) {
+                    if queued <= *bound {
+                        *bucket += 1;
+                    }
+                }
+                sum += queued;
+                total_bytes_sent += client.stats.read().await.bytes_sent;
+            }
+            total_bytes_read += stats.bytes_read;
+            total_dropped += stats.listeners_dropped;
+
+            // Hidden mounts still count towards the totals
+            if !listed(source.hidden || serv.hidden(mount), mount) {
+                continue;
+            }
+            let label = format!("mount=\"{}\"", escape(mount));
+
+            listeners.add(&label, source.clients.len());
+            peak_listeners.add(&label, stats.peak_listeners);
+            bytes_read.add(&label, stats.bytes_read);
+            bytes_sent.add(&label, stats.bytes_sent);
+            uptime.add(&label, now.saturating_sub(stats.start_time));
+            dropped.add(&label, stats.listeners_dropped);
+
+            for (count, bound) in buckets.iter().zip(QUEUE_BUCKETS.iter()) {
+                queue.add_suffixed("_bucket", &format!("{},le=\"{}\"", label, bound), count);
+            }
+            let count = source.clients.len();
+            queue.add_suffixed("_bucket", &format!("{},le=\"+Inf\"", label), count);
+            queue.add_suffixed("_sum", &label, sum);
+            queue.add_suffixed("_count", &label, count);
+        }
+
+        // Relays that aren't connected are listed too, with the upstream they'd start from
+        let mut relay_mounts = serv.relays();
+        relay_mounts.sort_by(|(a, _), (b, _)| a.mount.cmp(&b.mount));
+        for (relay, _) in relay_mounts {
+            let source = match serv.sources.get(&relay.mount) {
+                Some(source) => Some(source.read().await),
+                None => None,
+            };
+            let hidden = source.as_ref().is_some_and(|source| source.hidden);
+            if !listed(hidden || serv.hidden(&relay.mount), &relay.mount) {
+                continue;
+            }
+            let (url, up) = match source.as_ref().and_then(|source| source.relay.clone()) {
+                Some(url) => (url, 1),
+                None => (relay.url, 0),
+            };
+            relays.add(
+                &format!(
+                    "mount=\"{}\",url=\"{}\"",
+                    escape(&relay.mount),
+                    escape(&url)
+                ),
+                up,
+            );
+        }
+
+        let mut out = String::new();
+        single(
+            &mut out,
+            "rusty_zenith_uptime_seconds",
+            "gauge",
+            "How long the server has been running",
+            now.saturating_sub(serv.stats.start_time),
+        );
+        single(
+            &mut out,
+            "rusty_zenith_listeners",
+            "gauge",
+            "Listeners currently connected",
+            serv.clients.len(),
+        );
+        single(
+            &mut out,
+            "rusty_zenith_peak_listeners",
+            "gauge",
+            "Most listeners connected at once",
+            serv.stats.peak_listeners,
+        );
+        single(
+            &mut out,
+            "rusty_zenith_sources",
+            "gauge",
+            "Sources currently connected, not including relays",
+            serv.source_count,
+        );
+        single(
+            &mut out,
+            "rusty_zenith_relays",
+            "gauge",
+            "Relays currently connected",
+            serv.relay_count,
+        );
+        single(
+            &mut out,
+            "rusty_zenith_bytes_read_total",
+            "counter",
+            "Bytes received from every source",
+            total_bytes_read,
+        );
+        single(
+            &mut out,
+            "rusty_zenith_bytes_sent_total",
+            "counter",
+            "Bytes sent to every listener",
+            total_bytes_sent,
+        );
+        single(
+            &mut out,
+            "rusty_zenith_listeners_dropped_total",
+            "counter",
+            "Listeners disconnected for falling more than queue_size bytes behind",
+            total_dropped,
+        );
+        for family in [
+            &listeners,
+            &peak_listeners,
+            &bytes_read,
+            &bytes_sent,
+            &uptime,
+            &dropped,
+            &relays,
+            &queue,
+        ] {
+            family.write(&mut out);
+        }
+
+        Ok(Response::ok(Some((
+            "text/plain; version=0.0.4; charset=utf-8",
+            &out,
+        ))))
+    })
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n",
+        name, help, name, kind
+    ));
+}
+
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    header(out, name, kind, help);
+    out.push_str(&format!("{} {}\n", name, value));
+}
+
+// Label values are quoted, so quotes, backslashes and newlines have to be escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
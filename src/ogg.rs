@@ -0,0 +1,808 @@
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::codec;
+use crate::icy;
+
+// Every link of a chained Ogg stream starts with the codec headers of its logical streams,
+// and the title only ever arrives in one of them
+// https://xiph.org/ogg/doc/framing.html
+
+const CONTINUED: u8 = 0x01;
+const BOS: u8 = 0x02;
+const EOS: u8 = 0x04;
+// Pages where no packet ends don't have a granule position
+const NO_GRANULE: u64 = u64::MAX;
+
+pub enum Page {
+    // The first header page of a new link sent by the source
+    Chain,
+    Header,
+    // A header page finishing a comment header, along with the title it carries
+    Comments(Option<icy::Metadata>),
+    // A data page, preceded by the headers of a new link with a different title
+    Restart,
+    Data,
+}
+
+// How a codec is mapped into Ogg, as far as its headers go
+#[derive(Clone, Copy)]
+enum Mapping {
+    Vorbis,
+    Theora,
+    Opus,
+    Speex,
+    Flac,
+    Unknown,
+}
+
+impl Mapping {
+    // Going by the first packet of a logical stream
+    fn from_packet(packet: &[u8]) -> Mapping {
+        if packet.starts_with(b"\x01vorbis") {
+            Mapping::Vorbis
+        } else if packet.starts_with(b"\x80theora") {
+            Mapping::Theora
+        } else if packet.starts_with(b"OpusHead") {
+            Mapping::Opus
+        } else if packet.starts_with(b"Speex   ") && packet.len() >= 80 {
+            Mapping::Speex
+        } else if packet.starts_with(b"\x7fFLAC") && packet.len() >= 9 {
+            Mapping::Flac
+        } else {
+            Mapping::Unknown
+        }
+    }
+
+    // How many header packets the logical stream has, including the first
+    fn header_packets(&self, packet: &[u8]) -> usize {
+        match self {
+            // Identification, comment and setup
+            Mapping::Vorbis | Mapping::Theora => 3,
+            // Identification and comment
+            Mapping::Opus => 2,
+            // Identification, comment and any extra headers it announces
+            Mapping::Speex => {
+                2 + u32::from_le_bytes([packet[76], packet[77], packet[78], packet[79]]) as usize
+            }
+            // The mapping header announces how many metadata packets follow it
+            Mapping::Flac => 1 + u16::from_be_bytes([packet[7], packet[8]]) as usize,
+            Mapping::Unknown => 1,
+        }
+    }
+
+    // The comment list in a header packet, if that's what the packet is
+    fn comments<'a>(&self, index: usize, packet: &'a [u8]) -> Option<&'a [u8]> {
+        match self {
+            Mapping::Vorbis => packet.strip_prefix(b"\x03vorbis"),
+            Mapping::Theora => packet.strip_prefix(b"\x81theora"),
+            Mapping::Opus => packet.strip_prefix(b"OpusTags"),
+            Mapping::Speex if index == 1 => Some(packet),
+            // A metadata block of type 4, after its 4 byte header
+            Mapping::Flac if index > 0 && packet.len() >= 4 && packet[0] & 0x7F == 4 => {
+                Some(&packet[4..])
+            }
+            _ => None,
+        }
+    }
+
+    // The same header packet with the title replaced, if it is a comment header
+    fn replace_title(&self, index: usize, packet: &[u8], title: Option<&str>) -> Option<Vec<u8>> {
+        let comments = self.comments(index, packet)?;
+        let prefix = &packet[..packet.len() - comments.len()];
+        let (vendor, list) = read_comments(comments)?;
+
+        // Artist and title are both part of the new title
+        let mut list: Vec<Vec<u8>> = list
+            .into_iter()
+            .filter(|comment| {
+                let field = comment.split(|&byte| byte == b'=').next().unwrap_or(&[]);
+                !field.eq_ignore_ascii_case(b"TITLE") && !field.eq_ignore_ascii_case(b"ARTIST")
+            })
+            .map(|comment| comment.to_vec())
+            .collect();
+        if let Some(title) = title {
+            list.push(format!("TITLE={}", title).into_bytes());
+        }
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        body.extend_from_slice(vendor);
+        body.extend_from_slice(&(list.len() as u32).to_le_bytes());
+        for comment in list {
+            body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            body.extend_from_slice(&comment);
+        }
+
+        let mut packet = match self {
+            // The block header holds the length of the block
+            Mapping::Flac => {
+                let length = (body.len() as u32).to_be_bytes();
+                vec![prefix[0], length[1], length[2], length[3]]
+            }
+            _ => prefix.to_vec(),
+        };
+        packet.extend_from_slice(&body);
+        if let Mapping::Vorbis = self {
+            // The framing bit
+            packet.push(1);
+        }
+        Some(packet)
+    }
+}
+
+// A logical stream of the current link
+struct Stream {
+    mapping: Mapping,
+    // How many header packets are still to come
+    remaining: usize,
+    // The header packets read so far, and the one being read
+    packets: Vec<Vec<u8>>,
+    packet: Vec<u8>,
+    // The serial number and next page sequence as listeners see them, which differ once restarted
+    serial: u32,
+    sequence: u32,
+    granule: u64,
+    restarted: bool,
+    // A restarted stream can't begin with the rest of a packet from before
+    fresh: bool,
+}
+
+/**
+ * Keeps the header pages of every logical stream in the current link of an Ogg stream
+ * A listener can't decode anything without them, so they're sent before the burst
+ */
+pub struct Headers {
+    pages: Vec<u8>,
+    streams: HashMap<u32, Stream>,
+    // Beginning of stream pages that follow each other are multiplexed into the same link
+    grouping: bool,
+    // A title waiting for the next data page to start a new link with
+    retitle: Option<icy::Metadata>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers {
+            pages: Vec::new(),
+            streams: HashMap::new(),
+            grouping: false,
+            retitle: None,
+        }
+    }
+
+    pub fn pages(&self) -> &[u8] {
+        &self.pages
+    }
+
+    /**
+     * Start a new link with the title in its comment headers, so players without ICY metadata see it too
+     * It begins at the next data page, once every header of the current link is known
+     */
+    pub fn retitle(&mut self, metadata: &Option<icy::Metadata>) {
+        self.retitle = Some(metadata.clone().unwrap_or(icy::Metadata {
+            title: None,
+            url: None,
+        }));
+    }
+
+    /**
+     * Takes a whole page and writes it out as listeners should get it, returning what it was
+     */
+    pub fn push(&mut self, page: &[u8], out: &mut Vec<u8>) -> Page {
+        let serial = read_u32(page, 14);
+        let sequence = read_u32(page, 18);
+
+        if page[5] & BOS != 0 {
+            let kind = if self.grouping {
+                Page::Header
+            } else {
+                // Anything from the previous link is of no use anymore
+                self.pages.clear();
+                self.streams.clear();
+                Page::Chain
+            };
+            self.grouping = true;
+
+            let first = body(page);
+            let mapping = Mapping::from_packet(first);
+            self.streams.insert(
+                serial,
+                Stream {
+                    mapping,
+                    remaining: mapping.header_packets(first),
+                    packets: Vec::new(),
+                    packet: Vec::new(),
+                    serial,
+                    sequence: sequence.wrapping_add(1),
+                    granule: 0,
+                    restarted: false,
+                    fresh: false,
+                },
+            );
+            self.pages.extend_from_slice(page);
+            self.read_packets(serial, page);
+            out.extend_from_slice(page);
+            return kind;
+        }
+        self.grouping = false;
+
+        let headers_done = self.streams.values().all(|stream| stream.remaining == 0);
+        match self.streams.get_mut(&serial) {
+            None => {
+                // Only streams that started while the source was connected are known
+                out.extend_from_slice(page);
+                return Page::Data;
+            }
+            Some(stream) if stream.remaining > 0 => {
+                stream.sequence = sequence.wrapping_add(1);
+                self.pages.extend_from_slice(page);
+                out.extend_from_slice(page);
+                return match self.read_packets(serial, page) {
+                    Some(comments) => Page::Comments(parse_comments(&comments)),
+                    None => Page::Header,
+                };
+            }
+            Some(_) => (),
+        }
+
+        let mut kind = Page::Data;
+        if headers_done {
+            if let Some(metadata) = self.retitle.take() {
+                self.restart(metadata.title.as_deref(), out);
+                kind = Page::Restart;
+            }
+        }
+        if let Some(stream) = self.streams.get_mut(&serial) {
+            write_page(stream, page, out);
+        }
+        kind
+    }
+
+    // Put the header packets on a page together, returning the comment list if one was finished
+    fn read_packets(&mut self, serial: u32, page: &[u8]) -> Option<Vec<u8>> {
+        let stream = self.streams.get_mut(&serial)?;
+        let mut position = 27 + page[26] as usize;
+        let mut comments = None;
+
+        for &lacing in lacing(page) {
+            if stream.remaining == 0 {
+                break;
+            }
+            let end = position + lacing as usize;
+            stream.packet.extend_from_slice(&page[position..end]);
+            position = end;
+
+            // A lacing value under 255 ends a packet
+            if lacing < 255 {
+                let packet = std::mem::take(&mut stream.packet);
+                if let Some(found) = stream.mapping.comments(stream.packets.len(), &packet) {
+                    comments = Some(found.to_vec());
+                }
+                stream.packets.push(packet);
+                stream.remaining -= 1;
+            }
+        }
+        comments
+    }
+
+    // End every logical stream, then begin them again under new serial numbers with the new title
+    fn restart(&mut self, title: Option<&str>, out: &mut Vec<u8>) {
+        let mut serials: Vec<u32> = self.streams.keys().copied().collect();
+        serials.sort_unstable();
+
+        for serial in &serials {
+            if let Some(stream) = self.streams.get(serial) {
+                out.extend(build_page(
+                    EOS,
+                    stream.granule,
+                    stream.serial,
+                    stream.sequence,
+                    &[],
+                    &[],
+                ));
+            }
+        }
+
+        self.pages.clear();
+        // Every beginning of stream page comes before any other header page
+        for serial in &serials {
+            if let Some(stream) = self.streams.get_mut(serial) {
+                stream.serial = Uuid::new_v4().as_fields().0;
+                stream.sequence = 0;
+                stream.restarted = true;
+                stream.fresh = true;
+                if !stream.packets.is_empty() {
+                    paginate(
+                        stream.serial,
+                        &mut stream.sequence,
+                        BOS,
+                        &stream.packets[..1],
+                        &mut self.pages,
+                    );
+                }
+            }
+        }
+        for serial in &serials {
+            if let Some(stream) = self.streams.get_mut(serial) {
+                let mapping = stream.mapping;
+                let packets: Vec<Vec<u8>> = stream
+                    .packets
+                    .iter()
+                    .enumerate()
+                    .skip(1)
+                    .map(|(index, packet)| {
+                        mapping
+                            .replace_title(index, packet, title)
+                            .unwrap_or_else(|| packet.clone())
+                    })
+                    .collect();
+                paginate(
+                    stream.serial,
+                    &mut stream.sequence,
+                    0,
+                    &packets,
+                    &mut self.pages,
+                );
+            }
+        }
+        out.extend_from_slice(&self.pages);
+    }
+}
+
+// Write a data page out, under the serial number of the stream it was restarted as
+fn write_page(stream: &mut Stream, page: &[u8], out: &mut Vec<u8>) {
+    let granule = u64::from_le_bytes([
+        page[6], page[7], page[8], page[9], page[10], page[11], page[12], page[13],
+    ]);
+    if granule != NO_GRANULE {
+        stream.granule = granule;
+    }
+    if !stream.restarted {
+        stream.sequence = read_u32(page, 18).wrapping_add(1);
+        out.extend_from_slice(page);
+        return;
+    }
+
+    let mut flags = page[5];
+    let mut lacing = lacing(page);
+    let mut body = body(page);
+    if stream.fresh && flags & CONTINUED != 0 {
+        // The rest of a packet from before the restart can't be decoded
+        match lacing.iter().position(|&value| value < 255) {
+            Some(end) => {
+                let skipped: usize = lacing[..=end].iter().map(|&value| value as usize).sum();
+                lacing = &lacing[end + 1..];
+                body = &body[skipped..];
+                flags &= !CONTINUED;
+            }
+            None => return,
+        }
+    }
+    stream.fresh = false;
+    if lacing.is_empty() && flags & EOS == 0 {
+        return;
+    }
+
+    out.extend(build_page(
+        flags,
+        granule,
+        stream.serial,
+        stream.sequence,
+        lacing,
+        body,
+    ));
+    stream.sequence = stream.sequence.wrapping_add(1);
+}
+
+// Lay packets out over as few pages as possible, the way header pages are usually written
+fn paginate(serial: u32, sequence: &mut u32, flags: u8, packets: &[Vec<u8>], out: &mut Vec<u8>) {
+    let mut lacing = Vec::new();
+    let mut body = Vec::new();
+    let mut continued = false;
+    let mut ended = false;
+
+    for packet in packets {
+        let mut position = 0;
+        let mut values = vec![255u8; packet.len() / 255];
+        values.push((packet.len() % 255) as u8);
+
+        for (index, &value) in values.iter().enumerate() {
+            if lacing.len() == 255 {
+                let granule = if ended { 0 } else { NO_GRANULE };
+                let page_flags = flags | if continued { CONTINUED } else { 0 };
+                out.extend(build_page(
+                    page_flags, granule, serial, *sequence, &lacing, &body,
+                ));
+                *sequence = sequence.wrapping_add(1);
+                lacing.clear();
+                body.clear();
+                continued = index > 0;
+                ended = false;
+            }
+            lacing.push(value);
+            body.extend_from_slice(&packet[position..position + value as usize]);
+            position += value as usize;
+        }
+        ended = true;
+    }
+
+    if !lacing.is_empty() {
+        let page_flags = flags | if continued { CONTINUED } else { 0 };
+        out.extend(build_page(page_flags, 0, serial, *sequence, &lacing, &body));
+        *sequence = sequence.wrapping_add(1);
+    }
+}
+
+fn build_page(
+    flags: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    lacing: &[u8],
+    body: &[u8],
+) -> Vec<u8> {
+    let mut page = Vec::with_capacity(27 + lacing.len() + body.len());
+    page.extend_from_slice(b"OggS");
+    page.push(0);
+    page.push(flags);
+    page.extend_from_slice(&granule.to_le_bytes());
+    page.extend_from_slice(&serial.to_le_bytes());
+    page.extend_from_slice(&sequence.to_le_bytes());
+    // The checksum is calculated with itself set to zero
+    page.extend_from_slice(&[0; 4]);
+    page.push(lacing.len() as u8);
+    page.extend_from_slice(lacing);
+    page.extend_from_slice(body);
+
+    let crc = codec::crc32(0, &page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes([
+        data[position],
+        data[position + 1],
+        data[position + 2],
+        data[position + 3],
+    ])
+}
+
+fn lacing(page: &[u8]) -> &[u8] {
+    &page[27..27 + page[26] as usize]
+}
+
+fn body(page: &[u8]) -> &[u8] {
+    &page[27 + page[26] as usize..]
+}
+
+// Split a comment list into its vendor string and comments
+fn read_comments(comments: &[u8]) -> Option<(&[u8], Vec<&[u8]>)> {
+    let read_length = |position: usize| -> Option<usize> {
+        comments.get(position..position + 4)?;
+        Some(read_u32(comments, position) as usize)
+    };
+
+    let vendor_length = read_length(0)?;
+    let vendor = comments.get(4..4 + vendor_length)?;
+    let mut position = 4 + vendor_length;
+    let count = read_length(position)?;
+    position += 4;
+
+    let mut list = Vec::new();
+    for _ in 0..count {
+        let length = read_length(position)?;
+        position += 4;
+        list.push(comments.get(position..position + length)?);
+        position += length;
+    }
+    Some((vendor, list))
+}
+
+/**
+ * Turn a Vorbis comment list into an "ARTIST - TITLE" title
+ * https://xiph.org/vorbis/doc/v-comment.html
+ */
+fn parse_comments(comments: &[u8]) -> Option<icy::Metadata> {
+    let (_, list) = read_comments(comments)?;
+
+    let mut artist = None;
+    let mut title = None;
+    for comment in list {
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((field, value)) = comment.split_once('=') {
+            if value.is_empty() {
+                continue;
+            }
+            match field.to_uppercase().as_str() {
+                "ARTIST" => artist = Some(value.to_string()),
+                "TITLE" => title = Some(value.to_string()),
+                _ => (),
+            }
+        }
+    }
+
+    let title = match (artist, title) {
+        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+        (None, title) => title,
+        (artist, None) => artist,
+    };
+    title.map(|title| icy::Metadata {
+        title: Some(title),
+        url: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    const SERIAL: u32 = 0x1234;
+
+    struct Parsed {
+        flags: u8,
+        granule: u64,
+        serial: u32,
+        sequence: u32,
+        lacing: Vec<u8>,
+        body: Vec<u8>,
+    }
+
+    // Split pages back up, checking every CRC along the way
+    fn parse(mut data: &[u8]) -> Vec<Parsed> {
+        let mut pages = Vec::new();
+        while !data.is_empty() {
+            assert!(data.starts_with(b"OggS"));
+            let length = 27
+                + lacing(data)
+                    .iter()
+                    .map(|&value| value as usize)
+                    .sum::<usize>()
+                + data[26] as usize;
+            let mut page = data[..length].to_vec();
+            let stored = read_u32(&page, 22);
+            page[22..26].fill(0);
+            assert_eq!(codec::crc32(0, &page), stored);
+
+            pages.push(Parsed {
+                flags: page[5],
+                granule: u64::from_le_bytes(page[6..14].try_into().unwrap()),
+                serial: read_u32(&page, 14),
+                sequence: read_u32(&page, 18),
+                lacing: lacing(&page).to_vec(),
+                body: body(&page).to_vec(),
+            });
+            data = &data[length..];
+        }
+        pages
+    }
+
+    fn packets(pages: &[Parsed]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut packet = Vec::new();
+        for page in pages {
+            let mut position = 0;
+            for &value in &page.lacing {
+                packet.extend_from_slice(&page.body[position..position + value as usize]);
+                position += value as usize;
+                if value < 255 {
+                    packets.push(std::mem::take(&mut packet));
+                }
+            }
+        }
+        packets
+    }
+
+    // A single page holding whole packets
+    fn page(flags: u8, granule: u64, sequence: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        let mut body = Vec::new();
+        for packet in packets {
+            lacing.extend(vec![255; packet.len() / 255]);
+            lacing.push((packet.len() % 255) as u8);
+            body.extend_from_slice(packet);
+        }
+        build_page(flags, granule, SERIAL, sequence, &lacing, &body)
+    }
+
+    fn identification() -> Vec<u8> {
+        let mut packet = b"\x01vorbis".to_vec();
+        packet.resize(30, 0);
+        packet
+    }
+
+    fn comment(comments: &[&str]) -> Vec<u8> {
+        let mut packet = b"\x03vorbis".to_vec();
+        packet.extend_from_slice(&4u32.to_le_bytes());
+        packet.extend_from_slice(b"test");
+        packet.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            packet.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            packet.extend_from_slice(comment.as_bytes());
+        }
+        packet.push(1);
+        packet
+    }
+
+    fn setup() -> Vec<u8> {
+        let mut packet = b"\x05vorbis".to_vec();
+        packet.resize(600, 0xAB);
+        packet
+    }
+
+    // Push the headers of a Vorbis stream, returning the pages that make them up
+    fn push_headers(headers: &mut Headers) -> Vec<u8> {
+        let mut out = Vec::new();
+        let first = page(BOS, 0, 0, &[&identification()]);
+        assert!(matches!(headers.push(&first, &mut out), Page::Chain));
+
+        let second = page(
+            0,
+            0,
+            1,
+            &[
+                &comment(&["ARTIST=Miles", "TITLE=So What", "DATE=1959"]),
+                &setup(),
+            ],
+        );
+        match headers.push(&second, &mut out) {
+            Page::Comments(Some(metadata)) => {
+                assert_eq!(metadata.title.as_deref(), Some("Miles - So What"))
+            }
+            _ => panic!("expected the comment header"),
+        }
+        assert_eq!(out, [first, second].concat());
+        out
+    }
+
+    #[test]
+    fn header_pages_are_kept() {
+        let mut headers = Headers::new();
+        let pages = push_headers(&mut headers);
+        assert_eq!(headers.pages(), &pages[..]);
+
+        // Data pages go through untouched
+        let data = page(0, 1024, 2, &[b"audio"]);
+        let mut out = Vec::new();
+        assert!(matches!(headers.push(&data, &mut out), Page::Data));
+        assert_eq!(out, data);
+        assert_eq!(headers.pages(), &pages[..]);
+
+        // A new link replaces them
+        let chained = page(BOS, 0, 0, &[&identification()]);
+        assert!(matches!(headers.push(&chained, &mut out), Page::Chain));
+        assert_eq!(headers.pages(), &chained[..]);
+    }
+
+    #[test]
+    fn retitle_starts_a_new_link() {
+        let mut headers = Headers::new();
+        push_headers(&mut headers);
+        let mut out = Vec::new();
+        headers.push(&page(0, 1024, 2, &[b"first"]), &mut out);
+
+        headers.retitle(&Some(icy::Metadata {
+            title: Some("New Song".to_string()),
+            url: None,
+        }));
+        let mut out = Vec::new();
+        assert!(matches!(
+            headers.push(&page(0, 2048, 3, &[b"second"]), &mut out),
+            Page::Restart
+        ));
+        let pages = parse(&out);
+
+        // The old stream ends where it left off
+        let eos = &pages[0];
+        assert_eq!(
+            (eos.flags, eos.granule, eos.serial, eos.sequence),
+            (EOS, 1024, SERIAL, 3)
+        );
+        assert!(eos.lacing.is_empty());
+
+        // Then begins again with the beginning of stream page first
+        let serial = pages[1].serial;
+        assert_ne!(serial, SERIAL);
+        assert_eq!((pages[1].flags, pages[1].sequence), (BOS, 0));
+        let header_pages = &pages[1..pages.len() - 1];
+        for (sequence, page) in header_pages.iter().enumerate() {
+            assert_eq!((page.serial, page.sequence), (serial, sequence as u32));
+            assert_eq!(page.flags & EOS, 0);
+            if sequence > 0 {
+                assert_eq!(page.flags & BOS, 0);
+            }
+        }
+        assert_eq!(
+            packets(header_pages),
+            vec![
+                identification(),
+                comment(&["DATE=1959", "TITLE=New Song"]),
+                setup()
+            ]
+        );
+
+        // The cached headers are the new ones, between the empty end of stream page and the data page
+        let data = pages.last().unwrap();
+        let data_length = 27 + data.lacing.len() + data.body.len();
+        assert_eq!(headers.pages(), &out[27..out.len() - data_length]);
+
+        assert_eq!((data.flags, data.granule, data.serial), (0, 2048, serial));
+        assert_eq!(data.sequence, header_pages.len() as u32);
+        assert_eq!(data.body, b"second");
+    }
+
+    #[test]
+    fn restarted_streams_drop_continued_packets() {
+        let mut headers = Headers::new();
+        push_headers(&mut headers);
+        headers.retitle(&None);
+
+        // The first 265 bytes finish a packet from before the restart
+        let mut lacing = vec![255, 10, 20];
+        let mut body = vec![1; 265];
+        body.extend_from_slice(&[2; 20]);
+        let continued = build_page(CONTINUED, 4096, SERIAL, 2, &lacing, &body);
+
+        let mut out = Vec::new();
+        assert!(matches!(headers.push(&continued, &mut out), Page::Restart));
+        let data = parse(&out).pop().unwrap();
+        assert_eq!(data.flags, 0);
+        assert_eq!(data.lacing, vec![20]);
+        assert_eq!(data.body, vec![2; 20]);
+
+        // A page that only continues a packet is dropped entirely
+        lacing = vec![255];
+        let mut out = Vec::new();
+        headers.retitle(&None);
+        headers.push(
+            &build_page(CONTINUED, NO_GRANULE, SERIAL, 3, &lacing, &[1; 255]),
+            &mut out,
+        );
+        assert!(parse(&out).iter().all(|page| page.body != [1; 255]));
+    }
+
+    #[test]
+    fn paginate_laces_long_packets() {
+        // Exactly two lacing values of 255 need a 0 to end the packet
+        let short = vec![7; 510];
+        // Needs more than 255 lacing values, so it spans pages
+        let long: Vec<u8> = (0..255 * 300).map(|i| i as u8).collect();
+        let mut sequence = 5;
+        let mut out = Vec::new();
+        paginate(
+            SERIAL,
+            &mut sequence,
+            0,
+            &[short.clone(), long.clone()],
+            &mut out,
+        );
+
+        let pages = parse(&out);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(sequence, 7);
+        assert_eq!(&pages[0].lacing[..3], &[255, 255, 0]);
+        assert_eq!(pages[0].lacing.len(), 255);
+        // The first page only ends the short packet
+        assert_eq!((pages[0].flags, pages[0].granule), (0, 0));
+        assert_eq!((pages[1].flags, pages[1].sequence), (CONTINUED, 6));
+        assert_eq!(*pages[1].lacing.last().unwrap(), 0);
+        assert_eq!(packets(&pages), vec![short, long]);
+    }
+
+    #[test]
+    fn paginate_marks_pages_without_a_packet_end() {
+        let long = vec![3; 255 * 600];
+        let mut sequence = 0;
+        let mut out = Vec::new();
+        paginate(SERIAL, &mut sequence, 0, std::slice::from_ref(&long), &mut out);
+
+        let pages = parse(&out);
+        assert_eq!(pages.len(), 3);
+        assert_eq!((pages[0].flags, pages[0].granule), (0, NO_GRANULE));
+        assert_eq!((pages[1].flags, pages[1].granule), (CONTINUED, NO_GRANULE));
+        assert_eq!((pages[2].flags, pages[2].granule), (CONTINUED, 0));
+        assert_eq!(packets(&pages), vec![long]);
+    }
+}
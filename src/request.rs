@@ -10,15 +10,19 @@ pub fn extract_queries(url: &str) -> (&str, Option<Vec<Query>>) {
     if let Some((path, last)) = url.split_once("?") {
         let mut queries: Vec<Query> = Vec::new();
         for field in last.split('&') {
+            if field.is_empty() {
+                continue;
+            }
             // decode doesn't treat + as a space
-            if let Some((name, value)) = field.replace("+", " ").split_once('=') {
-                let name = urlencoding::decode(name);
-                let value = urlencoding::decode(value);
+            let field = field.replace("+", " ");
+            // A field without a value, like ?hidden, is taken as empty
+            let (name, value) = field.split_once('=').unwrap_or((&field, ""));
+            let name = urlencoding::decode(name);
+            let value = urlencoding::decode(value);
 
-                if let Ok(field) = name {
-                    if let Ok(value) = value {
-                        queries.push(Query { field, value });
-                    }
+            if let Ok(field) = name {
+                if let Ok(value) = value {
+                    queries.push(Query { field, value });
                 }
             }
         }
@@ -59,14 +63,51 @@ pub fn get_basic_auth(headers: &[httparse::Header]) -> Option<(String, String)>
         let reg =
             Regex::new(r"^Basic ((?:[A-Za-z0-9+/]{4})*(?:[A-Za-z0-9+/]{2}==|[A-Za-z0-9+/]{3}=)?)$")
                 .unwrap();
-        if let Some(capture) = reg.captures(std::str::from_utf8(&auth).unwrap()) {
-            if let Some((name, pass)) = std::str::from_utf8(&base64::decode(&capture[1]).unwrap())
-                .unwrap()
-                .split_once(":")
-            {
-                return Some((String::from(name), String::from(pass)));
-            }
+        // Anything that isn't valid UTF-8 is treated like no credentials at all
+        let capture = reg.captures(std::str::from_utf8(auth).ok()?)?;
+        let decoded = base64::decode(&capture[1]).ok()?;
+        if let Some((name, pass)) = std::str::from_utf8(&decoded).ok()?.split_once(":") {
+            return Some((String::from(name), String::from(pass)));
         }
     }
     None
 }
+
+// A parsed request, borrowing from the buffer it was read into
+pub struct Request<'a> {
+    pub method: &'a str,
+    // Cleaned, and without the query
+    pub path: String,
+    pub raw_query: &'a str,
+    pub queries: Option<Vec<Query>>,
+    pub headers: &'a [httparse::Header<'a>],
+    // The minor version, either HTTP/1.0 or HTTP/1.1
+    pub version: u8,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(req: httparse::Request<'a, 'a>) -> Request<'a> {
+        let full_path = req.path.unwrap_or("/");
+        let (base_path, queries) = extract_queries(full_path);
+        Request {
+            method: req.method.unwrap_or(""),
+            path: path_clean::clean(base_path),
+            raw_query: full_path
+                .split_once('?')
+                .map(|(_, query)| query)
+                .unwrap_or(""),
+            queries,
+            headers: req.headers,
+            version: req.version.unwrap_or(0),
+        }
+    }
+
+    // HTTP/1.1 connections stay open unless asked otherwise, HTTP/1.0 ones have to ask
+    pub fn keep_alive(&self) -> bool {
+        match get_header("Connection", self.headers) {
+            Some(value) if value.eq_ignore_ascii_case(b"close") => false,
+            Some(value) if value.eq_ignore_ascii_case(b"keep-alive") => true,
+            _ => self.version == 1,
+        }
+    }
+}
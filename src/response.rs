@@ -1,85 +1,163 @@
-use tokio::net::TcpStream;
+use tokio::net::{ TcpStream, UnixStream };
 use std::io::ErrorKind;
 use std::error::Error;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
 use std::time::SystemTime;
 use httparse::Status;
 use httpdate::fmt_http_date;
 use tokio_native_tls::TlsStream;
-use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf };
 use crate::icy;
 
+// Either side of a connection, whether we accepted it or opened it ourselves
 pub enum Stream {
     Plain( TcpStream ),
+    Unix( UnixStream ),
     Tls( Box< TlsStream< TcpStream > > )
 }
 
-impl Stream {
-    pub async fn read( &mut self, buf: &mut [ u8 ] ) -> std::io::Result< usize > {
-        match self {
-            Stream::Plain( stream ) => stream.read( buf ).await,
-            Stream::Tls( stream ) => stream.read( buf ).await
+impl AsyncRead for Stream {
+    fn poll_read( self: Pin< &mut Self >, cx: &mut Context< '_ >, buf: &mut ReadBuf< '_ > ) -> Poll< std::io::Result< () > > {
+        match self.get_mut() {
+            Stream::Plain( stream ) => Pin::new( stream ).poll_read( cx, buf ),
+            Stream::Unix( stream ) => Pin::new( stream ).poll_read( cx, buf ),
+            Stream::Tls( stream ) => Pin::new( stream ).poll_read( cx, buf )
         }
     }
+}
 
-    pub async fn write_all( &mut self, buf: &[ u8 ] ) -> std::io::Result< () > {
-        match self {
-            Stream::Plain( stream ) => stream.write_all( buf ).await,
-            Stream::Tls( stream ) => stream.write_all( buf ).await
+impl AsyncWrite for Stream {
+    fn poll_write( self: Pin< &mut Self >, cx: &mut Context< '_ >, buf: &[ u8 ] ) -> Poll< std::io::Result< usize > > {
+        match self.get_mut() {
+            Stream::Plain( stream ) => Pin::new( stream ).poll_write( cx, buf ),
+            Stream::Unix( stream ) => Pin::new( stream ).poll_write( cx, buf ),
+            Stream::Tls( stream ) => Pin::new( stream ).poll_write( cx, buf )
         }
     }
-}
 
-pub async fn send_bad_request( stream: &mut TcpStream, id: &str, message: Option< ( &str, &str ) > ) -> Result< (), Box< dyn Error > > {
-    stream.write_all( b"HTTP/1.0 400 Bad Request\r\n" ).await?;
-    stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
-    stream.write_all( b"Connection: Close\r\n" ).await?;
-    if let Some( ( content_type, text ) ) = message {
-        stream.write_all( ( format!( "Content-Type: {}\r\n", content_type ) ).as_bytes() ).await?;
-        stream.write_all( ( format!( "Content-Length: {}\r\n", text.len() ) ).as_bytes() ).await?;
+    fn poll_flush( self: Pin< &mut Self >, cx: &mut Context< '_ > ) -> Poll< std::io::Result< () > > {
+        match self.get_mut() {
+            Stream::Plain( stream ) => Pin::new( stream ).poll_flush( cx ),
+            Stream::Unix( stream ) => Pin::new( stream ).poll_flush( cx ),
+            Stream::Tls( stream ) => Pin::new( stream ).poll_flush( cx )
+        }
     }
-    server_info(stream).await?;
-    if let Some( ( _, text ) ) = message {
-        stream.write_all( text.as_bytes() ).await?;
+
+    fn poll_shutdown( self: Pin< &mut Self >, cx: &mut Context< '_ > ) -> Poll< std::io::Result< () > > {
+        match self.get_mut() {
+            Stream::Plain( stream ) => Pin::new( stream ).poll_shutdown( cx ),
+            Stream::Unix( stream ) => Pin::new( stream ).poll_shutdown( cx ),
+            Stream::Tls( stream ) => Pin::new( stream ).poll_shutdown( cx )
+        }
     }
+}
 
-    Ok( () )
+// A complete response that doesn't take over the connection, so it can be kept alive afterwards
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec< String >,
+    pub body: Option< ( String, Vec< u8 > ) >
 }
 
+impl Response {
+    pub fn new( status: &'static str, message: Option< ( &str, &str ) > ) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: message.map( | ( content_type, text ) | ( content_type.to_string(), text.as_bytes().to_vec() ) )
+        }
+    }
 
-pub async fn send_forbidden( stream: &mut TcpStream, id: &str, message: Option< ( &str, &str ) > ) -> Result< (), Box< dyn Error > > {
-    stream.write_all( b"HTTP/1.0 403 Forbidden\r\n" ).await?;
-    stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
-    stream.write_all( b"Connection: Close\r\n" ).await?;
-    if let Some( ( content_type, text ) ) = message {
-        stream.write_all( ( format!( "Content-Type: {}\r\n", content_type ) ).as_bytes() ).await?;
-        stream.write_all( ( format!( "Content-Length: {}\r\n", text.len() ) ).as_bytes() ).await?;
+    pub fn ok( message: Option< ( &str, &str ) > ) -> Response {
+        Response::new( "200 OK", message )
     }
-    server_info(stream).await?;
-    if let Some( ( _, text ) ) = message {
-        stream.write_all( text.as_bytes() ).await?;
+
+    pub fn json( value: &serde_json::Value ) -> Response {
+        Response::ok( Some( ( "application/json; charset=utf-8", &value.to_string() ) ) )
     }
 
-    Ok( () )
+    pub fn bad_request( message: Option< ( &str, &str ) > ) -> Response {
+        Response::new( "400 Bad Request", message )
+    }
+
+    pub fn unauthorized( message: Option< ( &str, &str ) > ) -> Response {
+        let mut response = Response::new( "401 Authorization Required", message );
+        response.headers.push( "WWW-Authenticate: Basic realm=\"Icy Server\"".to_string() );
+        response
+    }
+
+    pub fn forbidden( message: Option< ( &str, &str ) > ) -> Response {
+        Response::new( "403 Forbidden", message )
+    }
+
+    pub fn not_found() -> Response {
+        Response::new( "404 File Not Found", Some( ( "text/html; charset=utf-8", "<html><head><title>Error 404</title></head><body><b>404 - The file you requested could not be found</b></body></html>" ) ) )
+    }
+
+    pub fn method_not_allowed() -> Response {
+        let mut response = Response::new( "405 Method Not Allowed", None );
+        response.headers.push( format!( "Allow: {}", ALLOWED_METHODS ) );
+        response
+    }
+
+    pub fn internal_error( message: Option< ( &str, &str ) > ) -> Response {
+        Response::new( "500 Internal Server Error", message )
+    }
+
+    pub fn options() -> Response {
+        let mut response = Response::new( "204 No Content", None );
+        response.headers.push( format!( "Allow: {}", ALLOWED_METHODS ) );
+        response.headers.push( "Access-Control-Allow-Methods: GET, HEAD, OPTIONS".to_string() );
+        response.headers.push( "Access-Control-Allow-Headers: Authorization, Icy-MetaData".to_string() );
+        response
+    }
 }
 
-pub async fn send_unauthorized( stream: &mut TcpStream, id: &str, message: Option< ( &str, &str ) > ) -> Result< (), Box< dyn Error > > {
-    stream.write_all( b"HTTP/1.0 401 Authorization Required\r\n" ).await?;
-    stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
-    stream.write_all( b"Connection: Close\r\n" ).await?;
-    if let Some( ( content_type, text ) ) = message {
-        stream.write_all( ( format!( "Content-Type: {}\r\n", content_type ) ).as_bytes() ).await?;
-        stream.write_all( ( format!( "Content-Length: {}\r\n", text.len() ) ).as_bytes() ).await?;
+pub const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS, PUT, SOURCE";
+
+/**
+ * Write a response with a Content-Length, leaving out the body for HEAD requests
+ */
+pub async fn send_response( stream: &mut Stream, id: &str, response: &Response, version: u8, keep_alive: bool, head: bool ) -> Result< (), Box< dyn Error > > {
+    // Build it all at once, since keep alive connections shouldn't see partial responses
+    let mut buf = Vec::new();
+    buf.extend_from_slice( format!( "HTTP/1.{} {}\r\n", version, response.status ).as_bytes() );
+    buf.extend_from_slice( format!( "Server: {}\r\n", id ).as_bytes() );
+    if keep_alive {
+        buf.extend_from_slice( b"Connection: keep-alive\r\n" );
+    } else {
+        buf.extend_from_slice( b"Connection: Close\r\n" );
     }
-    stream.write_all( b"WWW-Authenticate: Basic realm=\"Icy Server\"\r\n" ).await?;
-    server_info(stream).await?;
-    if let Some( ( _, text ) ) = message {
-        stream.write_all( text.as_bytes() ).await?;
+    for header in &response.headers {
+        buf.extend_from_slice( header.as_bytes() );
+        buf.extend_from_slice( b"\r\n" );
+    }
+    match &response.body {
+        Some( ( content_type, body ) ) => {
+            buf.extend_from_slice( format!( "Content-Type: {}\r\n", content_type ).as_bytes() );
+            buf.extend_from_slice( format!( "Content-Length: {}\r\n", body.len() ).as_bytes() );
+        }
+        // 204 responses can't carry a body at all
+        None if response.status.starts_with( "204" ) => (),
+        None => buf.extend_from_slice( b"Content-Length: 0\r\n" )
+    }
+    buf.extend_from_slice( format!( "Date: {}\r\n", fmt_http_date( SystemTime::now() ) ).as_bytes() );
+    buf.extend_from_slice( b"Cache-Control: no-cache, no-store\r\n" );
+    buf.extend_from_slice( b"Expires: Mon, 26 Jul 1997 05:00:00 GMT\r\n" );
+    buf.extend_from_slice( b"Pragma: no-cache\r\n" );
+    buf.extend_from_slice( b"Access-Control-Allow-Origin: *\r\n\r\n" );
+    if let Some( ( _, body ) ) = &response.body {
+        if !head {
+            buf.extend_from_slice( body );
+        }
     }
+    stream.write_all( &buf ).await?;
 
     Ok( () )
 }
 
-pub async fn server_info( stream: &mut TcpStream )  -> Result< (), Box< dyn Error > > {
+pub async fn server_info( stream: &mut Stream )  -> Result< (), Box< dyn Error > > {
     stream.write_all( ( format!( "Date: {}\r\n", fmt_http_date( SystemTime::now() ) ) ).as_bytes() ).await?;
     stream.write_all( b"Cache-Control: no-cache, no-store\r\n" ).await?;
     stream.write_all( b"Expires: Mon, 26 Jul 1997 05:00:00 GMT\r\n" ).await?;
@@ -89,7 +167,7 @@ pub async fn server_info( stream: &mut TcpStream )  -> Result< (), Box< dyn Erro
     Ok( () )
 }
 
-pub async fn send_listener_ok( stream: &mut TcpStream, id: &str, properties: &icy::Properties, meta_enabled: bool, metaint: usize ) -> Result< (), Box< dyn Error > > {
+pub async fn send_listener_ok( stream: &mut Stream, id: &str, properties: &icy::Properties, meta_enabled: bool, metaint: usize ) -> Result< (), Box< dyn Error > > {
     stream.write_all( b"HTTP/1.0 200 OK\r\n" ).await?;
     stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
     stream.write_all( b"Connection: Close\r\n" ).await?;
@@ -118,27 +196,7 @@ pub async fn send_listener_ok( stream: &mut TcpStream, id: &str, properties: &ic
     Ok( () )
 }
 
-pub async fn send_not_found( stream: &mut TcpStream, id: &str, message: Option< ( &str, &str ) > ) -> Result< (), Box< dyn Error > > {
-    stream.write_all( b"HTTP/1.0 404 File Not Found\r\n" ).await?;
-    stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
-    stream.write_all( b"Connection: Close\r\n" ).await?;
-    if let Some( ( content_type, text ) ) = message {
-        stream.write_all( ( format!( "Content-Type: {}\r\n", content_type ) ).as_bytes() ).await?;
-        stream.write_all( ( format!( "Content-Length: {}\r\n", text.len() ) ).as_bytes() ).await?;
-    }
-    stream.write_all( ( format!( "Date: {}\r\n", fmt_http_date( SystemTime::now() ) ) ).as_bytes() ).await?;
-    stream.write_all( b"Cache-Control: no-cache, no-store\r\n" ).await?;
-    stream.write_all( b"Expires: Mon, 26 Jul 1997 05:00:00 GMT\r\n" ).await?;
-    stream.write_all( b"Pragma: no-cache\r\n" ).await?;
-    stream.write_all( b"Access-Control-Allow-Origin: *\r\n\r\n" ).await?;
-    if let Some( ( _, text ) ) = message {
-        stream.write_all( text.as_bytes() ).await?;
-    }
-
-    Ok( () )
-}
-
-pub async fn send_ok( stream: &mut TcpStream, id: &str, message: Option< ( &str, &str ) > ) -> Result< (), Box< dyn Error > > {
+pub async fn send_ok( stream: &mut Stream, id: &str, message: Option< ( &str, &str ) > ) -> Result< (), Box< dyn Error > > {
     stream.write_all( b"HTTP/1.0 200 OK\r\n" ).await?;
     stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
     stream.write_all( b"Connection: Close\r\n" ).await?;
@@ -154,7 +212,7 @@ pub async fn send_ok( stream: &mut TcpStream, id: &str, message: Option< ( &str,
     Ok( () )
 }
 
-pub async fn send_continue( stream: &mut TcpStream, id: &str ) -> Result< (), Box< dyn Error > > {
+pub async fn send_continue( stream: &mut Stream, id: &str ) -> Result< (), Box< dyn Error > > {
     stream.write_all( b"HTTP/1.0 200 OK\r\n" ).await?;
     stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
     stream.write_all( b"Connection: Close\r\n" ).await?;
@@ -162,22 +220,6 @@ pub async fn send_continue( stream: &mut TcpStream, id: &str ) -> Result< (), Bo
     Ok( () )
 }
 
-pub async fn send_internal_error( stream: &mut TcpStream, id: &str, message: Option< ( &str, &str ) > ) -> Result< (), Box< dyn Error > > {
-    stream.write_all( b"HTTP/1.0 500 Internal Server Error\r\n" ).await?;
-    stream.write_all( ( format!( "Server: {}\r\n", id ) ).as_bytes() ).await?;
-    stream.write_all( b"Connection: Close\r\n" ).await?;
-    if let Some( ( content_type, text ) ) = message {
-        stream.write_all( ( format!( "Content-Type: {}\r\n", content_type ) ).as_bytes() ).await?;
-        stream.write_all( ( format!( "Content-Length: {}\r\n", text.len() ) ).as_bytes() ).await?;
-    }
-    server_info(stream).await?;
-    if let Some( ( _, text ) ) = message {
-        stream.write_all( text.as_bytes() ).await?;
-    }
-
-    Ok( () )
-}
-
 pub async fn read_http_response( stream: &mut Stream, buffer: &mut Vec< u8 >, max_len: usize ) -> Result< usize, Box< dyn Error > > {
     let mut buf = [ 0; 1024 ];
     loop {
@@ -185,16 +227,16 @@ pub async fn read_http_response( stream: &mut Stream, buffer: &mut Vec< u8 >, ma
         let mut res = httparse::Response::new( &mut headers );
         let read = stream.read( &mut buf ).await?;
         buffer.extend_from_slice( &buf[ .. read ] );
-        match res.parse( &buffer ) {
+        match res.parse( buffer ) {
             Ok( Status::Complete( offset ) ) => return Ok( offset ),
-            Ok( Status::Partial ) if buffer.len() > max_len => return Err( Box::new( std::io::Error::new( ErrorKind::Other, "Request exceeded the maximum allowed length" ) ) ),
+            Ok( Status::Partial ) if buffer.len() > max_len => return Err( Box::new( std::io::Error::other( "Request exceeded the maximum allowed length" ) ) ),
             Ok( Status::Partial ) => (),
             Err( e ) => return Err( Box::new( std::io::Error::new( ErrorKind::InvalidData, format!( "Received an invalid request: {}", e ) ) ) )
         }
     }
 }
 
-pub async fn write_to_client( stream: &mut TcpStream, sent_count: &mut usize, metalen: usize, data: &[ u8 ], metadata: &[ u8 ] ) -> Result< (), std::io::Error > {
+pub async fn write_to_client( stream: &mut Stream, sent_count: &mut usize, metalen: usize, data: &[ u8 ], metadata: &[ u8 ] ) -> Result< (), std::io::Error > {
     let new_sent = *sent_count + data.len();
     // Check if we need to send the metadata
     if new_sent > metalen {
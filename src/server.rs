@@ -1,9 +1,17 @@
 use crate::client;
+use crate::events;
+use crate::hls;
+use crate::logging;
 use crate::source;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use uuid::Uuid;
 
 pub struct Server {
@@ -14,19 +22,131 @@ pub struct Server {
     pub relay_count: usize,
     pub properties: Properties,
     pub stats: Stats,
+    // Subscribers to /api/events listen on this
+    pub events: broadcast::Sender<events::Event>,
+    // Where the config is read from again when reloading
+    pub config_location: String,
+    // Mounts last listed by the master server
+    pub master_mounts: Vec<String>,
+    // Mounts the master server listed as hidden, so they stay hidden here too
+    pub hidden_master_mounts: HashSet<String>,
+    // On demand relays that are connecting or connected, so only the first listener starts one
+    pub on_demand_running: HashSet<String>,
+    // Set once a shutdown has started, so nothing new is mounted
+    pub shutting_down: bool,
 }
 
 impl Server {
     pub fn new(properties: Properties) -> Server {
         Server {
+            events: broadcast::channel(events::CAPACITY).0,
             sources: HashMap::new(),
             clients: HashMap::new(),
             source_count: 0,
             relay_count: 0,
             properties,
             stats: Stats::new(),
+            config_location: String::new(),
+            master_mounts: Vec::new(),
+            hidden_master_mounts: HashSet::new(),
+            on_demand_running: HashSet::new(),
+            shutting_down: false,
         }
     }
+
+    /**
+     * Every relay from the config and the master server, connected or not, along with the relay limit it's held to
+     */
+    pub fn relays(&self) -> Vec<(Relay, Option<usize>)> {
+        let mut relays: Vec<(Relay, Option<usize>)> = self
+            .properties
+            .relays
+            .iter()
+            .map(|relay| (relay.clone(), None))
+            .collect();
+        let master = &self.properties.master_server;
+        if master.enabled {
+            for mount in &self.master_mounts {
+                // Configured relays take precedence over the master
+                if !relays.iter().any(|(relay, _)| &relay.mount == mount) {
+                    relays.push((Relay::from_master(master, mount), Some(master.relay_limit)));
+                }
+            }
+        }
+        relays
+    }
+
+    /**
+     * Every relay that's only connected while it has listeners
+     */
+    pub fn on_demand_relays(&self) -> Vec<(Relay, Option<usize>)> {
+        self.relays()
+            .into_iter()
+            .filter(|(relay, _)| relay.on_demand)
+            .collect()
+    }
+
+    pub fn on_demand_relay(&self, mount: &str) -> Option<(Relay, Option<usize>)> {
+        self.on_demand_relays()
+            .into_iter()
+            .find(|(relay, _)| relay.mount == mount)
+    }
+
+    // Whether the mountpoint is left out of the listings, sources can also ask for it themselves
+    pub fn hidden(&self, mount: &str) -> bool {
+        self.properties
+            .mounts
+            .get(mount)
+            .is_some_and(|mount| mount.hidden)
+            || self.hidden_master_mounts.contains(mount)
+    }
+
+    pub fn relayable(&self, mount: &str) -> bool {
+        self.properties
+            .mounts
+            .get(mount)
+            .is_none_or(|mount| mount.relayable)
+    }
+}
+
+/**
+ * Read the config again and swap it in, leaving the running server untouched if it's invalid
+ * Connected sources get the new limits, settings that only apply on startup keep their running values
+ * Returns the names of the settings that changed but need a restart
+ */
+pub async fn reload(server: &Arc<RwLock<Server>>) -> Result<Vec<&'static str>, String> {
+    let location = server.read().await.config_location.clone();
+    let contents = std::fs::read_to_string(&location)
+        .map_err(|e| format!("Unable to read the config at {}: {}", location, e))?;
+    let mut properties: Properties = serde_json::from_str(&contents)
+        .map_err(|e| format!("Unable to parse the config at {}: {}", location, e))?;
+    properties.validate()?;
+
+    let restart = properties.keep_startup_settings(&server.read().await.properties);
+    // Done last, since it can't be undone, but without the lock as it opens files and sockets
+    logging::init(&properties.logging).map_err(|e| format!("Unable to open the logs: {}", e))?;
+
+    let mut serv = server.write().await;
+    let limits: Vec<_> = serv
+        .sources
+        .iter()
+        .map(|(mount, source)| (source.clone(), properties.source_limits(mount)))
+        .collect();
+    serv.properties = properties;
+    drop(serv);
+    // Sources are only locked once the server isn't, as unmounting locks a source before the server
+    for (source, limits) in limits {
+        source.write().await.limits = limits;
+    }
+
+    log_info!("Reloaded the config from {}", location);
+    if !restart.is_empty() {
+        log_warn!(
+            "These settings only apply after a restart: {}",
+            restart.join(", ")
+        );
+    }
+    Ok(restart)
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -35,6 +155,7 @@ pub struct Stats {
     pub peak_listeners: usize,
     pub session_bytes_sent: usize,
     pub session_bytes_read: usize,
+    pub session_listeners_dropped: usize,
 }
 
 impl Stats {
@@ -44,17 +165,22 @@ impl Stats {
             peak_listeners: 0,
             session_bytes_sent: 0,
             session_bytes_read: 0,
+            session_listeners_dropped: 0,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Properties {
-    // Ideally, there would be multiple addresses and ports and TLS support
+    // Only used if no listen sockets are configured
     #[serde(default = "default_property_address")]
     pub address: String,
     #[serde(default = "default_property_port")]
     pub port: u16,
+    #[serde(default = "default_property_listen_sockets")]
+    pub listen_sockets: Vec<ListenSocket>,
+    #[serde(default = "default_property_tls")]
+    pub tls: Tls,
     #[serde(default = "default_property_metaint")]
     pub metaint: usize,
     #[serde(default = "default_property_server_id")]
@@ -67,12 +193,29 @@ pub struct Properties {
     pub location: String,
     #[serde(default = "default_property_description")]
     pub description: String,
+    // Every mountpoint has to be under this path, like /stream for /stream/radio
+    #[serde(default = "default_property_mount_prefix")]
+    pub mount_prefix: String,
     #[serde(default = "default_property_limits")]
     pub limits: Limits,
     #[serde(default = "default_property_users")]
     pub users: Vec<Credential>,
     #[serde(default = "default_property_master_server")]
     pub master_server: Master,
+    #[serde(default = "default_property_relays")]
+    pub relays: Vec<Relay>,
+    #[serde(default = "default_property_mounts")]
+    pub mounts: HashMap<String, Mount>,
+    #[serde(default = "default_property_listener_auth")]
+    pub listener_auth: ListenerAuth,
+    #[serde(default = "default_property_shoutcast")]
+    pub shoutcast: Shoutcast,
+    #[serde(default = "default_property_hls")]
+    pub hls: Hls,
+    #[serde(default = "default_property_metrics")]
+    pub metrics: Metrics,
+    #[serde(default = "default_property_logging")]
+    pub logging: Logging,
 }
 
 impl Properties {
@@ -80,24 +223,380 @@ impl Properties {
         Properties {
             address: default_property_address(),
             port: default_property_port(),
+            listen_sockets: default_property_listen_sockets(),
+            tls: default_property_tls(),
             metaint: default_property_metaint(),
             server_id: default_property_server_id(),
             admin: default_property_admin(),
             host: default_property_host(),
             location: default_property_location(),
             description: default_property_description(),
+            mount_prefix: default_property_mount_prefix(),
             limits: default_property_limits(),
             users: default_property_users(),
             master_server: default_property_master_server(),
+            relays: default_property_relays(),
+            mounts: default_property_mounts(),
+            listener_auth: default_property_listener_auth(),
+            shoutcast: default_property_shoutcast(),
+            hls: default_property_hls(),
+            metrics: default_property_metrics(),
+            logging: default_property_logging(),
+        }
+    }
+
+    // Problems that would keep the server from working, checked on startup and before reloading
+    pub fn validate(&self) -> Result<(), String> {
+        if self.users.is_empty() {
+            return Err("At least one user must be configured in the config!".to_string());
+        }
+        if self.metaint == 0 {
+            return Err("The metaint must be greater than 0".to_string());
+        }
+        if !self.mount_prefix.starts_with('/') {
+            return Err(format!("Invalid mount prefix: {}", self.mount_prefix));
+        }
+        if self.master_server.enabled {
+            if let Err(e) = url::Url::parse(&self.master_server.url) {
+                return Err(format!("Invalid master server URL: {}", e));
+            }
+        }
+        for (i, relay) in self.relays.iter().enumerate() {
+            for url in relay.urls() {
+                if let Err(e) = url::Url::parse(url) {
+                    return Err(format!(
+                        "Invalid URL for the relay on {}: {}",
+                        relay.mount, e
+                    ));
+                }
+            }
+            self.validate_mount(&relay.mount)?;
+            if self.relays[..i]
+                .iter()
+                .any(|other| other.mount == relay.mount)
+            {
+                return Err(format!("More than one relay is mounted on {}", relay.mount));
+            }
+        }
+        if self.shoutcast.enabled {
+            for mount in
+                std::iter::once(&self.shoutcast.mount).chain(self.shoutcast.streams.values())
+            {
+                self.validate_mount(mount)?;
+            }
+        }
+        for (mount, settings) in &self.mounts {
+            for target in &settings.push {
+                let parsed = url::Url::parse(&target.url);
+                if !parsed.is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https") {
+                    return Err(format!(
+                        "Invalid URL for a push target of {}: {}",
+                        mount, target.url
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Check that a source or relay can be mounted on a path, without its trailing '/'
+     * Mountpoints can be nested, but have to be under the mount prefix and can't shadow the server's own endpoints
+     */
+    pub fn validate_mount(&self, mount: &str) -> Result<(), String> {
+        let invalid = |reason: &str| Err(format!("Invalid mountpoint {}: {}", mount, reason));
+
+        if !mount.starts_with('/') || mount == "/" {
+            return invalid("it needs to start with '/' and have a name");
+        }
+        if mount
+            .split('/')
+            .skip(1)
+            .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+        {
+            return invalid("it can't have empty, '.' or '..' segments");
+        }
+        if RESERVED_PATHS
+            .iter()
+            .any(|reserved| mount == *reserved || mount.starts_with(&format!("{}/", reserved)))
+        {
+            return invalid("it's reserved for the server");
+        }
+        if mount.rsplit('/').next().is_some_and(hls::is_rendition_file) {
+            return invalid("it's the name of an HLS playlist or segment");
+        }
+        let prefix = self.mount_prefix.trim_end_matches('/');
+        if !mount.starts_with(&format!("{}/", prefix)) {
+            return invalid(&format!("it has to be under {}", self.mount_prefix));
+        }
+        Ok(())
+    }
+
+    /**
+     * Use the running values for settings that are only read on startup, like the sockets to bind to
+     * Returns the names of the ones that were changed
+     */
+    fn keep_startup_settings(&mut self, running: &Properties) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        keep(&mut changed, "address", &mut self.address, &running.address);
+        keep(&mut changed, "port", &mut self.port, &running.port);
+        keep(
+            &mut changed,
+            "listen_sockets",
+            &mut self.listen_sockets,
+            &running.listen_sockets,
+        );
+        keep(&mut changed, "tls", &mut self.tls, &running.tls);
+        keep(
+            &mut changed,
+            "shoutcast.enabled",
+            &mut self.shoutcast.enabled,
+            &running.shoutcast.enabled,
+        );
+        keep(
+            &mut changed,
+            "shoutcast.port",
+            &mut self.shoutcast.port,
+            &running.shoutcast.port,
+        );
+        changed
+    }
+
+    // The configured listen sockets, or ones made from the legacy address, port and tls settings
+    pub fn listen_sockets(&self) -> Vec<ListenSocket> {
+        if !self.listen_sockets.is_empty() {
+            return self.listen_sockets.clone();
+        }
+
+        let mut sockets = vec![ListenSocket {
+            address: format_address(&self.address, self.port),
+            tls: false,
+            roles: default_property_listen_sockets_roles(),
+        }];
+        if self.tls.enabled {
+            sockets.push(ListenSocket {
+                address: format_address(&self.address, self.tls.port),
+                tls: true,
+                roles: default_property_listen_sockets_roles(),
+            });
         }
+        sockets
+    }
+
+    // The limits for a source on the given mountpoint, mountpoints without their own use the defaults
+    pub fn source_limits(&self, mount: &str) -> source::ActiveLimits {
+        let limits = &self.limits;
+        match limits.source_limits.get(mount) {
+            Some(limit) => source::ActiveLimits {
+                queue_size: limits.queue_size,
+                burst_size: limit.burst_size,
+                source_timeout: limit.source_timeout,
+            },
+            None => source::ActiveLimits {
+                queue_size: limits.queue_size,
+                burst_size: limits.burst_size,
+                source_timeout: limits.source_timeout,
+            },
+        }
+    }
+
+    // SHOUTcast encoders expect the port after the first TCP listen socket, unless one is configured
+    pub fn shoutcast_address(&self) -> Option<SocketAddr> {
+        let mut address = self
+            .listen_sockets()
+            .iter()
+            .find_map(|socket| socket.address.parse::<SocketAddr>().ok())?;
+        address.set_port(match self.shoutcast.port {
+            Some(port) => port,
+            None => address.port().checked_add(1)?,
+        });
+        Some(address)
+    }
+}
+
+fn format_address(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        // IPv6 addresses need brackets
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
     }
 }
 
-// TODO Add permissions, specifically source, admin, bypass client count, etc
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListenSocket {
+    // Either ip:port, such as 0.0.0.0:8000 or [::]:8000, or unix:/path/to/socket
+    pub address: String,
+    // Uses the certificate and key of the tls section, not supported for unix sockets
+    #[serde(default = "default_property_listen_sockets_tls")]
+    pub tls: bool,
+    #[serde(default = "default_property_listen_sockets_roles")]
+    pub roles: Vec<SocketRole>,
+}
+
+impl ListenSocket {
+    pub fn unix_path(&self) -> Option<&str> {
+        self.address.strip_prefix("unix:")
+    }
+
+    pub fn allows(&self, role: SocketRole) -> bool {
+        self.roles.contains(&role)
+    }
+}
+
+// What requests a listen socket will serve
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketRole {
+    // Listening to mountpoints
+    Listener,
+    // SOURCE and PUT requests
+    Source,
+    // Everything under /admin
+    Admin,
+    // Everything under /api
+    Api,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Credential {
     pub username: String,
     pub password: String,
+    // Users without any roles listed keep full access, unless they have per mount grants
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<Role>>,
+    // Permissions that only apply to a specific mountpoint
+    #[serde(default = "default_property_users_mounts")]
+    pub mounts: HashMap<String, Vec<Permission>>,
+}
+
+impl Credential {
+    // Passwords are either a PHC string produced by hash_password or legacy plaintext
+    pub fn is_hashed(&self) -> bool {
+        self.password.starts_with("$argon2")
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        if self.is_hashed() {
+            match PasswordHash::new(&self.password) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(_) => false,
+            }
+        } else {
+            constant_time_eq(self.password.as_bytes(), password.as_bytes())
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission, mount: Option<&str>) -> bool {
+        let global = match &self.roles {
+            Some(roles) => roles
+                .iter()
+                .any(|role| role.permissions().contains(&permission)),
+            None => self.mounts.is_empty(),
+        };
+
+        global
+            || mount
+                .and_then(|mount| self.mounts.get(mount))
+                .is_some_and(|grants| grants.contains(&permission))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // Can do everything
+    Admin,
+    // Can mount sources anywhere and update their metadata
+    Source,
+    // Can only view the admin listings
+    Stats,
+    // Can list the mounts to relay, for slave servers
+    Relay,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Admin => &[
+                Permission::Source,
+                Permission::Metadata,
+                Permission::Fallbacks,
+                Permission::ListClients,
+                Permission::MoveClients,
+                Permission::KillClient,
+                Permission::KillSource,
+                Permission::ListMounts,
+                Permission::Metrics,
+                Permission::Reload,
+                Permission::Relay,
+                Permission::Hidden,
+            ],
+            Role::Source => &[Permission::Source, Permission::Metadata],
+            Role::Stats => &[
+                Permission::ListClients,
+                Permission::ListMounts,
+                Permission::Metrics,
+            ],
+            Role::Relay => &[Permission::Relay],
+        }
+    }
+}
+
+// Named after the request or admin function they allow
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Source,
+    Metadata,
+    Fallbacks,
+    ListClients,
+    MoveClients,
+    KillClient,
+    KillSource,
+    ListMounts,
+    Metrics,
+    Reload,
+    Relay,
+    // Seeing hidden mounts in the listings
+    Hidden,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Source => "source",
+            Permission::Metadata => "metadata",
+            Permission::Fallbacks => "fallbacks",
+            Permission::ListClients => "listclients",
+            Permission::MoveClients => "moveclients",
+            Permission::KillClient => "killclient",
+            Permission::KillSource => "killsource",
+            Permission::ListMounts => "listmounts",
+            Permission::Metrics => "metrics",
+            Permission::Reload => "reload",
+            Permission::Relay => "relay",
+            Permission::Hidden => "hidden",
+        })
+    }
+}
+
+// The enabled and port fields add a second TLS socket when no listen sockets are configured
+// The certificate and key are used by every listen socket with TLS
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tls {
+    #[serde(default = "default_property_tls_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_property_tls_port")]
+    pub port: u16,
+    // PEM encoded certificate chain
+    #[serde(default = "default_property_tls_certificate")]
+    pub certificate: String,
+    // PEM encoded PKCS #8 private key
+    #[serde(default = "default_property_tls_key")]
+    pub key: String,
 }
 
 // Add a total source limit
@@ -123,12 +622,12 @@ pub struct Limits {
     pub http_max_redirects: usize,
     #[serde(default = "default_property_limits_source_limits")]
     pub source_limits: HashMap<String, source::Limits>,
+    #[serde(default = "default_property_limits_shutdown_timeout")]
+    pub shutdown_timeout: u64,
 }
 
-// TODO Add a list of "relay" structs
-// Relay structs should have an auth of their own, if provided
-// Having a master server is not very good
-// It would be much better to add relays through an api or something
+// Mirrors every mount of another server
+// TODO It would be much better to add relays through an api or something
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Master {
     #[serde(default = "default_property_master_server_enabled")]
@@ -139,6 +638,231 @@ pub struct Master {
     pub update_interval: u64,
     #[serde(default = "default_property_master_server_relay_limit")]
     pub relay_limit: usize,
+    // Only connect to a mount while it has listeners
+    #[serde(default = "default_property_master_server_on_demand")]
+    pub on_demand: bool,
+    #[serde(default = "default_property_master_server_idle_timeout")]
+    pub idle_timeout: u64,
+    // A user with the relay permission on the master, for listing its mounts and relaying them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+// A single stream relayed from another server, which is reconnected whenever it drops
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Relay {
+    pub url: String,
+    // Upstreams to switch to in order, whenever the one in use fails
+    #[serde(default = "default_property_relays_backup_urls")]
+    pub backup_urls: Vec<String>,
+    // Where the stream is mounted on this server
+    pub mount: String,
+    // Basic auth for the upstream, if it needs any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default = "default_property_relays_icy_metadata")]
+    pub icy_metadata: bool,
+    // Seconds to wait before reconnecting, doubled after every failed attempt
+    #[serde(default = "default_property_relays_retry_interval")]
+    pub retry_interval: u64,
+    // Only connect while the mount has listeners
+    #[serde(default = "default_property_relays_on_demand")]
+    pub on_demand: bool,
+    // Seconds an on demand relay stays connected without any listeners
+    #[serde(default = "default_property_relays_idle_timeout")]
+    pub idle_timeout: u64,
+    // Seconds without any stream data before switching to the next upstream, 0 to never switch
+    #[serde(default = "default_property_relays_silence_timeout")]
+    pub silence_timeout: u64,
+}
+
+impl Relay {
+    // Relays made on the fly for the mounts of a master server
+    pub fn from_master(master: &Master, mount: &str) -> Relay {
+        Relay {
+            url: format!("{}{}", master.url, mount),
+            backup_urls: default_property_relays_backup_urls(),
+            mount: mount.to_string(),
+            username: master.username.clone(),
+            password: master.password.clone(),
+            icy_metadata: default_property_relays_icy_metadata(),
+            retry_interval: default_property_relays_retry_interval(),
+            on_demand: master.on_demand,
+            idle_timeout: master.idle_timeout,
+            silence_timeout: default_property_relays_silence_timeout(),
+        }
+    }
+
+    // The primary upstream followed by the backups, in the order they're tried
+    pub fn urls(&self) -> Vec<&str> {
+        std::iter::once(&self.url)
+            .chain(&self.backup_urls)
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+// Settings for a single mountpoint, whatever source is on it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Mount {
+    // Left out of the mount listings, but still served
+    #[serde(default = "default_property_mounts_hidden")]
+    pub hidden: bool,
+    // Listed to slaves in /admin/streamlist
+    #[serde(default = "default_property_mounts_relayable")]
+    pub relayable: bool,
+    #[serde(default = "default_property_mounts_push")]
+    pub push: Vec<PushTarget>,
+}
+
+// Another server that every source on a mount is forwarded to, as a source of its own
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct PushTarget {
+    // The mount on the other server, like http://cdn.example.com:8000/live
+    pub url: String,
+    #[serde(default = "default_property_mounts_push_username")]
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_property_mounts_push_method")]
+    pub method: PushMethod,
+    // Seconds to wait before reconnecting, doubled after every failed attempt
+    #[serde(default = "default_property_mounts_push_retry_interval")]
+    pub retry_interval: u64,
+}
+
+// How a push target is sent the stream
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PushMethod {
+    // Icecast 2.4 and later
+    Put,
+    // Older servers
+    Source,
+}
+
+// Icecast style URL authentication, the hooks receive a form encoded POST for every listener
+// A listener is only admitted if the listener_add response contains auth_header with a value of 1
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListenerAuth {
+    #[serde(default = "default_property_listener_auth_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_property_listener_auth_listener_add")]
+    pub listener_add: String,
+    #[serde(default = "default_property_listener_auth_listener_remove")]
+    pub listener_remove: Option<String>,
+    #[serde(default = "default_property_listener_auth_auth_header")]
+    pub auth_header: String,
+    // If empty, every mountpoint requires authentication
+    #[serde(default = "default_property_listener_auth_mounts")]
+    pub mounts: Vec<String>,
+}
+
+impl ListenerAuth {
+    pub fn applies_to(&self, mount: &str) -> bool {
+        self.enabled && (self.mounts.is_empty() || self.mounts.iter().any(|m| m == mount))
+    }
+}
+
+// SHOUTcast v1 encoders send a password line instead of an HTTP request, and only ever use one mountpoint
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Shoutcast {
+    #[serde(default = "default_property_shoutcast_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_property_shoutcast_port")]
+    pub port: Option<u16>,
+    #[serde(default = "default_property_shoutcast_mount")]
+    pub mount: String,
+    // The user the encoder's password is checked against, if it doesn't send one itself
+    #[serde(default = "default_property_shoutcast_username")]
+    pub username: String,
+    // Mountpoints for SHOUTcast v2 stream IDs, stream ID 1 defaults to the mount above
+    #[serde(default = "default_property_shoutcast_streams")]
+    pub streams: HashMap<u16, String>,
+    // SHOUTcast v2 sources encrypt their credentials with this, like uvoxcipherkey
+    #[serde(default = "default_property_shoutcast_cipher_key")]
+    pub cipher_key: String,
+}
+
+impl Shoutcast {
+    pub fn mount_for(&self, stream_id: u16) -> Option<&str> {
+        match self.streams.get(&stream_id) {
+            Some(mount) => Some(mount),
+            None if stream_id == 1 => Some(&self.mount),
+            None => None,
+        }
+    }
+}
+
+// MP3 and AAC mountpoints are also segmented into MPEG-TS, for players that can't handle an endless stream
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Hls {
+    #[serde(default = "default_property_hls_enabled")]
+    pub enabled: bool,
+    // In seconds, segments are cut on the first frame boundary past this
+    #[serde(default = "default_property_hls_segment_duration")]
+    pub segment_duration: u64,
+    // How many segments are kept in memory and listed in the playlist
+    #[serde(default = "default_property_hls_segments")]
+    pub segments: usize,
+}
+
+// Prometheus text format at /metrics
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Metrics {
+    #[serde(default = "default_property_metrics_enabled")]
+    pub enabled: bool,
+    // The permission needed to scrape, or None to leave the endpoint public
+    #[serde(default = "default_property_metrics_permission")]
+    pub permission: Option<Permission>,
+}
+
+// Where diagnostics go, the access log of listener sessions is kept separately
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Logging {
+    #[serde(default = "default_property_logging_level")]
+    pub level: LogLevel,
+    #[serde(default = "default_property_logging_target")]
+    pub target: LogTarget,
+    #[serde(default = "default_property_logging_format")]
+    pub format: LogFormat,
+    // Only used with the file target
+    #[serde(default = "default_property_logging_file")]
+    pub file: String,
+    // Only used with the syslog target
+    #[serde(default = "default_property_logging_syslog_socket")]
+    pub syslog_socket: String,
+    // Listener sessions in Combined Log Format, not written if None
+    #[serde(default = "default_property_logging_access_log")]
+    pub access_log: Option<String>,
+}
+
+// Ordered from least to most verbose
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Stdout,
+    File,
+    Syslog,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
 }
 
 // Default constants
@@ -156,6 +880,10 @@ const HOST: &str = "localhost";
 const LOCATION: &str = "1.048596";
 // Description of the internet radio
 const DESCRIPTION: &str = "Yet Another Internet Radio";
+// Where mountpoints can be, everywhere by default
+const MOUNT_PREFIX: &str = "/";
+// The server's own endpoints, along with everything under them
+const RESERVED_PATHS: &[&str] = &["/admin", "/admin.cgi", "/api", "/metrics"];
 
 // How many regular sources, not including relays
 const SOURCES: usize = 4;
@@ -172,12 +900,17 @@ const BURST_SIZE: usize = 65536;
 const HEADER_TIMEOUT: u64 = 15_000;
 // How long in milliseconds a source has to send something before being disconnected
 const SOURCE_TIMEOUT: u64 = 10_000;
+// How long in milliseconds to wait for listeners to be drained when shutting down
+const SHUTDOWN_TIMEOUT: u64 = 10_000;
 // The maximum size in bytes of an acceptable http message not including the body
 const HTTP_MAX_LENGTH: usize = 8192;
 // The maximum number of redirects allowed, when fetching relays from another server/stream
 const HTTP_MAX_REDIRECTS: usize = 5;
+// Password of the default users, stored hashed in the generated config
+const PASSWORD: &str = "hackme";
 const ADDRESS: &str = "0.0.0.0";
 const PORT: u16 = 8000;
+const TLS_PORT: u16 = 8443;
 
 fn default_property_address() -> String {
     ADDRESS.to_string()
@@ -185,12 +918,49 @@ fn default_property_address() -> String {
 fn default_property_port() -> u16 {
     PORT
 }
+fn default_property_listen_sockets() -> Vec<ListenSocket> {
+    Vec::new()
+}
+fn default_property_listen_sockets_tls() -> bool {
+    false
+}
+fn default_property_listen_sockets_roles() -> Vec<SocketRole> {
+    vec![
+        SocketRole::Listener,
+        SocketRole::Source,
+        SocketRole::Admin,
+        SocketRole::Api,
+    ]
+}
+fn default_property_tls() -> Tls {
+    Tls {
+        enabled: default_property_tls_enabled(),
+        port: default_property_tls_port(),
+        certificate: default_property_tls_certificate(),
+        key: default_property_tls_key(),
+    }
+}
+fn default_property_tls_enabled() -> bool {
+    false
+}
+fn default_property_tls_port() -> u16 {
+    TLS_PORT
+}
+fn default_property_tls_certificate() -> String {
+    "cert.pem".to_string()
+}
+fn default_property_tls_key() -> String {
+    "key.pem".to_string()
+}
 fn default_property_metaint() -> usize {
     METAINT
 }
 fn default_property_server_id() -> String {
     SERVER_ID.to_string()
 }
+fn default_property_mount_prefix() -> String {
+    MOUNT_PREFIX.to_string()
+}
 fn default_property_admin() -> String {
     ADMIN.to_string()
 }
@@ -207,14 +977,25 @@ fn default_property_users() -> Vec<Credential> {
     vec![
         Credential {
             username: "admin".to_string(),
-            password: "hackme".to_string(),
+            password: default_property_users_password(),
+            roles: Some(vec![Role::Admin]),
+            mounts: default_property_users_mounts(),
         },
         Credential {
             username: "source".to_string(),
-            password: "hackme".to_string(),
+            password: default_property_users_password(),
+            roles: Some(vec![Role::Source]),
+            mounts: default_property_users_mounts(),
         },
     ]
 }
+fn default_property_users_password() -> String {
+    // Only fall back to plaintext if hashing somehow fails
+    hash_password(PASSWORD).unwrap_or_else(|_| PASSWORD.to_string())
+}
+fn default_property_users_mounts() -> HashMap<String, Vec<Permission>> {
+    HashMap::new()
+}
 fn default_property_limits() -> Limits {
     Limits {
         clients: default_property_limits_clients(),
@@ -227,6 +1008,7 @@ fn default_property_limits() -> Limits {
         source_limits: default_property_limits_source_limits(),
         http_max_length: default_property_limits_http_max_length(),
         http_max_redirects: default_property_limits_http_max_redirects(),
+        shutdown_timeout: default_property_limits_shutdown_timeout(),
     }
 }
 
@@ -251,6 +1033,9 @@ fn default_property_limits_header_timeout() -> u64 {
 pub fn default_property_limits_source_timeout() -> u64 {
     SOURCE_TIMEOUT
 }
+fn default_property_limits_shutdown_timeout() -> u64 {
+    SHUTDOWN_TIMEOUT
+}
 fn default_property_limits_http_max_length() -> usize {
     HTTP_MAX_LENGTH
 }
@@ -278,6 +1063,10 @@ fn default_property_master_server() -> Master {
         url: default_property_master_server_url(),
         update_interval: default_property_master_server_update_interval(),
         relay_limit: default_property_master_server_relay_limit(),
+        on_demand: default_property_master_server_on_demand(),
+        idle_timeout: default_property_master_server_idle_timeout(),
+        username: None,
+        password: None,
     }
 }
 fn default_property_master_server_enabled() -> bool {
@@ -292,13 +1081,219 @@ fn default_property_master_server_update_interval() -> u64 {
 fn default_property_master_server_relay_limit() -> usize {
     SOURCES
 }
+fn default_property_master_server_on_demand() -> bool {
+    false
+}
+fn default_property_master_server_idle_timeout() -> u64 {
+    30
+}
+fn default_property_relays() -> Vec<Relay> {
+    Vec::new()
+}
+fn default_property_relays_backup_urls() -> Vec<String> {
+    Vec::new()
+}
+fn default_property_relays_icy_metadata() -> bool {
+    true
+}
+fn default_property_relays_retry_interval() -> u64 {
+    5
+}
+fn default_property_relays_on_demand() -> bool {
+    false
+}
+fn default_property_relays_idle_timeout() -> u64 {
+    30
+}
+fn default_property_relays_silence_timeout() -> u64 {
+    0
+}
+fn default_property_mounts() -> HashMap<String, Mount> {
+    HashMap::new()
+}
+fn default_property_mounts_hidden() -> bool {
+    false
+}
+fn default_property_mounts_relayable() -> bool {
+    true
+}
+fn default_property_mounts_push() -> Vec<PushTarget> {
+    Vec::new()
+}
+fn default_property_mounts_push_username() -> String {
+    "source".to_string()
+}
+fn default_property_mounts_push_method() -> PushMethod {
+    PushMethod::Put
+}
+fn default_property_mounts_push_retry_interval() -> u64 {
+    5
+}
 
-// TODO Add some sort of permission system
-pub fn validate_user(properties: &Properties, username: String, password: String) -> bool {
-    for cred in &properties.users {
-        if cred.username == username && cred.password == password {
-            return true;
-        }
+fn default_property_listener_auth() -> ListenerAuth {
+    ListenerAuth {
+        enabled: default_property_listener_auth_enabled(),
+        listener_add: default_property_listener_auth_listener_add(),
+        listener_remove: default_property_listener_auth_listener_remove(),
+        auth_header: default_property_listener_auth_auth_header(),
+        mounts: default_property_listener_auth_mounts(),
+    }
+}
+fn default_property_listener_auth_enabled() -> bool {
+    false
+}
+fn default_property_listener_auth_listener_add() -> String {
+    "http://localhost/listener_add".to_string()
+}
+fn default_property_listener_auth_listener_remove() -> Option<String> {
+    None
+}
+fn default_property_listener_auth_auth_header() -> String {
+    "icecast-auth-user".to_string()
+}
+fn default_property_listener_auth_mounts() -> Vec<String> {
+    Vec::new()
+}
+
+fn default_property_shoutcast() -> Shoutcast {
+    Shoutcast {
+        enabled: default_property_shoutcast_enabled(),
+        port: default_property_shoutcast_port(),
+        mount: default_property_shoutcast_mount(),
+        username: default_property_shoutcast_username(),
+        streams: default_property_shoutcast_streams(),
+        cipher_key: default_property_shoutcast_cipher_key(),
     }
+}
+fn default_property_shoutcast_enabled() -> bool {
     false
 }
+fn default_property_shoutcast_port() -> Option<u16> {
+    None
+}
+fn default_property_shoutcast_mount() -> String {
+    "/stream".to_string()
+}
+fn default_property_shoutcast_username() -> String {
+    "source".to_string()
+}
+fn default_property_shoutcast_streams() -> HashMap<u16, String> {
+    HashMap::new()
+}
+fn default_property_shoutcast_cipher_key() -> String {
+    "foobar".to_string()
+}
+fn default_property_hls() -> Hls {
+    Hls {
+        enabled: default_property_hls_enabled(),
+        segment_duration: default_property_hls_segment_duration(),
+        segments: default_property_hls_segments(),
+    }
+}
+fn default_property_hls_enabled() -> bool {
+    true
+}
+fn default_property_hls_segment_duration() -> u64 {
+    6
+}
+fn default_property_hls_segments() -> usize {
+    6
+}
+
+fn default_property_logging() -> Logging {
+    Logging {
+        level: default_property_logging_level(),
+        target: default_property_logging_target(),
+        format: default_property_logging_format(),
+        file: default_property_logging_file(),
+        syslog_socket: default_property_logging_syslog_socket(),
+        access_log: default_property_logging_access_log(),
+    }
+}
+fn default_property_logging_level() -> LogLevel {
+    LogLevel::Info
+}
+fn default_property_logging_target() -> LogTarget {
+    LogTarget::Stdout
+}
+fn default_property_logging_format() -> LogFormat {
+    LogFormat::Text
+}
+fn default_property_logging_file() -> String {
+    "rusty-zenith.log".to_string()
+}
+fn default_property_logging_syslog_socket() -> String {
+    "/dev/log".to_string()
+}
+fn default_property_logging_access_log() -> Option<String> {
+    None
+}
+
+fn default_property_metrics() -> Metrics {
+    Metrics {
+        enabled: default_property_metrics_enabled(),
+        permission: default_property_metrics_permission(),
+    }
+}
+fn default_property_metrics_enabled() -> bool {
+    true
+}
+fn default_property_metrics_permission() -> Option<Permission> {
+    Some(Permission::Metrics)
+}
+
+// Settings are compared through their serialized form, so they don't all need PartialEq
+fn keep<T: Serialize + Clone>(
+    changed: &mut Vec<&'static str>,
+    name: &'static str,
+    value: &mut T,
+    running: &T,
+) {
+    if serde_json::to_value(&*value).ok() != serde_json::to_value(running).ok() {
+        changed.push(name);
+        *value = running.clone();
+    }
+}
+
+/**
+ * Find the user and check its password
+ * Hashes are verified on a blocking thread, after letting go of the server lock, as argon2 is slow by design
+ */
+pub async fn validate_user(
+    server: &Arc<RwLock<Server>>,
+    username: &str,
+    password: &str,
+) -> Option<Credential> {
+    let cred = server
+        .read()
+        .await
+        .properties
+        .users
+        .iter()
+        .find(|cred| cred.username == username)
+        .cloned()?;
+
+    if !cred.is_hashed() {
+        return cred.verify_password(password).then_some(cred);
+    }
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || cred.verify_password(&password).then_some(cred))
+        .await
+        .ok()
+        .flatten()
+}
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+// Compares every byte so the time taken does not depend on where the inputs differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::server::Server;
+use crate::source::Source;
+
+// Graceful shutdowns, and upgrades where a new process takes over the listen sockets of the running one
+// The new process is started with the same arguments, finds its sockets through the environment
+// and sends the old process SIGTERM once it's accepting, so connections wait in the accept queue instead of being refused
+
+// The pid of the process handing over its sockets
+const UPGRADE_FROM: &str = "RUSTY_ZENITH_UPGRADE_FROM";
+// One socket per line, as the file descriptor followed by the address it's bound to
+const LISTEN_FDS: &str = "RUSTY_ZENITH_LISTEN_FDS";
+// How often to check whether everyone is gone
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/**
+ * Disconnect every source and wait for their listeners to get what was queued for them, or to be moved to a fallback
+ * Fallbacks are disconnected after the sources using them, so the listeners moved there aren't cut off early
+ * Gives up after limits.shutdown_timeout, then logs the final stats
+ */
+pub async fn graceful(server: &Arc<RwLock<Server>>) {
+    let shutdown_timeout = {
+        let mut serv = server.write().await;
+        serv.shutting_down = true;
+        log_info!(
+            "Shutting down with {} sources and {} listeners connected",
+            serv.sources.len(),
+            serv.clients.len()
+        );
+        serv.properties.limits.shutdown_timeout
+    };
+
+    let deadline = Instant::now() + Duration::from_millis(shutdown_timeout);
+    loop {
+        // Sources lock the server while unmounting, so they're only locked once the server isn't
+        let sources: Vec<Arc<RwLock<Source>>> = {
+            let serv = server.read().await;
+            if serv.sources.is_empty() && serv.clients.is_empty() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                log_warn!(
+                    "Stopped waiting on {} sources and {} listeners after {}ms",
+                    serv.sources.len(),
+                    serv.clients.len(),
+                    shutdown_timeout
+                );
+                break;
+            }
+            serv.sources.values().cloned().collect()
+        };
+        disconnect_sources(&sources).await;
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let serv = server.read().await;
+    let stats = &serv.stats;
+    log_info!(
+        "Shut down after reading {} bytes and sending {} bytes, with at most {} listeners",
+        stats.session_bytes_read,
+        stats.session_bytes_sent,
+        stats.peak_listeners
+    );
+}
+
+// Disconnect the sources that no other remaining source falls back to
+async fn disconnect_sources(sources: &[Arc<RwLock<Source>>]) {
+    let mut fallbacks = HashSet::new();
+    for source in sources {
+        let source = source.read().await;
+        if let Some(fallback) = &source.fallback {
+            if *fallback != source.mountpoint {
+                fallbacks.insert(fallback.clone());
+            }
+        }
+    }
+
+    let mut ready = Vec::new();
+    for source in sources {
+        if !fallbacks.contains(&source.read().await.mountpoint) {
+            ready.push(source);
+        }
+    }
+    // Sources falling back to each other would wait forever
+    if ready.is_empty() {
+        ready = sources.iter().collect();
+    }
+    for source in ready {
+        source.write().await.disconnect_flag = true;
+    }
+}
+
+/**
+ * Start a new process that takes over the given listen sockets, returning its pid
+ */
+pub fn upgrade(sockets: &[(String, RawFd)]) -> io::Result<u32> {
+    let mut args = std::env::args_os();
+    args.next();
+    let fds: Vec<RawFd> = sockets.iter().map(|(_, fd)| *fd).collect();
+    let listen_fds = sockets
+        .iter()
+        .map(|(address, fd)| format!("{} {}", fd, address))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .env(UPGRADE_FROM, std::process::id().to_string())
+        .env(LISTEN_FDS, listen_fds);
+    // Every socket is opened with close-on-exec, which would close them for the new process
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &fds {
+                if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(command.spawn()?.id())
+}
+
+// Only trusted if the process that set it is the parent, so a leftover variable can't do anything
+fn upgrading_from() -> Option<u32> {
+    std::env::var(UPGRADE_FROM)
+        .ok()?
+        .parse::<u32>()
+        .ok()
+        .filter(|pid| *pid == std::os::unix::process::parent_id())
+}
+
+/**
+ * The listen sockets handed over by the previous process, by address
+ */
+pub fn inherited_sockets() -> HashMap<String, RawFd> {
+    if upgrading_from().is_none() {
+        return HashMap::new();
+    }
+
+    std::env::var(LISTEN_FDS)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (fd, address) = line.split_once(' ')?;
+            Some((address.to_string(), fd.parse().ok()?))
+        })
+        .collect()
+}
+
+/**
+ * Let the previous process know it can shut down, now that this one is accepting
+ */
+pub fn finish_upgrade() {
+    if let Some(pid) = upgrading_from() {
+        log_info!("Taking over from process {}", pid);
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == -1 {
+            log_error!(
+                "Unable to stop process {}: {}",
+                pid,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
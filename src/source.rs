@@ -1,16 +1,18 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use uuid::Uuid;
 
 use crate::client;
+use crate::codec;
+use crate::events;
+use crate::hls;
 use crate::icy;
+use crate::ogg;
 use crate::server;
 
-// TODO Add something determining if a source is a relay, or any other kind of source, for that matter
-// TODO Implement hidden sources
 pub struct Source {
     // Is setting the mountpoint in the source really useful, since it's not like the source has any use for it
     pub mountpoint: String,
@@ -19,21 +21,42 @@ pub struct Source {
     pub metadata_vec: Vec<u8>,
     pub clients: HashMap<Uuid, Arc<RwLock<client::Client>>>,
     pub burst_buffer: Vec<u8>,
+    // The length of every frame in the burst buffer, if the codec is known
+    pub burst_frames: VecDeque<usize>,
+    pub framer: codec::Framer,
+    // The header pages new listeners need first, for Ogg streams
+    pub ogg_headers: Option<ogg::Headers>,
+    pub hls: Option<hls::Rendition>,
+    // Set once mounted, so changes reach /api/events
+    pub events: Option<broadcast::Sender<events::Event>>,
     pub stats: RwLock<Stats>,
+    pub limits: ActiveLimits,
     pub fallback: Option<String>,
+    // The source asked to be left out of the listings, the mount can also be configured that way
+    pub hidden: bool,
+    // The upstream URL, if the source is a relay
+    pub relay: Option<String>,
+    // Feeds the push targets of the mount, if it has any
+    pub forward: Option<broadcast::Sender<Forward>>,
     // Not really sure how else to signal when to disconnect the source
     pub disconnect_flag: bool,
 }
 
 impl Source {
-    pub fn new(mountpoint: String, properties: icy::Properties) -> Source {
+    pub fn new(mountpoint: String, properties: icy::Properties, limits: ActiveLimits) -> Source {
+        let framer = codec::Framer::new(&properties.content_type);
         Source {
             mountpoint,
+            ogg_headers: (framer.codec() == codec::Codec::Ogg).then(ogg::Headers::new),
+            framer,
+            hls: None,
+            events: None,
             properties,
             metadata: None,
             metadata_vec: vec![0],
             clients: HashMap::new(),
             burst_buffer: Vec::new(),
+            burst_frames: VecDeque::new(),
             stats: RwLock::new(Stats {
                 start_time: {
                     if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
@@ -43,19 +66,146 @@ impl Source {
                     }
                 },
                 bytes_read: 0,
+                bytes_sent: 0,
                 peak_listeners: 0,
+                listeners_dropped: 0,
             }),
+            limits,
             fallback: None,
+            hidden: false,
+            relay: None,
+            forward: None,
             disconnect_flag: false,
         }
     }
+
+    /**
+     * Keep the burst on connect buffer and any codec headers up to date, returning what listeners should be sent
+     * The burst is trimmed to whole frames when they are known
+     * Ogg header pages are kept aside instead, since new listeners are always sent them first
+     */
+    pub fn add_frames(
+        &mut self,
+        data: Vec<u8>,
+        frames: Option<Vec<usize>>,
+        burst_size: usize,
+    ) -> Vec<u8> {
+        let frames = match frames {
+            Some(frames) => frames,
+            None => {
+                self.burst_frames.clear();
+                self.burst_buffer.extend_from_slice(&data);
+                if self.burst_buffer.len() > burst_size {
+                    self.burst_buffer
+                        .drain(..self.burst_buffer.len() - burst_size);
+                }
+                return data;
+            }
+        };
+
+        // Ogg pages may be rewritten under a new link
+        let mut sent = Vec::with_capacity(data.len());
+        let mut position = 0;
+        for length in frames {
+            let frame = &data[position..position + length];
+            position += length;
+            let start = sent.len();
+
+            let kind = match &mut self.ogg_headers {
+                Some(headers) => headers.push(frame, &mut sent),
+                None => {
+                    sent.extend_from_slice(frame);
+                    ogg::Page::Data
+                }
+            };
+            match kind {
+                ogg::Page::Data => (),
+                ogg::Page::Header => continue,
+                // Song changes arrive as new comment headers, rather than through updinfo
+                ogg::Page::Comments(metadata) => {
+                    log_info!(
+                        "Updated source {} metadata from its comment header with title '{}'",
+                        self.mountpoint,
+                        metadata
+                            .as_ref()
+                            .and_then(|metadata| metadata.title.as_deref())
+                            .unwrap_or("")
+                    );
+                    self.set_metadata(metadata);
+                    continue;
+                }
+                // Pages from the previous link can't be decoded with the new headers
+                ogg::Page::Chain | ogg::Page::Restart => {
+                    self.burst_buffer.clear();
+                    self.burst_frames.clear();
+                    continue;
+                }
+            }
+
+            let frame = &sent[start..];
+            if let Some(hls) = &mut self.hls {
+                hls.push(frame);
+            }
+            if burst_size > 0 && !frame.is_empty() {
+                self.burst_buffer.extend_from_slice(frame);
+                self.burst_frames.push_back(frame.len());
+            }
+        }
+
+        let mut excess = 0;
+        while self.burst_buffer.len() - excess > burst_size {
+            match self.burst_frames.pop_front() {
+                Some(length) => excess += length,
+                None => break,
+            }
+        }
+        self.burst_buffer.drain(..excess);
+        sent
+    }
+
+    pub fn set_metadata(&mut self, metadata: Option<icy::Metadata>) {
+        if let Some(events) = &self.events {
+            drop(events.send(events::Event::new(&self.mountpoint, events::Kind::Metadata)));
+        }
+        if let Some(hls) = &mut self.hls {
+            hls.set_title(
+                metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.title.clone()),
+            );
+        }
+        // Ogg streams carry their title in the stream itself
+        if let (Some(forward), None) = (&self.forward, &self.ogg_headers) {
+            drop(forward.send(Forward::Metadata(metadata.clone())));
+        }
+        self.metadata_vec = icy::get_metadata_vec(&metadata);
+        self.metadata = metadata;
+    }
+}
+
+// What is passed on to push targets, in the order the source sent it
+#[derive(Clone)]
+pub enum Forward {
+    Data(Arc<Vec<u8>>),
+    Metadata(Option<icy::Metadata>),
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Stats {
     pub start_time: u64,
     pub bytes_read: usize,
+    pub bytes_sent: usize,
     pub peak_listeners: usize,
+    // Listeners that fell more than queue_size behind
+    pub listeners_dropped: usize,
+}
+
+// The limits in effect for a connected source, replaced when the config is reloaded
+#[derive(Clone, Copy)]
+pub struct ActiveLimits {
+    pub queue_size: usize,
+    pub burst_size: usize,
+    pub source_timeout: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
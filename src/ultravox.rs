@@ -0,0 +1,387 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::sync::LazyLock;
+
+use crate::icy;
+
+// Ultravox 2.1, as spoken by SHOUTcast v2 sources
+// Every message is the sync byte, a reserved byte, the class and type in 16 bits,
+// the payload length in 16 bits, the payload and a trailing 0x00
+const SYNC: u8 = 0x5A;
+const HEADER_LENGTH: usize = 6;
+pub const MAX_PAYLOAD: usize = 16377;
+
+// Class 1 is the broadcaster handshake
+pub const AUTHENTICATE: u16 = 0x1001;
+pub const SETUP: u16 = 0x1002;
+pub const BUFFER_SIZE: u16 = 0x1003;
+pub const STANDBY: u16 = 0x1004;
+pub const TERMINATE: u16 = 0x1005;
+pub const FLUSH_METADATA: u16 = 0x1006;
+pub const MAX_PAYLOAD_SIZE: u16 = 0x1008;
+pub const CIPHER_KEY: u16 = 0x1009;
+pub const MIME_TYPE: u16 = 0x1040;
+pub const ICY_NAME: u16 = 0x1100;
+pub const ICY_GENRE: u16 = 0x1101;
+pub const ICY_URL: u16 = 0x1102;
+pub const ICY_PUB: u16 = 0x1103;
+// Class 3 is cacheable metadata
+pub const SHOUTCAST_METADATA: u16 = 0x3901;
+pub const XML_METADATA: u16 = 0x3902;
+// Class 7 is MP3 and class 8 is AAC
+pub const CLASS_MP3: u16 = 0x7;
+pub const CLASS_AAC: u16 = 0x8;
+
+// The ID3v2 frames of XML metadata, which are never nested
+static XML_FRAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<([A-Z0-9]{4})>(.*?)</([A-Z0-9]{4})>").unwrap());
+static SHOUTCAST_FIELD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(StreamTitle|StreamUrl)='(.*?)';").unwrap());
+
+/**
+ * Whether a source opened with an Ultravox message rather than a SHOUTcast v1 password line
+ * Checks the reserved byte and the handshake class too, as a password can start with the sync byte
+ */
+pub fn is_ultravox(message: &[u8]) -> bool {
+    message.len() >= 3 && message[0] == SYNC && message[1] == 0x00 && message[2] >> 4 == 0x1
+}
+
+pub struct Message {
+    pub kind: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn class(&self) -> u16 {
+        self.kind >> 12
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.payload).to_string()
+    }
+}
+
+/**
+ * Take the next message from the start of the buffer, if all of it is there
+ * Returns the message and how many bytes it took up
+ */
+pub fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>, Box<dyn Error>> {
+    if buf.len() < HEADER_LENGTH {
+        return Ok(None);
+    }
+    if buf[0] != SYNC {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Lost the Ultravox sync byte",
+        )));
+    }
+
+    let kind = u16::from_be_bytes([buf[2], buf[3]]);
+    let length = u16::from_be_bytes([buf[4], buf[5]]) as usize;
+    if length > MAX_PAYLOAD {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Ultravox message exceeded the maximum payload",
+        )));
+    }
+
+    let end = HEADER_LENGTH + length;
+    // Wait for the trailing 0x00 too
+    if buf.len() <= end {
+        return Ok(None);
+    }
+
+    Ok(Some((
+        Message {
+            kind,
+            payload: buf[HEADER_LENGTH..end].to_vec(),
+        },
+        end + 1,
+    )))
+}
+
+pub fn encode(kind: u16, payload: &str) -> Vec<u8> {
+    let payload = payload.as_bytes();
+    let mut message = vec![SYNC, 0];
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    message.extend_from_slice(payload);
+    message.push(0);
+    message
+}
+
+/**
+ * Decrypt a hex encoded XTEA credential, as sent in the authenticate message
+ * The key is the cipher key padded with zeroes to 16 bytes, and every 8 byte block is big endian
+ */
+pub fn decrypt(hex: &str, key: &str) -> Option<String> {
+    let mut padded = [0u8; 16];
+    for (i, byte) in key.bytes().take(16).enumerate() {
+        padded[i] = byte;
+    }
+    let key: Vec<u32> = padded
+        .chunks(4)
+        .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect();
+
+    let blocks = hex.as_bytes().chunks_exact(16);
+    if !blocks.remainder().is_empty() || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut plain = Vec::new();
+    for block in blocks {
+        let block = std::str::from_utf8(block).ok()?;
+        let mut v0 = u32::from_str_radix(&block[..8], 16).ok()?;
+        let mut v1 = u32::from_str_radix(&block[8..], 16).ok()?;
+
+        let delta: u32 = 0x9E37_79B9;
+        let mut sum = delta.wrapping_mul(32);
+        for _ in 0..32 {
+            v1 = v1.wrapping_sub(
+                ((v0 << 4) ^ (v0 >> 5)).wrapping_add(v0)
+                    ^ sum.wrapping_add(key[((sum >> 11) & 3) as usize]),
+            );
+            sum = sum.wrapping_sub(delta);
+            v0 = v0.wrapping_sub(
+                ((v1 << 4) ^ (v1 >> 5)).wrapping_add(v1)
+                    ^ sum.wrapping_add(key[(sum & 3) as usize]),
+            );
+        }
+
+        plain.extend_from_slice(&v0.to_be_bytes());
+        plain.extend_from_slice(&v1.to_be_bytes());
+    }
+
+    // Blocks are padded with zeroes
+    while plain.last() == Some(&0) {
+        plain.pop();
+    }
+    String::from_utf8(plain).ok()
+}
+
+/**
+ * XML metadata can be split over several messages, each starting with an id, the total span and its index
+ */
+pub struct MetadataBuffer {
+    id: u16,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataBuffer {
+    pub fn new() -> MetadataBuffer {
+        MetadataBuffer {
+            id: 0,
+            parts: Vec::new(),
+        }
+    }
+
+    // Returns the whole document once the last missing part arrives
+    pub fn push(&mut self, payload: &[u8]) -> Option<String> {
+        if payload.len() < 6 {
+            return None;
+        }
+        let id = u16::from_be_bytes([payload[0], payload[1]]);
+        let span = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+        let index = u16::from_be_bytes([payload[4], payload[5]]) as usize;
+        if span == 0 || index == 0 || index > span {
+            return None;
+        }
+
+        // A new id replaces anything that didn't complete
+        if id != self.id || self.parts.len() != span {
+            self.id = id;
+            self.parts = vec![None; span];
+        }
+        self.parts[index - 1] = Some(payload[6..].to_vec());
+
+        if self.parts.iter().all(|part| part.is_some()) {
+            let document: Vec<u8> = self.parts.drain(..).flatten().flatten().collect();
+            Some(String::from_utf8_lossy(&document).to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/**
+ * Turn SHOUTcast v2 XML metadata into a title, using the ID3v2 frame names it is made of
+ */
+pub fn parse_xml_metadata(xml: &str) -> Option<icy::Metadata> {
+    let mut frames = HashMap::new();
+    for capture in XML_FRAME.captures_iter(xml) {
+        if capture[1] == capture[3] {
+            frames
+                .entry(capture[1].to_string())
+                .or_insert(capture[2].to_string());
+        }
+    }
+    let tag = |name: &str| -> Option<String> {
+        let value = frames.get(name)?.trim();
+        let value = value
+            .strip_prefix("<![CDATA[")
+            .and_then(|value| value.strip_suffix("]]>"))
+            .map(|value| value.to_string())
+            .unwrap_or_else(|| unescape(value));
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    };
+
+    let title = match (tag("TPE1"), tag("TIT2")) {
+        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+        (None, title) => title,
+        (artist, None) => artist,
+    };
+    let url = tag("WORS").or_else(|| tag("WOAS"));
+
+    match (title, url) {
+        (None, None) => None,
+        (title, url) => Some(icy::Metadata { title, url }),
+    }
+}
+
+/**
+ * Turn SHOUTcast v1 style metadata into a title and url
+ */
+pub fn parse_shoutcast_metadata(text: &str) -> Option<icy::Metadata> {
+    let field = |name: &str| -> Option<String> {
+        SHOUTCAST_FIELD
+            .captures_iter(text)
+            .find(|capture| &capture[1] == name)
+            .map(|capture| capture[2].to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    match (field("StreamTitle"), field("StreamUrl")) {
+        (None, None) => None,
+        (title, url) => Some(icy::Metadata { title, url }),
+    }
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let mut buf = encode(AUTHENTICATE, "2.1:1:user:pass");
+        buf.extend_from_slice(&encode(MIME_TYPE, "audio/mpeg"));
+
+        let (message, used) = decode(&buf).unwrap().unwrap();
+        assert_eq!(message.kind, AUTHENTICATE);
+        assert_eq!(message.class(), 0x1);
+        assert_eq!(message.text(), "2.1:1:user:pass");
+        assert_eq!(used, HEADER_LENGTH + 15 + 1);
+
+        let (message, used) = decode(&buf[used..]).unwrap().unwrap();
+        assert_eq!(message.kind, MIME_TYPE);
+        assert_eq!(message.text(), "audio/mpeg");
+        assert_eq!(used, HEADER_LENGTH + 10 + 1);
+    }
+
+    #[test]
+    fn decode_waits_for_the_whole_message() {
+        let buf = encode(ICY_NAME, "Radio");
+        assert!(decode(&buf[..3]).unwrap().is_none());
+        // Everything but the trailing 0x00
+        assert!(decode(&buf[..buf.len() - 1]).unwrap().is_none());
+        assert!(decode(&buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn decode_rejects_bad_messages() {
+        assert!(decode(b"ABCDEFGH").is_err());
+
+        let mut oversized = vec![SYNC, 0, 0x70, 0x00];
+        oversized.extend_from_slice(&((MAX_PAYLOAD + 1) as u16).to_be_bytes());
+        assert!(decode(&oversized).is_err());
+    }
+
+    #[test]
+    fn detects_ultravox_and_v1_passwords() {
+        assert!(is_ultravox(&encode(CIPHER_KEY, "")));
+        assert!(!is_ultravox(b"Zebra123\r\n"));
+        assert!(!is_ultravox(b"Z\x00\x70"));
+        assert!(!is_ultravox(b"hackme\r\n"));
+    }
+
+    #[test]
+    fn xtea_known_answer() {
+        let key: String = (0u8..16).map(char::from).collect();
+        assert_eq!(
+            decrypt("497df3d072612cb5", &key).as_deref(),
+            Some("ABCDEFGH")
+        );
+    }
+
+    #[test]
+    fn xtea_strips_the_padding() {
+        assert_eq!(
+            decrypt("ad271eeeedcb7452db2d15c93ce848a4", "cipher").as_deref(),
+            Some("dj:hackme")
+        );
+        assert!(decrypt("ad271eeeedcb74", "cipher").is_none());
+        assert!(decrypt("zz271eeeedcb7452", "cipher").is_none());
+    }
+
+    fn part(id: u16, span: u16, index: u16, data: &str) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&span.to_be_bytes());
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(data.as_bytes());
+        payload
+    }
+
+    #[test]
+    fn reassembles_out_of_order_parts() {
+        let mut buffer = MetadataBuffer::new();
+        assert_eq!(buffer.push(&part(7, 3, 3, "</metadata>")), None);
+        assert_eq!(buffer.push(&part(7, 3, 1, "<metadata>")), None);
+        assert_eq!(
+            buffer.push(&part(7, 3, 2, "<TIT2>Song</TIT2>")).as_deref(),
+            Some("<metadata><TIT2>Song</TIT2></metadata>")
+        );
+    }
+
+    #[test]
+    fn a_new_id_drops_incomplete_parts() {
+        let mut buffer = MetadataBuffer::new();
+        assert_eq!(buffer.push(&part(1, 2, 1, "old")), None);
+        assert_eq!(buffer.push(&part(2, 2, 2, "new")), None);
+        assert_eq!(
+            buffer.push(&part(2, 2, 1, "the ")).as_deref(),
+            Some("the new")
+        );
+        assert_eq!(buffer.push(&part(3, 2, 3, "out of span")), None);
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let metadata = parse_xml_metadata(
+            "<metadata><TPE1>A &amp; B</TPE1><TIT2><![CDATA[<Song>]]></TIT2><WOAS>http://a</WOAS></metadata>",
+        )
+        .unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("A & B - <Song>"));
+        assert_eq!(metadata.url.as_deref(), Some("http://a"));
+
+        let metadata = parse_shoutcast_metadata("StreamTitle='Song';StreamUrl='';").unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!(metadata.url, None);
+        assert!(parse_shoutcast_metadata("StreamTitle='';").is_none());
+    }
+}